@@ -0,0 +1,93 @@
+use anyhow::Result;
+use chrono::Utc;
+use serde_json::{json, Value};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::config::AuditLogConfig;
+
+/// Tracing target of audit events. The default log filter always lets it
+/// through at info, whatever `logging.level` is.
+pub const TARGET: &str = "browser_proxy::audit";
+
+/// Record of security-relevant events: lockouts, and later administrative
+/// changes.
+///
+/// Every event is logged under the `browser_proxy::audit` tracing target. With
+/// `audit_log.enabled` it is also appended to a JSON-lines file, one object
+/// per event with `timestamp` and `event` fields next to the details.
+pub struct AuditLog {
+    file: Option<Mutex<File>>,
+}
+
+impl AuditLog {
+    pub fn new(config: &AuditLogConfig) -> Result<Self> {
+        if !config.enabled {
+            return Ok(Self { file: None });
+        }
+
+        let path = Path::new(&config.path);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Some(Mutex::new(file)),
+        })
+    }
+
+    /// Record an event. `details` should be a JSON object.
+    pub fn record(&self, event: &str, details: Value) {
+        tracing::info!(target: TARGET, event, %details);
+
+        let Some(file) = &self.file else {
+            return;
+        };
+        let mut entry = json!({
+            "timestamp": Utc::now().to_rfc3339(),
+            "event": event,
+        });
+        if let (Some(entry), Value::Object(details)) = (entry.as_object_mut(), details) {
+            entry.extend(details);
+        }
+
+        let mut line = entry.to_string();
+        line.push('\n');
+        if let Err(e) = file.lock().unwrap().write_all(line.as_bytes()) {
+            tracing::error!("Failed to write audit log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_written_as_json_lines() {
+        let dir = std::env::temp_dir().join(format!("browser_proxy_audit_{}", std::process::id()));
+        let path = dir.join("audit.log");
+        let audit = AuditLog::new(&AuditLogConfig {
+            enabled: true,
+            path: path.to_str().unwrap().to_string(),
+        })
+        .unwrap();
+
+        audit.record("login_lockout", json!({"scope": "user", "key": "alice"}));
+        audit.record("login_lockout", json!({"scope": "ip", "key": "10.0.0.1"}));
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "login_lockout");
+        assert_eq!(lines[0]["key"], "alice");
+        assert!(lines[1]["timestamp"].is_string());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
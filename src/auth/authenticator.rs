@@ -0,0 +1,266 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use super::client_cert::ClientCert;
+use super::oidc::PendingLogin;
+use super::users::UserStore;
+
+/// What the user presented to log in
+pub enum Credentials<'a> {
+    Password {
+        username: &'a str,
+        password: &'a str,
+    },
+    /// Authorization code returned to the OIDC callback
+    OidcCode {
+        pending: &'a PendingLogin,
+        code: &'a str,
+    },
+    /// Certificate presented and verified during the TLS handshake
+    ClientCert(&'a ClientCert),
+}
+
+/// Which kind of authenticator vouched for a user
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdentitySource {
+    #[default]
+    Local,
+    Ldap,
+    Oidc,
+    #[serde(rename = "cert")]
+    ClientCert,
+}
+
+impl IdentitySource {
+    /// Whether the username names a local account. Client certificates are
+    /// mapped onto local accounts by the admin's configuration; directory and
+    /// SSO names are chosen elsewhere and only share the namespace.
+    pub fn is_local_account(self) -> bool {
+        matches!(self, IdentitySource::Local | IdentitySource::ClientCert)
+    }
+}
+
+/// A successfully authenticated user
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub username: String,
+    pub roles: Vec<String>,
+    pub source: IdentitySource,
+}
+
+/// A source of user identities (local accounts, a directory, an identity provider)
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether this authenticator can check usernames and passwords
+    fn accepts_passwords(&self) -> bool;
+
+    /// Returns `Ok(None)` when the credentials are wrong or of a kind this
+    /// authenticator does not handle. `Err` means it could not decide, for
+    /// example because a directory server is unreachable.
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Option<Identity>>;
+}
+
+/// The configured authenticators, tried in order until one accepts
+pub struct Authenticators {
+    authenticators: Vec<Arc<dyn Authenticator>>,
+    /// Names an LDAP or SSO login may not take over
+    local_users: Arc<UserStore>,
+}
+
+impl Authenticators {
+    pub fn new(authenticators: Vec<Arc<dyn Authenticator>>, local_users: Arc<UserStore>) -> Self {
+        Self {
+            authenticators,
+            local_users,
+        }
+    }
+
+    pub fn accepts_passwords(&self) -> bool {
+        self.authenticators.iter().any(|a| a.accepts_passwords())
+    }
+
+    pub async fn authenticate(&self, credentials: &Credentials<'_>) -> Option<Identity> {
+        for authenticator in &self.authenticators {
+            match authenticator.authenticate(credentials).await {
+                Ok(Some(identity))
+                    if !identity.source.is_local_account()
+                        && self.local_users.contains_ignoring_case(&identity.username) =>
+                {
+                    // Tokens, TOTP enrollments and sessions are keyed by name,
+                    // and directories match names ignoring case
+                    tracing::warn!(
+                        "Refused {} login as {}: the name belongs to a local user",
+                        authenticator.name(),
+                        identity.username
+                    );
+                }
+                Ok(Some(identity)) => {
+                    tracing::debug!(
+                        "User {} authenticated by {}",
+                        identity.username,
+                        authenticator.name()
+                    );
+                    return Some(identity);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::error!("{} authentication failed: {:#}", authenticator.name(), e)
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Turn external group names into proxy roles. With an empty mapping group
+/// names are used as roles directly; otherwise unmapped groups are dropped.
+pub fn map_roles<'a>(
+    groups: impl IntoIterator<Item = &'a str>,
+    mapping: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let mut roles: Vec<String> = Vec::new();
+    for group in groups {
+        let role = if mapping.is_empty() {
+            Some(group)
+        } else {
+            mapping.get(group).map(String::as_str)
+        };
+        if let Some(role) = role
+            && !roles.iter().any(|r| r == role)
+        {
+            roles.push(role.to_string());
+        }
+    }
+    roles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixed(Option<&'static str>, bool, IdentitySource);
+
+    #[async_trait]
+    impl Authenticator for Fixed {
+        fn name(&self) -> &'static str {
+            "fixed"
+        }
+
+        fn accepts_passwords(&self) -> bool {
+            true
+        }
+
+        async fn authenticate(&self, _: &Credentials<'_>) -> Result<Option<Identity>> {
+            if self.1 {
+                anyhow::bail!("unreachable");
+            }
+            Ok(self.0.map(|username| Identity {
+                username: username.to_string(),
+                roles: Vec::new(),
+                source: self.2,
+            }))
+        }
+    }
+
+    fn password() -> Credentials<'static> {
+        Credentials::Password {
+            username: "alice",
+            password: "s3cret",
+        }
+    }
+
+    const LOCAL: IdentitySource = IdentitySource::Local;
+
+    /// A store holding the local user "root"
+    fn local_users() -> Arc<UserStore> {
+        Arc::new(
+            UserStore::from_config(&crate::config::AuthConfig {
+                username: Some("root".to_string()),
+                password: Some("unused".to_string()),
+                ..toml::from_str("").unwrap()
+            })
+            .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_first_accepting_authenticator_wins() {
+        let chain = Authenticators::new(
+            vec![
+                Arc::new(Fixed(None, false, LOCAL)),
+                Arc::new(Fixed(None, true, LOCAL)),
+                Arc::new(Fixed(Some("alice"), false, LOCAL)),
+                Arc::new(Fixed(Some("bob"), false, LOCAL)),
+            ],
+            local_users(),
+        );
+
+        let identity = chain.authenticate(&password()).await.unwrap();
+        assert_eq!(identity.username, "alice");
+    }
+
+    #[tokio::test]
+    async fn test_all_rejecting_returns_none() {
+        let chain = Authenticators::new(vec![Arc::new(Fixed(None, false, LOCAL))], local_users());
+        assert!(chain.authenticate(&password()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_external_identity_cannot_take_local_name() {
+        let chain = Authenticators::new(
+            vec![
+                Arc::new(Fixed(Some("root"), false, IdentitySource::Oidc)),
+                Arc::new(Fixed(Some("root"), false, IdentitySource::Ldap)),
+            ],
+            local_users(),
+        );
+        assert!(chain.authenticate(&password()).await.is_none());
+
+        // A certificate mapped to a local account is that account
+        let chain = Authenticators::new(
+            vec![
+                Arc::new(Fixed(Some("root"), false, IdentitySource::ClientCert)),
+                Arc::new(Fixed(Some("carol"), false, IdentitySource::Ldap)),
+            ],
+            local_users(),
+        );
+        let identity = chain.authenticate(&password()).await.unwrap();
+        assert_eq!(identity.source, IdentitySource::ClientCert);
+        let chain = Authenticators::new(
+            vec![Arc::new(Fixed(Some("ROOT"), false, IdentitySource::Ldap))],
+            local_users(),
+        );
+        assert!(chain.authenticate(&password()).await.is_none());
+
+        let chain = Authenticators::new(
+            vec![Arc::new(Fixed(Some("carol"), false, IdentitySource::Ldap))],
+            local_users(),
+        );
+        assert_eq!(
+            chain.authenticate(&password()).await.unwrap().username,
+            "carol"
+        );
+    }
+
+    #[test]
+    fn test_map_roles() {
+        let mut mapping = BTreeMap::new();
+        mapping.insert("eng".to_string(), "engineers".to_string());
+        mapping.insert("eng-leads".to_string(), "engineers".to_string());
+
+        assert_eq!(
+            map_roles(["eng", "eng-leads", "sales"], &mapping),
+            vec!["engineers"]
+        );
+        assert_eq!(
+            map_roles(["eng", "sales"], &BTreeMap::new()),
+            vec!["eng", "sales"]
+        );
+    }
+}
@@ -0,0 +1,292 @@
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use super::authenticator::{Authenticator, Credentials, Identity, IdentitySource};
+use super::users::UserStore;
+use crate::config::ClientCertConfig;
+
+/// Names found in a client certificate that passed TLS verification. Added
+/// to request extensions by the TLS listener.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientCert {
+    pub subject_cn: Option<String>,
+    pub emails: Vec<String>,
+    pub dns_names: Vec<String>,
+    pub uris: Vec<String>,
+}
+
+impl ClientCert {
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        let (_, cert) = X509Certificate::from_der(der)
+            .map_err(|e| anyhow!("Invalid client certificate: {}", e))?;
+
+        let mut client = ClientCert {
+            subject_cn: cert
+                .subject()
+                .iter_common_name()
+                .next()
+                .and_then(|cn| cn.as_str().ok())
+                .map(str::to_string),
+            ..Default::default()
+        };
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::RFC822Name(email) => client.emails.push(email.to_string()),
+                    GeneralName::DNSName(dns) => client.dns_names.push(dns.to_string()),
+                    GeneralName::URI(uri) => client.uris.push(uri.to_string()),
+                    _ => {}
+                }
+            }
+        }
+        Ok(client)
+    }
+}
+
+/// Certificate field that names the user
+#[derive(Debug, Clone, Copy)]
+enum IdentityField {
+    SubjectCn,
+    SanEmail,
+    SanDns,
+    SanUri,
+}
+
+/// Logs in users by TLS client certificate. The chosen certificate field is
+/// mapped to a username through `auth.client_cert.users`, or used as the
+/// username directly. Local accounts keep their roles and `enabled` flag.
+pub struct ClientCertAuthenticator {
+    field: IdentityField,
+    mapping: BTreeMap<String, String>,
+    users: Arc<UserStore>,
+}
+
+impl ClientCertAuthenticator {
+    pub fn new(config: &ClientCertConfig, users: Arc<UserStore>) -> Result<Self> {
+        let field = match config.identity.as_str() {
+            "subject_cn" => IdentityField::SubjectCn,
+            "san_email" => IdentityField::SanEmail,
+            "san_dns" => IdentityField::SanDns,
+            "san_uri" => IdentityField::SanUri,
+            other => bail!(
+                "Unknown auth.client_cert.identity '{}'. Use subject_cn, san_email, san_dns or san_uri",
+                other
+            ),
+        };
+        Ok(Self {
+            field,
+            mapping: config.users.clone(),
+            users,
+        })
+    }
+
+    /// Username for a certificate: the first identity in the configured
+    /// field that maps to one
+    fn username(&self, cert: &ClientCert) -> Option<String> {
+        let identities = match self.field {
+            IdentityField::SubjectCn => cert.subject_cn.iter().collect::<Vec<_>>(),
+            IdentityField::SanEmail => cert.emails.iter().collect(),
+            IdentityField::SanDns => cert.dns_names.iter().collect(),
+            IdentityField::SanUri => cert.uris.iter().collect(),
+        };
+        identities.into_iter().find_map(|identity| {
+            if self.mapping.is_empty() {
+                Some(identity.clone())
+            } else {
+                self.mapping.get(identity).cloned()
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl Authenticator for ClientCertAuthenticator {
+    fn name(&self) -> &'static str {
+        "client_cert"
+    }
+
+    fn accepts_passwords(&self) -> bool {
+        false
+    }
+
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Option<Identity>> {
+        let Credentials::ClientCert(cert) = credentials else {
+            return Ok(None);
+        };
+        let Some(username) = self.username(cert) else {
+            tracing::warn!("No user mapped to client certificate {:?}", cert.subject_cn);
+            return Ok(None);
+        };
+
+        let roles = match self.users.get(&username) {
+            Some(user) if !user.enabled => {
+                tracing::warn!("Client certificate for disabled user {}", username);
+                return Ok(None);
+            }
+            Some(user) => user.roles,
+            None => Vec::new(),
+        };
+        Ok(Some(Identity {
+            username,
+            roles,
+            source: IdentitySource::ClientCert,
+        }))
+    }
+}
+
+/// Throwaway CA and client certificates for tests
+#[cfg(test)]
+pub(crate) mod test_certs {
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, DnType, Ia5String, IsCa, KeyPair, SanType,
+    };
+
+    pub struct Ca {
+        pub cert: Certificate,
+        pub key: KeyPair,
+    }
+
+    pub fn ca(name: &str) -> Ca {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, name);
+        Ca {
+            cert: params.self_signed(&key).unwrap(),
+            key,
+        }
+    }
+
+    /// A certificate signed by `ca` with the given common name, DNS names
+    /// and email addresses
+    pub fn issue(ca: &Ca, cn: &str, dns: &[&str], emails: &[&str]) -> (Certificate, KeyPair) {
+        let key = KeyPair::generate().unwrap();
+        let mut params =
+            CertificateParams::new(dns.iter().map(|d| d.to_string()).collect::<Vec<_>>()).unwrap();
+        for email in emails {
+            params
+                .subject_alt_names
+                .push(SanType::Rfc822Name(Ia5String::try_from(*email).unwrap()));
+        }
+        params.distinguished_name.push(DnType::CommonName, cn);
+        let cert = params.signed_by(&key, &ca.cert, &ca.key).unwrap();
+        (cert, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_certs::{ca, issue};
+    use super::*;
+    use crate::auth::password::hash_password;
+    use crate::auth::User;
+
+    fn kiosk_cert() -> ClientCert {
+        let ca = ca("Test CA");
+        let (cert, _) = issue(
+            &ca,
+            "kiosk-1",
+            &["kiosk-1.example.com"],
+            &["kiosk-1@example.com"],
+        );
+        ClientCert::from_der(cert.der()).unwrap()
+    }
+
+    fn authenticator(identity: &str, mapping: &[(&str, &str)]) -> ClientCertAuthenticator {
+        let hash = hash_password("unused").unwrap();
+        let users = UserStore::from_config(&crate::config::AuthConfig {
+            users: vec![
+                User {
+                    username: "lobby".to_string(),
+                    password_hash: hash.clone(),
+                    enabled: true,
+                    roles: vec!["kiosk".to_string()],
+                    require_2fa: false,
+                },
+                User {
+                    username: "retired".to_string(),
+                    password_hash: hash,
+                    enabled: false,
+                    roles: Vec::new(),
+                    require_2fa: false,
+                },
+            ],
+            ..toml::from_str("").unwrap()
+        })
+        .unwrap();
+        let config = ClientCertConfig {
+            identity: identity.to_string(),
+            users: mapping
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        };
+        ClientCertAuthenticator::new(&config, Arc::new(users)).unwrap()
+    }
+
+    async fn login(authenticator: &ClientCertAuthenticator, cert: &ClientCert) -> Option<Identity> {
+        authenticator
+            .authenticate(&Credentials::ClientCert(cert))
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_names_read_from_certificate() {
+        let cert = kiosk_cert();
+        assert_eq!(cert.subject_cn.as_deref(), Some("kiosk-1"));
+        assert_eq!(cert.dns_names, vec!["kiosk-1.example.com"]);
+        assert_eq!(cert.emails, vec!["kiosk-1@example.com"]);
+    }
+
+    #[tokio::test]
+    async fn test_mapped_identity_gets_local_roles() {
+        let cert = kiosk_cert();
+        let auth = authenticator("san_email", &[("kiosk-1@example.com", "lobby")]);
+
+        let identity = login(&auth, &cert).await.unwrap();
+        assert_eq!(identity.username, "lobby");
+        assert_eq!(identity.roles, vec!["kiosk"]);
+    }
+
+    #[tokio::test]
+    async fn test_unmapped_identity_refused() {
+        let cert = kiosk_cert();
+        let auth = authenticator("subject_cn", &[("kiosk-2", "lobby")]);
+        assert!(login(&auth, &cert).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_identity_as_username_without_mapping() {
+        let cert = kiosk_cert();
+        let identity = login(&authenticator("san_dns", &[]), &cert).await.unwrap();
+        assert_eq!(identity.username, "kiosk-1.example.com");
+        assert!(identity.roles.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_disabled_user_refused() {
+        let cert = kiosk_cert();
+        let auth = authenticator("subject_cn", &[("kiosk-1", "retired")]);
+        assert!(login(&auth, &cert).await.is_none());
+    }
+
+    #[test]
+    fn test_unknown_identity_field_rejected() {
+        let config = ClientCertConfig {
+            identity: "serial".to_string(),
+            users: BTreeMap::new(),
+        };
+        let users = Arc::new(
+            UserStore::from_config(&crate::config::AuthConfig {
+                client_cert: Some(config.clone()),
+                ..toml::from_str("").unwrap()
+            })
+            .unwrap(),
+        );
+        assert!(ClientCertAuthenticator::new(&config, users).is_err());
+    }
+}
@@ -0,0 +1,385 @@
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use ldap3::{
+    dn_escape, ldap_escape, LdapConnAsync, LdapConnSettings, LdapError, Scope, SearchEntry,
+};
+use std::time::Duration;
+
+use super::authenticator::{map_roles, Authenticator, Credentials, Identity, IdentitySource};
+use crate::config::LdapConfig;
+
+/// LDAP result code for a failed bind
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// Authenticates by binding to a directory as the user (LDAP simple bind).
+///
+/// Each login opens its own connection, so a bind never leaks into another
+/// user's request. Groups are searched with the user's own credentials.
+pub struct LdapAuthenticator {
+    config: LdapConfig,
+}
+
+impl LdapAuthenticator {
+    pub fn new(config: &LdapConfig) -> Result<Self> {
+        let url = url::Url::parse(&config.url).context("Invalid auth.ldap.url")?;
+        match url.scheme() {
+            "ldap" => {}
+            "ldaps" if config.starttls => bail!("auth.ldap.starttls cannot be used with ldaps://"),
+            "ldaps" => {}
+            other => bail!("Unsupported auth.ldap.url scheme '{}'", other),
+        }
+        if !config.bind_dn.contains("{username}") {
+            bail!("auth.ldap.bind_dn must contain {{username}}");
+        }
+        if !config.starttls && url.scheme() == "ldap" {
+            tracing::warn!("LDAP passwords are sent unencrypted; use ldaps:// or starttls");
+        }
+
+        Ok(Self {
+            config: config.clone(),
+        })
+    }
+
+    async fn bind(&self, username: &str, password: &str) -> Result<Option<Identity>> {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(Duration::from_secs(self.config.timeout_secs))
+            .set_starttls(self.config.starttls);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url)
+            .await
+            .with_context(|| format!("Failed to connect to {}", self.config.url))?;
+        ldap3::drive!(conn);
+        ldap.with_timeout(Duration::from_secs(self.config.timeout_secs));
+
+        let dn = self
+            .config
+            .bind_dn
+            .replace("{username}", &dn_escape(username));
+        match ldap.simple_bind(&dn, password).await?.success() {
+            Ok(_) => {}
+            Err(LdapError::LdapResult { result }) if result.rc == INVALID_CREDENTIALS => {
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let groups = match &self.config.group_base_dn {
+            Some(base) => {
+                let filter = self
+                    .config
+                    .group_filter
+                    .replace("{dn}", &ldap_escape(dn.as_str()))
+                    .replace("{username}", &ldap_escape(username));
+                let (entries, _) = ldap
+                    .search(
+                        base,
+                        Scope::Subtree,
+                        &filter,
+                        vec![self.config.group_attribute.as_str()],
+                    )
+                    .await?
+                    .success()?;
+                entries
+                    .into_iter()
+                    .map(SearchEntry::construct)
+                    .filter_map(|mut entry| entry.attrs.remove(&self.config.group_attribute))
+                    .flatten()
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        let _ = ldap.unbind().await;
+
+        // The directory matched the name ignoring case, but TOTP enrollments,
+        // tokens and sessions are keyed by the exact name
+        Ok(Some(Identity {
+            username: username.to_lowercase(),
+            roles: map_roles(groups.iter().map(String::as_str), &self.config.role_mapping),
+            source: IdentitySource::Ldap,
+        }))
+    }
+}
+
+#[async_trait]
+impl Authenticator for LdapAuthenticator {
+    fn name(&self) -> &'static str {
+        "ldap"
+    }
+
+    fn accepts_passwords(&self) -> bool {
+        true
+    }
+
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Option<Identity>> {
+        let Credentials::Password { username, password } = credentials else {
+            return Ok(None);
+        };
+
+        // An empty password would be an unauthenticated bind, which many
+        // servers accept without checking anything
+        if username.is_empty() || password.is_empty() {
+            return Ok(None);
+        }
+
+        self.bind(username, password).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use ldap3::asn1::{
+        parse_tag, write, ASNTag, Enumerated, Integer, OctetString, Sequence, Set, StructureTag,
+        Tag, TagClass, PL,
+    };
+    use std::collections::{BTreeMap, HashMap};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Minimal LDAP server: simple bind against fixed DNs and passwords, and
+    /// a group search returning every group the bound DN is a member of
+    struct Directory {
+        passwords: HashMap<String, String>,
+        /// group name -> member DNs
+        groups: Vec<(String, Vec<String>)>,
+    }
+
+    fn octets(value: &str) -> Tag {
+        Tag::OctetString(OctetString {
+            inner: value.as_bytes().to_vec(),
+            ..Default::default()
+        })
+    }
+
+    fn ldap_result(id: u64, rc: i64) -> Tag {
+        Tag::Sequence(Sequence {
+            id,
+            class: TagClass::Application,
+            inner: vec![
+                Tag::Enumerated(Enumerated {
+                    inner: rc,
+                    ..Default::default()
+                }),
+                octets(""),
+                octets(""),
+            ],
+        })
+    }
+
+    async fn send(stream: &mut tokio::net::TcpStream, message_id: i64, op: Tag) {
+        let message = Tag::Sequence(Sequence {
+            inner: vec![
+                Tag::Integer(Integer {
+                    inner: message_id,
+                    ..Default::default()
+                }),
+                op,
+            ],
+            ..Default::default()
+        });
+        let mut buf = BytesMut::new();
+        write::encode_into(&mut buf, message.into_structure()).unwrap();
+        stream.write_all(&buf).await.unwrap();
+    }
+
+    fn primitive(tag: &StructureTag) -> String {
+        match &tag.payload {
+            PL::P(bytes) => String::from_utf8_lossy(bytes).to_string(),
+            PL::C(_) => String::new(),
+        }
+    }
+
+    async fn serve(directory: &Directory, mut stream: tokio::net::TcpStream) {
+        let mut buf = Vec::new();
+        let mut bound_dn = None;
+        loop {
+            let (message, consumed) = match parse_tag(&buf) {
+                Ok((rest, tag)) => (tag, buf.len() - rest.len()),
+                Err(_) => {
+                    let mut chunk = [0u8; 4096];
+                    match stream.read(&mut chunk).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                    }
+                    continue;
+                }
+            };
+            buf.drain(..consumed);
+
+            let PL::C(parts) = message.payload else {
+                return;
+            };
+            let PL::P(id_bytes) = &parts[0].payload else {
+                return;
+            };
+            let message_id = id_bytes.iter().fold(0i64, |acc, b| (acc << 8) | *b as i64);
+            let PL::C(fields) = &parts[1].payload else {
+                return;
+            };
+
+            match parts[1].id {
+                // BindRequest: version, name, simple password
+                0 => {
+                    let dn = primitive(&fields[1]);
+                    let password = primitive(&fields[2]);
+                    // DNs compare ignoring case, as in real directories
+                    let dn = dn.to_lowercase();
+                    let ok = directory.passwords.get(&dn) == Some(&password);
+                    if ok {
+                        bound_dn = Some(dn);
+                    }
+                    send(
+                        &mut stream,
+                        message_id,
+                        ldap_result(1, if ok { 0 } else { 49 }),
+                    )
+                    .await;
+                }
+                // SearchRequest: answer with the bound user's groups
+                3 => {
+                    for (group, members) in &directory.groups {
+                        if !bound_dn.as_ref().is_some_and(|dn| members.contains(dn)) {
+                            continue;
+                        }
+                        let entry = Tag::Sequence(Sequence {
+                            id: 4,
+                            class: TagClass::Application,
+                            inner: vec![
+                                octets(&format!("cn={},ou=groups,dc=example,dc=com", group)),
+                                Tag::Sequence(Sequence {
+                                    inner: vec![Tag::Sequence(Sequence {
+                                        inner: vec![
+                                            octets("cn"),
+                                            Tag::Set(Set {
+                                                inner: vec![octets(group)],
+                                                ..Default::default()
+                                            }),
+                                        ],
+                                        ..Default::default()
+                                    })],
+                                    ..Default::default()
+                                }),
+                            ],
+                        });
+                        send(&mut stream, message_id, entry).await;
+                    }
+                    send(&mut stream, message_id, ldap_result(5, 0)).await;
+                }
+                // UnbindRequest or anything else ends the connection
+                _ => return,
+            }
+        }
+    }
+
+    async fn directory() -> String {
+        let directory = std::sync::Arc::new(Directory {
+            passwords: HashMap::from([(
+                "uid=alice,ou=people,dc=example,dc=com".to_string(),
+                "s3cret".to_string(),
+            )]),
+            groups: vec![
+                (
+                    "eng".to_string(),
+                    vec!["uid=alice,ou=people,dc=example,dc=com".to_string()],
+                ),
+                (
+                    "finance".to_string(),
+                    vec!["uid=bob,ou=people,dc=example,dc=com".to_string()],
+                ),
+            ],
+        });
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let directory = directory.clone();
+                tokio::spawn(async move { serve(&directory, stream).await });
+            }
+        });
+        url
+    }
+
+    fn config(url: &str) -> LdapConfig {
+        LdapConfig {
+            url: url.to_string(),
+            starttls: false,
+            bind_dn: "uid={username},ou=people,dc=example,dc=com".to_string(),
+            group_base_dn: Some("ou=groups,dc=example,dc=com".to_string()),
+            group_filter: "(&(objectClass=groupOfNames)(member={dn}))".to_string(),
+            group_attribute: "cn".to_string(),
+            role_mapping: BTreeMap::from([("eng".to_string(), "engineers".to_string())]),
+            timeout_secs: 5,
+        }
+    }
+
+    fn password<'a>(username: &'a str, password: &'a str) -> Credentials<'a> {
+        Credentials::Password { username, password }
+    }
+
+    #[tokio::test]
+    async fn test_bind_and_group_roles() {
+        let ldap = LdapAuthenticator::new(&config(&directory().await)).unwrap();
+
+        let identity = ldap
+            .authenticate(&password("alice", "s3cret"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(identity.username, "alice");
+        assert_eq!(identity.roles, vec!["engineers"]);
+    }
+
+    #[tokio::test]
+    async fn test_name_case_canonicalized() {
+        let ldap = LdapAuthenticator::new(&config(&directory().await)).unwrap();
+
+        let identity = ldap
+            .authenticate(&password("ALICE", "s3cret"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(identity.username, "alice");
+        assert_eq!(identity.roles, vec!["engineers"]);
+    }
+
+    #[tokio::test]
+    async fn test_wrong_password_rejected() {
+        let ldap = LdapAuthenticator::new(&config(&directory().await)).unwrap();
+
+        assert!(ldap
+            .authenticate(&password("alice", "wrong"))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_empty_password_never_binds() {
+        let ldap = LdapAuthenticator::new(&config("ldap://127.0.0.1:9")).unwrap();
+
+        // Would fail to connect if a bind were attempted
+        assert!(ldap
+            .authenticate(&password("alice", ""))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_directory_is_an_error() {
+        let ldap = LdapAuthenticator::new(&config("ldap://127.0.0.1:9")).unwrap();
+
+        assert!(ldap
+            .authenticate(&password("alice", "s3cret"))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_bind_dn_requires_username_placeholder() {
+        let mut config = config("ldap://localhost");
+        config.bind_dn = "cn=admin,dc=example,dc=com".to_string();
+
+        assert!(LdapAuthenticator::new(&config).is_err());
+    }
+}
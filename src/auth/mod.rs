@@ -0,0 +1,32 @@
+pub mod authenticator;
+pub mod client_cert;
+pub mod ldap;
+pub mod oidc;
+pub mod password;
+pub mod throttle;
+pub mod tokens;
+pub mod totp;
+pub mod users;
+
+use std::sync::Arc;
+
+use crate::middleware::DomainFilter;
+
+pub use authenticator::{Authenticator, Authenticators, Credentials};
+pub use client_cert::{ClientCert, ClientCertAuthenticator};
+pub use ldap::LdapAuthenticator;
+pub use oidc::OidcClient;
+pub use throttle::LoginThrottle;
+pub use tokens::TokenStore;
+pub use totp::TotpStore;
+pub use users::{StaticAuthenticator, User, UserStore};
+
+/// The logged in user, added to request extensions by `require_auth`
+#[derive(Clone, Debug)]
+pub struct CurrentUser {
+    pub username: String,
+    pub roles: Vec<String>,
+    pub source: authenticator::IdentitySource,
+    /// Domain restriction of the API token the request was made with
+    pub token_domains: Option<Arc<DomainFilter>>,
+}
@@ -0,0 +1,67 @@
+use anyhow::{anyhow, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use std::sync::OnceLock;
+
+/// Hash a password with Argon2id and a random salt (PHC string format)
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| anyhow!("Failed to hash password: {}", e))
+}
+
+/// Check a password against a PHC hash string. The comparison is constant-time.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(e) => {
+            tracing::error!("Invalid password hash: {}", e);
+            false
+        }
+    }
+}
+
+/// Burn the same amount of work as a real verification, so unknown or
+/// disabled users cannot be told apart by response time
+pub fn verify_dummy(password: &str) {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    let hash = DUMMY_HASH
+        .get_or_init(|| hash_password("dummy password").expect("hashing a constant succeeds"));
+    let _ = verify_password(password, hash);
+}
+
+pub fn is_password_hash(value: &str) -> bool {
+    value.starts_with("$argon2")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_and_verify() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("wrong horse", &hash));
+    }
+
+    #[test]
+    fn test_salt_is_random() {
+        assert_ne!(
+            hash_password("same").unwrap(),
+            hash_password("same").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_invalid_hash_rejected() {
+        assert!(!verify_password("anything", "not-a-hash"));
+    }
+}
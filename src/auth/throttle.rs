@@ -0,0 +1,344 @@
+use anyhow::{anyhow, Result};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use time::OffsetDateTime;
+
+use crate::config::LockoutConfig;
+use crate::sessions::KeyValueStore;
+
+/// Key-value namespace holding the counters in a persistent session store
+const NAMESPACE: &str = "login";
+
+/// Failed logins for one IP address or username
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+struct Counter {
+    failures: u32,
+    /// Unix time of the latest failure
+    last_failure: u64,
+    /// Unix time the lockout ends, once the threshold was reached
+    locked_until: u64,
+}
+
+/// A counter that just reached its threshold
+#[derive(Debug, PartialEq)]
+pub struct Lockout {
+    /// `ip` or `user`
+    pub scope: &'static str,
+    pub key: String,
+    pub failures: u32,
+    /// Unix time the lockout ends
+    pub until: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Brute-force protection for password logins and second-factor codes.
+///
+/// Failures are counted per client IP and per username. Each failure delays
+/// the next attempt exponentially (`backoff_secs`, doubled per failure); at
+/// `max_failures` for a username or `max_failures_per_ip` for an address
+/// the key is locked out for `lockout_secs`. Counters are forgotten
+/// `lockout_secs` after the last failure, or reset by a successful login.
+///
+/// With a persistent session store the counters are written through to it,
+/// so a restart does not clear them.
+pub struct LoginThrottle {
+    config: LockoutConfig,
+    trusted: Vec<IpNet>,
+    counters: Mutex<HashMap<String, Counter>>,
+    store: Option<Arc<dyn KeyValueStore>>,
+}
+
+impl LoginThrottle {
+    pub async fn new(
+        config: &LockoutConfig,
+        store: Option<Arc<dyn KeyValueStore>>,
+    ) -> Result<Self> {
+        let trusted = config
+            .trusted_ips
+            .iter()
+            .map(|entry| {
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| anyhow!("Invalid trusted IP or CIDR range '{}'", entry))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut counters = HashMap::new();
+        if let Some(store) = &store {
+            for (key, value) in store.entries(NAMESPACE).await? {
+                match serde_json::from_str(&value) {
+                    Ok(counter) => {
+                        counters.insert(key, counter);
+                    }
+                    Err(e) => tracing::warn!("Ignoring stored login counter {}: {}", key, e),
+                }
+            }
+            if !counters.is_empty() {
+                tracing::info!("Loaded {} login failure counter(s)", counters.len());
+            }
+        }
+
+        Ok(Self {
+            config: config.clone(),
+            trusted,
+            counters: Mutex::new(counters),
+            store,
+        })
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Counter keys for an attempt, with their scope and threshold
+    fn keys(&self, ip: IpAddr, username: &str) -> [(&'static str, String, u32); 2] {
+        [
+            ("ip", format!("ip:{}", ip), self.config.max_failures_per_ip),
+            (
+                "user",
+                format!("user:{}", username.to_lowercase()),
+                self.config.max_failures,
+            ),
+        ]
+    }
+
+    /// Unix time until which a counter blocks new attempts
+    fn blocked_until(&self, counter: &Counter, max_failures: u32) -> u64 {
+        if counter.failures >= max_failures {
+            return counter.locked_until;
+        }
+        if counter.failures == 0 {
+            return 0;
+        }
+        let exponent = (counter.failures - 1).min(32);
+        let delay = self
+            .config
+            .backoff_secs
+            .saturating_mul(1u64 << exponent)
+            .min(self.config.lockout_secs);
+        counter.last_failure + delay
+    }
+
+    /// Whether a counter no longer matters and can be dropped
+    fn is_stale(&self, counter: &Counter, now: u64) -> bool {
+        now >= counter.last_failure + self.config.lockout_secs && now >= counter.locked_until
+    }
+
+    /// Seconds until this IP and username may try again, or `None` if a
+    /// login attempt is allowed now
+    pub fn retry_after(&self, ip: IpAddr, username: &str) -> Option<u64> {
+        self.retry_after_at(ip, username, now())
+    }
+
+    fn retry_after_at(&self, ip: IpAddr, username: &str, now: u64) -> Option<u64> {
+        if !self.config.enabled || self.is_trusted(ip) {
+            return None;
+        }
+        let counters = self.counters.lock().unwrap();
+        self.keys(ip, username)
+            .iter()
+            .filter_map(|(_, key, max)| counters.get(key).map(|c| self.blocked_until(c, *max)))
+            .max()
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    /// Count a failed login. Returns the lockouts it triggered.
+    pub async fn record_failure(&self, ip: IpAddr, username: &str) -> Vec<Lockout> {
+        let (lockouts, changed) = self.record_failure_at(ip, username, now());
+        for (key, counter) in changed {
+            self.persist(&key, Some(&counter)).await;
+        }
+        lockouts
+    }
+
+    fn record_failure_at(
+        &self,
+        ip: IpAddr,
+        username: &str,
+        now: u64,
+    ) -> (Vec<Lockout>, Vec<(String, Counter)>) {
+        if !self.config.enabled || self.is_trusted(ip) {
+            return (Vec::new(), Vec::new());
+        }
+
+        let mut counters = self.counters.lock().unwrap();
+        counters.retain(|_, counter| !self.is_stale(counter, now));
+
+        let mut lockouts = Vec::new();
+        let mut changed = Vec::new();
+        for (scope, key, max) in self.keys(ip, username) {
+            let counter = counters.entry(key.clone()).or_default();
+            counter.failures += 1;
+            counter.last_failure = now;
+            if counter.failures >= max {
+                counter.locked_until = now + self.config.lockout_secs;
+                lockouts.push(Lockout {
+                    scope,
+                    key: key
+                        .split_once(':')
+                        .map(|(_, k)| k)
+                        .unwrap_or(&key)
+                        .to_string(),
+                    failures: counter.failures,
+                    until: counter.locked_until,
+                });
+            }
+            changed.push((key, counter.clone()));
+        }
+        (lockouts, changed)
+    }
+
+    /// Reset the username's counter after a successful login. The IP
+    /// counter is kept, so one valid account does not unlock guessing at
+    /// others from the same address.
+    pub async fn record_success(&self, ip: IpAddr, username: &str) {
+        if !self.config.enabled || self.is_trusted(ip) {
+            return;
+        }
+        let [_, (_, key, _)] = self.keys(ip, username);
+        if self.counters.lock().unwrap().remove(&key).is_some() {
+            self.persist(&key, None).await;
+        }
+    }
+
+    /// Write a counter through to the persistent store, if there is one
+    async fn persist(&self, key: &str, counter: Option<&Counter>) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        let result = match counter {
+            Some(counter) => {
+                let expires = counter
+                    .locked_until
+                    .max(counter.last_failure + self.config.lockout_secs);
+                let expiry = OffsetDateTime::from_unix_timestamp(expires as i64)
+                    .unwrap_or(OffsetDateTime::now_utc());
+                let value = serde_json::to_string(counter).unwrap_or_default();
+                store.put(NAMESPACE, key, &value, expiry).await
+            }
+            None => store.remove(NAMESPACE, key).await,
+        };
+        if let Err(e) = result {
+            tracing::error!("Failed to store login counter: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(203, 0, 113, 7));
+
+    fn config() -> LockoutConfig {
+        LockoutConfig {
+            max_failures: 3,
+            max_failures_per_ip: 5,
+            backoff_secs: 2,
+            lockout_secs: 60,
+            ..LockoutConfig::default()
+        }
+    }
+
+    async fn throttle(config: LockoutConfig) -> LoginThrottle {
+        LoginThrottle::new(&config, None).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_backoff_doubles_then_locks_out() {
+        let throttle = throttle(config()).await;
+        assert_eq!(throttle.retry_after_at(IP, "alice", 100), None);
+
+        throttle.record_failure_at(IP, "alice", 100);
+        assert_eq!(throttle.retry_after_at(IP, "alice", 100), Some(2));
+        assert_eq!(throttle.retry_after_at(IP, "alice", 102), None);
+
+        throttle.record_failure_at(IP, "alice", 102);
+        assert_eq!(throttle.retry_after_at(IP, "alice", 102), Some(4));
+
+        let (lockouts, _) = throttle.record_failure_at(IP, "Alice", 110);
+        assert_eq!(
+            lockouts,
+            vec![Lockout {
+                scope: "user",
+                key: "alice".to_string(),
+                failures: 3,
+                until: 170,
+            }]
+        );
+        assert_eq!(throttle.retry_after_at(IP, "alice", 110), Some(60));
+        assert_eq!(throttle.retry_after_at(IP, "alice", 170), None);
+    }
+
+    #[tokio::test]
+    async fn test_ip_locked_out_across_usernames() {
+        let throttle = throttle(LockoutConfig {
+            backoff_secs: 0,
+            ..config()
+        })
+        .await;
+        for (i, user) in ["a", "b", "c", "d"].iter().enumerate() {
+            let (lockouts, _) = throttle.record_failure_at(IP, user, 100 + i as u64);
+            assert!(lockouts.is_empty());
+        }
+        let (lockouts, _) = throttle.record_failure_at(IP, "e", 104);
+        assert_eq!(lockouts[0].scope, "ip");
+        assert_eq!(lockouts[0].key, "203.0.113.7");
+        assert_eq!(throttle.retry_after_at(IP, "someone-else", 104), Some(60));
+
+        let other: IpAddr = "198.51.100.1".parse().unwrap();
+        assert_eq!(throttle.retry_after_at(other, "someone-else", 104), None);
+    }
+
+    #[tokio::test]
+    async fn test_success_resets_user_but_not_ip() {
+        let throttle = throttle(config()).await;
+        throttle.record_failure_at(IP, "alice", 100);
+        throttle.record_success(IP, "alice").await;
+
+        let counters = throttle.counters.lock().unwrap();
+        assert!(!counters.contains_key("user:alice"));
+        assert_eq!(counters["ip:203.0.113.7"].failures, 1);
+    }
+
+    #[tokio::test]
+    async fn test_trusted_and_disabled_never_throttled() {
+        let trusted = throttle(LockoutConfig {
+            trusted_ips: vec!["203.0.113.0/24".to_string()],
+            ..config()
+        })
+        .await;
+        let disabled = throttle(LockoutConfig {
+            enabled: false,
+            ..config()
+        })
+        .await;
+        for throttle in [trusted, disabled] {
+            for _ in 0..10 {
+                throttle.record_failure_at(IP, "alice", 100);
+            }
+            assert_eq!(throttle.retry_after_at(IP, "alice", 100), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_trusted_ip_rejected() {
+        let config = LockoutConfig {
+            trusted_ips: vec!["10.0.0.0/33".to_string()],
+            ..config()
+        };
+        assert!(LoginThrottle::new(&config, None).await.is_err());
+    }
+}
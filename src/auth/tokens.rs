@@ -0,0 +1,451 @@
+use anyhow::{anyhow, bail, Result};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::authenticator::IdentitySource;
+use crate::config::DomainFilterConfig;
+use crate::middleware::DomainFilter;
+
+/// Tokens look like `bpt_<id>_<secret>`; the prefix makes leaked tokens easy
+/// to find with secret scanners
+const TOKEN_PREFIX: &str = "bpt_";
+const ID_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const ID_LEN: usize = 12;
+/// Longest lifetime a user can give a token
+pub const MAX_LIFETIME_DAYS: u64 = 365;
+
+/// A personal API token. Only a hash of the secret is kept.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ApiToken {
+    pub id: String,
+    pub username: String,
+    /// Label chosen by the user
+    pub name: String,
+    /// Roles at creation time, used for users not in the local user store.
+    /// Such tokens expire with the session they were created from.
+    pub roles: Vec<String>,
+    /// How the owner logged in. Only tokens of local accounts follow later
+    /// role changes in the user store.
+    #[serde(default)]
+    pub source: IdentitySource,
+    /// SHA-256 of the secret part
+    hash: String,
+    pub created_at: u64,
+    pub expires_at: u64,
+    /// Domain patterns the token is limited to; empty for all domains the
+    /// user may reach
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// Requests per minute; `None` for no limit
+    #[serde(default)]
+    pub rate_limit: Option<u32>,
+    #[serde(skip)]
+    filter: Option<Arc<DomainFilter>>,
+}
+
+impl ApiToken {
+    /// Filter for the token's domain restriction, if it has one
+    pub fn domain_filter(&self) -> Option<Arc<DomainFilter>> {
+        self.filter.clone()
+    }
+
+    fn build_filter(&mut self) -> Result<()> {
+        self.filter = if self.domains.is_empty() {
+            None
+        } else {
+            Some(Arc::new(DomainFilter::new(&DomainFilterConfig {
+                allowlist: self.domains.clone(),
+                blocklist: Vec::new(),
+                rules: Vec::new(),
+                feeds: Vec::new(),
+                feed_refresh_minutes: 360,
+            })?))
+        };
+        Ok(())
+    }
+}
+
+/// What a user asks for when creating a token
+pub struct TokenRequest {
+    pub name: String,
+    pub lifetime_days: u64,
+    pub domains: Vec<String>,
+    pub rate_limit: Option<u32>,
+}
+
+/// Why a bearer token was refused
+#[derive(Debug, PartialEq)]
+pub enum TokenError {
+    /// Unknown, malformed, revoked or expired
+    Invalid,
+    /// Over the token's rate limit; retry after this many seconds
+    RateLimited(u64),
+}
+
+/// Personal API tokens keyed by ID. Changes are written back to
+/// `auth.tokens_file`.
+pub struct TokenStore {
+    tokens: RwLock<HashMap<String, ApiToken>>,
+    file: Option<PathBuf>,
+    /// Longest lifetime of a token whose owner logged in through a directory
+    /// or SSO. Their roles cannot be rechecked when the token is used, so the
+    /// token lasts no longer than the login its roles came from.
+    external_lifetime_secs: u64,
+    /// Requests per token in the current minute: (minute, count)
+    usage: Mutex<HashMap<String, (u64, u32)>>,
+}
+
+impl TokenStore {
+    pub fn new(file: Option<&str>, external_lifetime_secs: u64) -> Result<Self> {
+        let file = file.map(PathBuf::from);
+        let mut tokens: HashMap<String, ApiToken> = match &file {
+            Some(path) if path.is_file() => {
+                let content = std::fs::read_to_string(path)?;
+                serde_json::from_str(&content)
+                    .map_err(|e| anyhow!("Failed to parse token file {}: {}", path.display(), e))?
+            }
+            _ => HashMap::new(),
+        };
+        for token in tokens.values_mut() {
+            token.build_filter()?;
+        }
+
+        Ok(Self {
+            tokens: RwLock::new(tokens),
+            file,
+            external_lifetime_secs,
+            usage: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Mint a token. Returns its record and the plaintext token, which is
+    /// shown to the user once.
+    pub fn create(
+        &self,
+        username: &str,
+        roles: &[String],
+        source: IdentitySource,
+        request: TokenRequest,
+    ) -> Result<(ApiToken, String)> {
+        let name = request.name.trim();
+        if name.is_empty() {
+            bail!("Token name is required");
+        }
+        if request.lifetime_days == 0 || request.lifetime_days > MAX_LIFETIME_DAYS {
+            bail!("Tokens must expire within 1 to {} days", MAX_LIFETIME_DAYS);
+        }
+        if request.rate_limit == Some(0) {
+            bail!("Rate limit must be at least one request per minute");
+        }
+
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        let secret = URL_SAFE_NO_PAD.encode(secret);
+        let now = now();
+        let mut lifetime = request.lifetime_days * 86400;
+        if !source.is_local_account() {
+            lifetime = lifetime.min(self.external_lifetime_secs);
+        }
+        let mut token = ApiToken {
+            id: token_id(),
+            username: username.to_string(),
+            name: name.to_string(),
+            roles: roles.to_vec(),
+            source,
+            hash: hash_secret(&secret),
+            created_at: now,
+            expires_at: now + lifetime,
+            domains: request.domains,
+            rate_limit: request.rate_limit,
+            filter: None,
+        };
+        token.build_filter()?;
+
+        let plaintext = format!("{}{}_{}", TOKEN_PREFIX, token.id, secret);
+        let mut tokens = self.tokens.write().unwrap();
+        tokens.insert(token.id.clone(), token.clone());
+        self.persist(&tokens)?;
+        Ok((token, plaintext))
+    }
+
+    /// When the token stops working. Tokens of external users issued before
+    /// the cap existed are held to it as well.
+    fn expires_at(&self, token: &ApiToken) -> u64 {
+        if token.source.is_local_account() {
+            token.expires_at
+        } else {
+            token
+                .expires_at
+                .min(token.created_at + self.external_lifetime_secs)
+        }
+    }
+
+    /// The user's tokens, oldest first, including expired ones, each with
+    /// the expiry that applies to it
+    pub fn list(&self, username: &str) -> Vec<ApiToken> {
+        let mut tokens: Vec<ApiToken> = self
+            .tokens
+            .read()
+            .unwrap()
+            .values()
+            .filter(|t| t.username == username)
+            .map(|t| ApiToken {
+                expires_at: self.expires_at(t),
+                ..t.clone()
+            })
+            .collect();
+        tokens.sort_by_key(|t| t.created_at);
+        tokens
+    }
+
+    /// Delete one of the user's tokens. Returns whether it existed.
+    pub fn revoke(&self, username: &str, id: &str) -> Result<bool> {
+        let mut tokens = self.tokens.write().unwrap();
+        if tokens.get(id).is_none_or(|t| t.username != username) {
+            return Ok(false);
+        }
+        tokens.remove(id);
+        self.persist(&tokens)?;
+        self.usage.lock().unwrap().remove(id);
+        Ok(true)
+    }
+
+    /// Check a bearer token and count the request against its rate limit
+    pub fn authenticate(&self, token: &str) -> Result<ApiToken, TokenError> {
+        self.authenticate_at(token, now())
+    }
+
+    fn authenticate_at(&self, token: &str, now: u64) -> Result<ApiToken, TokenError> {
+        let (id, secret) = token
+            .strip_prefix(TOKEN_PREFIX)
+            .and_then(|rest| rest.split_once('_'))
+            .ok_or(TokenError::Invalid)?;
+
+        let token = self
+            .tokens
+            .read()
+            .unwrap()
+            .get(id)
+            .filter(|t| t.hash == hash_secret(secret) && self.expires_at(t) > now)
+            .cloned()
+            .ok_or(TokenError::Invalid)?;
+
+        if let Some(limit) = token.rate_limit {
+            let minute = now / 60;
+            let mut usage = self.usage.lock().unwrap();
+            let (window, count) = usage.entry(token.id.clone()).or_insert((minute, 0));
+            if *window != minute {
+                *window = minute;
+                *count = 0;
+            }
+            if *count >= limit {
+                return Err(TokenError::RateLimited(60 - now % 60));
+            }
+            *count += 1;
+        }
+        Ok(token)
+    }
+
+    fn persist(&self, tokens: &HashMap<String, ApiToken>) -> Result<()> {
+        let Some(path) = &self.file else {
+            tracing::warn!("No auth.tokens_file configured; API tokens will not survive restart");
+            return Ok(());
+        };
+
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(tokens)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn token_id() -> String {
+    let mut bytes = [0u8; ID_LEN];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+        .iter()
+        .map(|b| ID_ALPHABET[*b as usize % ID_ALPHABET.len()] as char)
+        .collect()
+}
+
+/// Secrets are 256 random bits, so a fast hash is enough
+fn hash_secret(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL: IdentitySource = IdentitySource::Local;
+
+    fn request(domains: &[&str], rate_limit: Option<u32>) -> TokenRequest {
+        TokenRequest {
+            name: "scraper".to_string(),
+            lifetime_days: 30,
+            domains: domains.iter().map(|d| d.to_string()).collect(),
+            rate_limit,
+        }
+    }
+
+    #[test]
+    fn test_token_authenticates_until_revoked() {
+        let store = TokenStore::new(None, 43200).unwrap();
+        let (token, plaintext) = store
+            .create("alice", &["staff".to_string()], LOCAL, request(&[], None))
+            .unwrap();
+        assert!(plaintext.starts_with("bpt_"));
+        assert!(!serde_json::to_string(&token)
+            .unwrap()
+            .contains(&plaintext[17..]));
+
+        let found = store.authenticate(&plaintext).unwrap();
+        assert_eq!(found.username, "alice");
+        assert_eq!(found.roles, vec!["staff".to_string()]);
+
+        assert!(!store.revoke("bob", &token.id).unwrap());
+        assert!(store.revoke("alice", &token.id).unwrap());
+        assert_eq!(
+            store.authenticate(&plaintext).unwrap_err(),
+            TokenError::Invalid
+        );
+    }
+
+    #[test]
+    fn test_wrong_secret_and_expiry_rejected() {
+        let store = TokenStore::new(None, 43200).unwrap();
+        let (token, plaintext) = store
+            .create("alice", &[], LOCAL, request(&[], None))
+            .unwrap();
+
+        let forged = format!("bpt_{}_{}", token.id, "A".repeat(43));
+        assert_eq!(
+            store.authenticate(&forged).unwrap_err(),
+            TokenError::Invalid
+        );
+        assert_eq!(
+            store.authenticate("not-a-token").unwrap_err(),
+            TokenError::Invalid
+        );
+        assert_eq!(
+            store
+                .authenticate_at(&plaintext, token.expires_at)
+                .unwrap_err(),
+            TokenError::Invalid
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_per_minute() {
+        let store = TokenStore::new(None, 43200).unwrap();
+        let (_, plaintext) = store
+            .create("alice", &[], LOCAL, request(&[], Some(2)))
+            .unwrap();
+
+        assert!(store.authenticate_at(&plaintext, 6000).is_ok());
+        assert!(store.authenticate_at(&plaintext, 6010).is_ok());
+        assert_eq!(
+            store.authenticate_at(&plaintext, 6015).unwrap_err(),
+            TokenError::RateLimited(45)
+        );
+        assert!(store.authenticate_at(&plaintext, 6060).is_ok());
+    }
+
+    #[test]
+    fn test_domain_restriction() {
+        let store = TokenStore::new(None, 43200).unwrap();
+        let (token, _) = store
+            .create("alice", &[], LOCAL, request(&["*.example.com"], None))
+            .unwrap();
+
+        let filter = token.domain_filter().unwrap();
+        assert!(filter.is_allowed("docs.example.com"));
+        assert!(!filter.is_allowed("example.org"));
+    }
+
+    #[test]
+    fn test_external_user_tokens_capped_to_session_lifetime() {
+        let store = TokenStore::new(None, 3600).unwrap();
+        let (token, plaintext) = store
+            .create("carol", &[], IdentitySource::Ldap, request(&[], None))
+            .unwrap();
+        assert_eq!(token.expires_at, token.created_at + 3600);
+        assert!(store
+            .authenticate_at(&plaintext, token.created_at + 3599)
+            .is_ok());
+        assert_eq!(
+            store
+                .authenticate_at(&plaintext, token.created_at + 3600)
+                .unwrap_err(),
+            TokenError::Invalid
+        );
+
+        // A token issued with a longer lifetime is held to the cap too
+        let mut long_lived = token.clone();
+        long_lived.expires_at = token.created_at + 30 * 86400;
+        store
+            .tokens
+            .write()
+            .unwrap()
+            .insert(long_lived.id.clone(), long_lived);
+        assert!(store
+            .authenticate_at(&plaintext, token.created_at + 7200)
+            .is_err());
+
+        let (local, _) = store
+            .create("alice", &[], LOCAL, request(&[], None))
+            .unwrap();
+        assert_eq!(local.expires_at, local.created_at + 30 * 86400);
+    }
+
+    #[test]
+    fn test_invalid_requests_rejected() {
+        let store = TokenStore::new(None, 43200).unwrap();
+        let mut no_name = request(&[], None);
+        no_name.name = " ".to_string();
+        assert!(store.create("alice", &[], LOCAL, no_name).is_err());
+
+        let mut forever = request(&[], None);
+        forever.lifetime_days = MAX_LIFETIME_DAYS + 1;
+        assert!(store.create("alice", &[], LOCAL, forever).is_err());
+
+        assert!(store
+            .create("alice", &[], LOCAL, request(&[], Some(0)))
+            .is_err());
+    }
+
+    #[test]
+    fn test_tokens_survive_reload() {
+        let dir = std::env::temp_dir().join(format!("browser_proxy_tokens_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tokens.json");
+        let path = path.to_str().unwrap();
+
+        let (_, plaintext) = TokenStore::new(Some(path), 43200)
+            .unwrap()
+            .create("alice", &[], LOCAL, request(&["example.com"], None))
+            .unwrap();
+
+        let reloaded = TokenStore::new(Some(path), 43200).unwrap();
+        let token = reloaded.authenticate(&plaintext).unwrap();
+        assert!(token.domain_filter().unwrap().is_allowed("example.com"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,294 @@
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use totp_rs::{Builder, Secret, Totp};
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// A user's confirmed TOTP enrollment
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TotpEnrollment {
+    /// Base32 shared secret
+    secret: String,
+    /// SHA-256 hashes of the unused recovery codes
+    recovery_codes: Vec<String>,
+    /// Last time step accepted, so a code cannot be replayed
+    #[serde(default)]
+    last_step: u64,
+}
+
+/// A secret shown to the user but not yet confirmed with a valid code
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PendingEnrollment {
+    pub secret: String,
+}
+
+/// TOTP (RFC 6238) enrollments keyed by username, for local, LDAP and SSO
+/// users alike. Changes are written back to `auth.totp_file`.
+pub struct TotpStore {
+    issuer: String,
+    enrollments: RwLock<HashMap<String, TotpEnrollment>>,
+    file: Option<PathBuf>,
+}
+
+impl TotpStore {
+    pub fn new(issuer: &str, file: Option<&str>) -> Result<Self> {
+        let file = file.map(PathBuf::from);
+        let enrollments = match &file {
+            Some(path) if path.is_file() => {
+                serde_json::from_str(&std::fs::read_to_string(path)?)
+                    .map_err(|e| anyhow!("Failed to parse TOTP file {}: {}", path.display(), e))?
+            }
+            _ => HashMap::new(),
+        };
+
+        Ok(Self {
+            issuer: issuer.to_string(),
+            enrollments: RwLock::new(enrollments),
+            file,
+        })
+    }
+
+    pub fn is_enrolled(&self, username: &str) -> bool {
+        self.enrollments.read().unwrap().contains_key(username)
+    }
+
+    pub fn recovery_codes_left(&self, username: &str) -> usize {
+        self.enrollments
+            .read()
+            .unwrap()
+            .get(username)
+            .map(|e| e.recovery_codes.len())
+            .unwrap_or(0)
+    }
+
+    /// Generate a new secret for the user to add to their authenticator app
+    pub fn begin_enrollment(&self) -> PendingEnrollment {
+        let mut bytes = [0u8; 20];
+        OsRng.fill_bytes(&mut bytes);
+        PendingEnrollment {
+            secret: Secret::from(bytes).to_base32(),
+        }
+    }
+
+    /// QR code (base64 PNG) and otpauth:// URL for a pending enrollment
+    pub fn provisioning(
+        &self,
+        username: &str,
+        pending: &PendingEnrollment,
+    ) -> Result<(String, String)> {
+        let totp = self.totp(&pending.secret, username)?;
+        let url = totp.to_url()?;
+        let qr = totp.to_qr_base64()?;
+        Ok((qr, url))
+    }
+
+    /// Confirm an enrollment with a code from the authenticator app. Returns
+    /// the plaintext recovery codes, which are shown to the user once.
+    pub fn confirm_enrollment(
+        &self,
+        username: &str,
+        pending: &PendingEnrollment,
+        code: &str,
+    ) -> Result<Option<Vec<String>>> {
+        let Some(step) = self
+            .totp(&pending.secret, username)?
+            .check(code.trim(), now())
+        else {
+            return Ok(None);
+        };
+
+        let codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| recovery_code()).collect();
+        let mut enrollments = self.enrollments.write().unwrap();
+        enrollments.insert(
+            username.to_string(),
+            TotpEnrollment {
+                secret: pending.secret.clone(),
+                recovery_codes: codes.iter().map(|c| hash_recovery_code(c)).collect(),
+                last_step: step,
+            },
+        );
+        self.persist(&enrollments)?;
+        Ok(Some(codes))
+    }
+
+    /// Check a TOTP code or, failing that, a one-time recovery code
+    pub fn verify(&self, username: &str, code: &str) -> Result<bool> {
+        let mut enrollments = self.enrollments.write().unwrap();
+        let Some(enrollment) = enrollments.get_mut(username) else {
+            return Ok(false);
+        };
+
+        let code = code.trim();
+        if let Some(step) = self.totp(&enrollment.secret, username)?.check(code, now()) {
+            if step <= enrollment.last_step {
+                tracing::warn!("Replayed TOTP code for user: {}", username);
+                return Ok(false);
+            }
+            enrollment.last_step = step;
+            self.persist(&enrollments)?;
+            return Ok(true);
+        }
+
+        let hash = hash_recovery_code(code);
+        if let Some(index) = enrollment.recovery_codes.iter().position(|c| *c == hash) {
+            enrollment.recovery_codes.remove(index);
+            tracing::info!(
+                "User {} used a recovery code ({} left)",
+                username,
+                enrollment.recovery_codes.len()
+            );
+            self.persist(&enrollments)?;
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    fn totp(&self, secret: &str, username: &str) -> Result<Totp> {
+        let secret = Secret::try_from_base32(secret).map_err(|e| anyhow!("{}", e))?;
+        Ok(Builder::new()
+            .with_secret(secret)
+            .with_issuer(Some(self.issuer.replace(':', "")))
+            .with_account_name(username.replace(':', "_"))
+            .build()?)
+    }
+
+    fn persist(&self, enrollments: &HashMap<String, TotpEnrollment>) -> Result<()> {
+        let Some(path) = &self.file else {
+            tracing::warn!(
+                "No auth.totp_file configured; TOTP enrollments will not survive restart"
+            );
+            return Ok(());
+        };
+
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(enrollments)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Ten characters from an unambiguous alphabet, formatted as `xxxxx-xxxxx`
+fn recovery_code() -> String {
+    let mut bytes = [0u8; 10];
+    OsRng.fill_bytes(&mut bytes);
+    let chars: String = bytes
+        .iter()
+        .map(|b| RECOVERY_CODE_ALPHABET[*b as usize % RECOVERY_CODE_ALPHABET.len()] as char)
+        .collect();
+    format!("{}-{}", &chars[..5], &chars[5..])
+}
+
+/// Recovery codes are random, so a fast hash is enough to keep them from
+/// being usable if the file leaks
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    Sha256::digest(normalized.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn current_code(store: &TotpStore, secret: &str, offset_steps: i64) -> String {
+        let time = (now() as i64 + offset_steps * 30) as u64;
+        store
+            .totp(secret, "alice")
+            .unwrap()
+            .generate(time)
+            .to_string()
+    }
+
+    fn enrolled() -> (TotpStore, String, Vec<String>) {
+        let store = TotpStore::new("Browser Proxy", None).unwrap();
+        let pending = store.begin_enrollment();
+        let code = current_code(&store, &pending.secret, -1);
+        let recovery = store
+            .confirm_enrollment("alice", &pending, &code)
+            .unwrap()
+            .unwrap();
+        (store, pending.secret, recovery)
+    }
+
+    #[test]
+    fn test_wrong_code_does_not_enroll() {
+        let store = TotpStore::new("Browser Proxy", None).unwrap();
+        let pending = store.begin_enrollment();
+
+        assert!(store
+            .confirm_enrollment("alice", &pending, "000000x")
+            .unwrap()
+            .is_none());
+        assert!(!store.is_enrolled("alice"));
+    }
+
+    #[test]
+    fn test_provisioning_url() {
+        let store = TotpStore::new("Browser Proxy", None).unwrap();
+        let pending = store.begin_enrollment();
+
+        let (qr, url) = store.provisioning("alice", &pending).unwrap();
+        assert!(url.starts_with("otpauth://totp/Browser%20Proxy:alice?"));
+        assert!(url.contains(&format!("secret={}", pending.secret)));
+        assert!(!qr.is_empty());
+    }
+
+    #[test]
+    fn test_verify_code_once() {
+        let (store, secret, _) = enrolled();
+        let code = current_code(&store, &secret, 0);
+
+        assert!(store.verify("alice", &code).unwrap());
+        assert!(!store.verify("alice", &code).unwrap());
+    }
+
+    #[test]
+    fn test_code_older_than_enrollment_rejected() {
+        let (store, secret, _) = enrolled();
+
+        assert!(!store
+            .verify("alice", &current_code(&store, &secret, -1))
+            .unwrap());
+    }
+
+    #[test]
+    fn test_recovery_code_is_single_use() {
+        let (store, _, recovery) = enrolled();
+        assert_eq!(recovery.len(), RECOVERY_CODE_COUNT);
+
+        let code = recovery[3].to_uppercase();
+        assert!(store.verify("alice", &code).unwrap());
+        assert!(!store.verify("alice", &code).unwrap());
+        assert_eq!(store.recovery_codes_left("alice"), RECOVERY_CODE_COUNT - 1);
+    }
+
+    #[test]
+    fn test_unknown_user_rejected() {
+        let (store, secret, _) = enrolled();
+
+        assert!(!store
+            .verify("bob", &current_code(&store, &secret, 0))
+            .unwrap());
+    }
+}
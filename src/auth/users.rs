@@ -0,0 +1,395 @@
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use super::authenticator::{Authenticator, Credentials, Identity, IdentitySource};
+use super::password::{hash_password, is_password_hash, verify_dummy, verify_password};
+use crate::config::AuthConfig;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct User {
+    pub username: String,
+    /// Argon2id PHC string, see `browser_proxy hash-password`
+    pub password_hash: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Roles selecting the user's domain policy, see `[roles.<name>]`
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Require a TOTP check for this user even if `auth.require_2fa` is off
+    #[serde(default)]
+    pub require_2fa: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Local user accounts, loaded from `[[auth.users]]` and an optional JSON
+/// users file. Users defined in config.toml are read-only here; changes to
+/// the others are written back to the users file.
+pub struct UserStore {
+    users: RwLock<HashMap<String, User>>,
+    /// Names defined in config.toml, which always wins over the users file
+    configured: HashSet<String>,
+    file: Option<PathBuf>,
+}
+
+impl UserStore {
+    pub fn from_config(config: &AuthConfig) -> Result<Self> {
+        let mut users = HashMap::new();
+
+        for user in &config.users {
+            if !is_password_hash(&user.password_hash) {
+                bail!(
+                    "User '{}' has no valid password_hash. Generate one with `browser_proxy hash-password`",
+                    user.username
+                );
+            }
+            users.insert(user.username.clone(), user.clone());
+        }
+
+        // Legacy single user with a plaintext password
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            tracing::warn!(
+                "auth.password for '{}' is stored in plaintext. Replace it with a \
+                 [[auth.users]] entry using `browser_proxy hash-password`",
+                username
+            );
+            users.insert(
+                username.clone(),
+                User {
+                    username: username.clone(),
+                    password_hash: hash_password(password)?,
+                    enabled: true,
+                    roles: Vec::new(),
+                    require_2fa: false,
+                },
+            );
+        }
+
+        let file = config.users_file.as_ref().map(PathBuf::from);
+        if let Some(path) = &file
+            && path.is_file()
+        {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read users file {}", path.display()))?;
+            let file_users: Vec<User> = serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse users file {}", path.display()))?;
+            let configured: HashSet<&String> = users.keys().collect();
+            let file_users: Vec<User> = file_users
+                .into_iter()
+                .filter(|user| {
+                    let shadowed = configured.contains(&user.username);
+                    if shadowed {
+                        tracing::warn!(
+                            "Ignoring user '{}' in {}: it is defined in config.toml",
+                            user.username,
+                            path.display()
+                        );
+                    }
+                    !shadowed
+                })
+                .collect();
+            for user in file_users {
+                users.insert(user.username.clone(), user);
+            }
+        }
+
+        if users.is_empty()
+            && config.oidc.is_none()
+            && config.ldap.is_none()
+            && config.client_cert.is_none()
+        {
+            bail!("No users configured. Add [[auth.users]] entries to config.toml");
+        }
+
+        let configured = config
+            .users
+            .iter()
+            .map(|u| u.username.clone())
+            .chain(
+                config
+                    .username
+                    .clone()
+                    .filter(|_| config.password.is_some()),
+            )
+            .collect();
+
+        Ok(Self {
+            users: RwLock::new(users),
+            configured,
+            file,
+        })
+    }
+
+    /// Check credentials, returning the user only if the password matches
+    /// and the account is enabled
+    pub fn verify(&self, username: &str, password: &str) -> Option<User> {
+        let user = self.get(username);
+
+        match user {
+            Some(user) if user.enabled => {
+                verify_password(password, &user.password_hash).then_some(user)
+            }
+            Some(user) => {
+                verify_dummy(password);
+                tracing::warn!("Login attempt for disabled user: {}", user.username);
+                None
+            }
+            None => {
+                verify_dummy(password);
+                None
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.read().unwrap().is_empty()
+    }
+
+    pub fn get(&self, username: &str) -> Option<User> {
+        self.users.read().unwrap().get(username).cloned()
+    }
+
+    /// Whether a user exists whose name matches ignoring case
+    pub fn contains_ignoring_case(&self, username: &str) -> bool {
+        let username = username.to_lowercase();
+        self.users
+            .read()
+            .unwrap()
+            .keys()
+            .any(|name| name.to_lowercase() == username)
+    }
+
+    /// All users, sorted by username
+    pub fn list(&self) -> Vec<User> {
+        let mut users: Vec<User> = self.users.read().unwrap().values().cloned().collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        users
+    }
+
+    /// Whether the user is defined in config.toml and so cannot be changed
+    pub fn is_configured(&self, username: &str) -> bool {
+        self.configured.contains(username)
+    }
+
+    /// Add or replace a user, persisting the change to the users file before
+    /// it takes effect
+    pub fn upsert(&self, user: User) -> Result<()> {
+        if self.is_configured(&user.username) {
+            bail!(
+                "User '{}' is defined in config.toml; change it there",
+                user.username
+            );
+        }
+
+        let mut users = self.users.write().unwrap();
+        let mut updated = users.clone();
+        updated.insert(user.username.clone(), user);
+        self.persist(&updated)?;
+        *users = updated;
+        Ok(())
+    }
+
+    fn persist(&self, users: &HashMap<String, User>) -> Result<()> {
+        let Some(path) = &self.file else {
+            tracing::warn!("No auth.users_file configured; user changes will not survive restart");
+            return Ok(());
+        };
+
+        let mut sorted: Vec<&User> = users
+            .values()
+            .filter(|u| !self.is_configured(&u.username))
+            .collect();
+        sorted.sort_by(|a, b| a.username.cmp(&b.username));
+
+        // Write to a temporary file first so a crash never leaves a half-written file
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(&sorted)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Authenticates against the local accounts in a `UserStore`
+pub struct StaticAuthenticator {
+    users: Arc<UserStore>,
+}
+
+impl StaticAuthenticator {
+    pub fn new(users: Arc<UserStore>) -> Self {
+        Self { users }
+    }
+}
+
+#[async_trait]
+impl Authenticator for StaticAuthenticator {
+    fn name(&self) -> &'static str {
+        "static"
+    }
+
+    fn accepts_passwords(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<Option<Identity>> {
+        let Credentials::Password { username, password } = credentials else {
+            return Ok(None);
+        };
+
+        // Argon2 is CPU heavy, keep it off the async workers
+        let users = self.users.clone();
+        let (username, password) = (username.to_string(), password.to_string());
+        let user = tokio::task::spawn_blocking(move || users.verify(&username, &password)).await?;
+
+        Ok(user.map(|user| Identity {
+            username: user.username,
+            roles: user.roles,
+            source: IdentitySource::Local,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LockoutConfig;
+
+    fn config(users: Vec<User>) -> AuthConfig {
+        AuthConfig {
+            username: None,
+            password: None,
+            users,
+            users_file: None,
+            oidc: None,
+            ldap: None,
+            require_2fa: false,
+            totp_file: None,
+            totp_issuer: "Browser Proxy".to_string(),
+            tokens_file: None,
+            client_cert: None,
+            lockout: LockoutConfig::default(),
+        }
+    }
+
+    fn user(username: &str, password: &str, enabled: bool) -> User {
+        User {
+            username: username.to_string(),
+            password_hash: hash_password(password).unwrap(),
+            enabled,
+            roles: Vec::new(),
+            require_2fa: false,
+        }
+    }
+
+    #[test]
+    fn test_verify_hashed_user() {
+        let store = UserStore::from_config(&config(vec![user("alice", "s3cret", true)])).unwrap();
+
+        assert!(store.verify("alice", "s3cret").is_some());
+        assert!(store.verify("alice", "wrong").is_none());
+        assert!(store.verify("bob", "s3cret").is_none());
+    }
+
+    #[test]
+    fn test_disabled_user_rejected() {
+        let store = UserStore::from_config(&config(vec![user("alice", "s3cret", false)])).unwrap();
+
+        assert!(store.verify("alice", "s3cret").is_none());
+    }
+
+    #[test]
+    fn test_legacy_plaintext_user() {
+        let mut config = config(vec![]);
+        config.username = Some("admin".to_string());
+        config.password = Some("changeme".to_string());
+
+        let store = UserStore::from_config(&config).unwrap();
+        assert!(store.verify("admin", "changeme").is_some());
+    }
+
+    #[test]
+    fn test_plaintext_in_password_hash_rejected() {
+        let mut bad = user("alice", "x", true);
+        bad.password_hash = "hunter2".to_string();
+
+        assert!(UserStore::from_config(&config(vec![bad])).is_err());
+    }
+
+    #[test]
+    fn test_no_users_rejected() {
+        assert!(UserStore::from_config(&config(vec![])).is_err());
+    }
+
+    #[test]
+    fn test_upsert_persists_to_users_file() {
+        let dir = std::env::temp_dir().join(format!("browser_proxy_users_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("users.json");
+
+        let mut config = config(vec![user("alice", "s3cret", true)]);
+        config.users_file = Some(path.to_string_lossy().to_string());
+
+        let store = UserStore::from_config(&config).unwrap();
+        store.upsert(user("bob", "hunter2", true)).unwrap();
+
+        let reloaded = UserStore::from_config(&config).unwrap();
+        assert!(reloaded.verify("bob", "hunter2").is_some());
+
+        // Only the users the file manages are written to it
+        let saved: Vec<User> =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].username, "bob");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_config_users_win_over_users_file() {
+        let dir =
+            std::env::temp_dir().join(format!("browser_proxy_users_config_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("users.json");
+        std::fs::write(
+            &path,
+            serde_json::to_string(&vec![user("alice", "old", true)]).unwrap(),
+        )
+        .unwrap();
+
+        let mut config = config(vec![user("alice", "s3cret", true)]);
+        config.users_file = Some(path.to_string_lossy().to_string());
+
+        let store = UserStore::from_config(&config).unwrap();
+        assert!(store.verify("alice", "s3cret").is_some());
+        assert!(store.verify("alice", "old").is_none());
+
+        assert!(store.upsert(user("alice", "changed", true)).is_err());
+        assert!(store.verify("alice", "s3cret").is_some());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_write_leaves_users_unchanged() {
+        let dir = std::env::temp_dir().join(format!(
+            "browser_proxy_users_unwritable_{}",
+            std::process::id()
+        ));
+        let mut config = config(vec![user("alice", "s3cret", true)]);
+        // The parent directory does not exist, so the write fails
+        config.users_file = Some(dir.join("missing/users.json").to_string_lossy().to_string());
+
+        let store = UserStore::from_config(&config).unwrap();
+        assert!(store.upsert(user("bob", "hunter2", true)).is_err());
+        assert!(store.get("bob").is_none());
+    }
+}
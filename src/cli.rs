@@ -0,0 +1,69 @@
+use anyhow::{bail, Result};
+use std::io::{BufRead, Write};
+
+use crate::auth::password::hash_password;
+use crate::auth::{User, UserStore};
+use crate::config::Config;
+
+const USAGE: &str = "Usage:
+  browser_proxy                    Start the proxy server
+  browser_proxy hash-password      Read a password from stdin and print its Argon2id hash
+  browser_proxy add-user <name> [role...]
+                                   Add or update a user in auth.users_file";
+
+/// Management commands, run instead of the server when arguments are given
+pub fn run(args: &[String]) -> Result<()> {
+    match args.first().map(String::as_str) {
+        Some("hash-password") => {
+            let password = read_password()?;
+            println!("{}", hash_password(&password)?);
+        }
+        Some("add-user") => {
+            let Some(username) = args.get(1) else {
+                bail!("{}", USAGE);
+            };
+
+            let config = Config::load("config.toml")?;
+            if config.auth.users_file.is_none() {
+                bail!("Set auth.users_file in config.toml to manage users from the command line");
+            }
+
+            let store = UserStore::from_config(&config.auth)?;
+            // Keep an existing user's roles unless new ones are given
+            let existing = store.get(username);
+            let roles = match &args[2..] {
+                [] => existing
+                    .as_ref()
+                    .map(|u| u.roles.clone())
+                    .unwrap_or_default(),
+                roles => roles.to_vec(),
+            };
+            let password = read_password()?;
+            store.upsert(User {
+                username: username.clone(),
+                password_hash: hash_password(&password)?,
+                enabled: true,
+                roles,
+                require_2fa: existing.is_some_and(|u| u.require_2fa),
+            })?;
+            println!("Saved user {}", username);
+        }
+        _ => bail!("{}", USAGE),
+    }
+
+    Ok(())
+}
+
+fn read_password() -> Result<String> {
+    eprint!("Password: ");
+    std::io::stderr().flush()?;
+
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    let password = line.trim_end_matches(['\r', '\n']).to_string();
+
+    if password.is_empty() {
+        bail!("Password must not be empty");
+    }
+    Ok(password)
+}
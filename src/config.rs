@@ -1,25 +1,226 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::env;
 
+use crate::auth::User;
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     pub server: ServerConfig,
     pub auth: AuthConfig,
     pub domain_filter: DomainFilterConfig,
     pub logging: LoggingConfig,
+    #[serde(default)]
+    pub access_log: AccessLogConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub session: SessionConfig,
+    #[serde(default)]
+    pub audit_log: AuditLogConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub learning: LearningConfig,
+    #[serde(default)]
+    pub ssrf: SsrfConfig,
+    /// Per-role domain policies, keyed by role name
+    #[serde(default)]
+    pub roles: BTreeMap<String, DomainFilterConfig>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
+    /// How long in-flight requests may drain after SIGTERM/SIGINT
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    /// How long `/readyz` fails before the listener stops, so load balancers
+    /// take the instance out of rotation while it still accepts requests
+    #[serde(default = "default_drain_delay_secs")]
+    pub drain_delay_secs: u64,
+    /// Serve HTTPS directly instead of plain HTTP
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first
+    pub cert_path: String,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1)
+    pub key_path: String,
+    /// PEM bundle of CAs whose client certificates are accepted. Clients
+    /// without a certificate can still log in with a password.
+    pub client_ca_path: Option<String>,
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+fn default_drain_delay_secs() -> u64 {
+    5
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AuthConfig {
-    pub username: String,
-    pub password: String,
+    /// Legacy single user. The plaintext password triggers a startup warning;
+    /// prefer `[[auth.users]]` with hashed passwords.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub users: Vec<User>,
+    /// JSON file holding additional users; written back when users change
+    pub users_file: Option<String>,
+    /// OpenID Connect single sign-on, offered next to (or instead of) passwords
+    pub oidc: Option<OidcConfig>,
+    /// LDAP / Active Directory simple bind, tried after local users
+    pub ldap: Option<LdapConfig>,
+    /// Require every user to pass a TOTP check (users enroll at first login)
+    #[serde(default)]
+    pub require_2fa: bool,
+    /// JSON file holding TOTP enrollments; written back when they change
+    pub totp_file: Option<String>,
+    /// Issuer name shown in authenticator apps
+    #[serde(default = "default_totp_issuer")]
+    pub totp_issuer: String,
+    /// JSON file holding personal API tokens; written back when they change
+    pub tokens_file: Option<String>,
+    #[serde(default)]
+    pub lockout: LockoutConfig,
+    /// Log in by verified TLS client certificate (needs `server.tls.client_ca_path`)
+    pub client_cert: Option<ClientCertConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ClientCertConfig {
+    /// Certificate field naming the user: `subject_cn`, `san_email`,
+    /// `san_dns` or `san_uri`
+    #[serde(default = "default_cert_identity")]
+    pub identity: String,
+    /// Maps certificate identities to usernames. When empty the identity is
+    /// the username; otherwise unmapped certificates are refused.
+    #[serde(default)]
+    pub users: BTreeMap<String, String>,
+}
+
+fn default_cert_identity() -> String {
+    "subject_cn".to_string()
+}
+
+fn default_totp_issuer() -> String {
+    "Browser Proxy".to_string()
+}
+
+/// Brute-force protection for password logins
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LockoutConfig {
+    pub enabled: bool,
+    /// Failures for one username before it is locked out
+    pub max_failures: u32,
+    /// Failures from one IP address (across usernames) before it is locked out
+    pub max_failures_per_ip: u32,
+    /// Delay after the first failure; doubles with each further failure
+    pub backoff_secs: u64,
+    /// How long a lockout lasts, and how long failures are remembered
+    pub lockout_secs: u64,
+    /// IP addresses or CIDR ranges that are never throttled
+    pub trusted_ips: Vec<String>,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_failures: 5,
+            max_failures_per_ip: 20,
+            backoff_secs: 1,
+            lockout_secs: 900,
+            trusted_ips: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OidcConfig {
+    /// Issuer URL; discovery is read from `<issuer>/.well-known/openid-configuration`
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Callback URL registered with the identity provider, ending in `/auth/oidc/callback`
+    pub redirect_url: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+    /// ID token claim used as the username (falls back to `sub`)
+    #[serde(default = "default_username_claim")]
+    pub username_claim: String,
+    /// ID token claim holding the user's groups
+    #[serde(default = "default_roles_claim")]
+    pub roles_claim: String,
+    /// Maps group names from `roles_claim` to proxy roles. When empty, group
+    /// names are used as roles directly; otherwise unmapped groups are ignored.
+    #[serde(default)]
+    pub role_mapping: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LdapConfig {
+    /// `ldap://` or `ldaps://` URL of the directory server
+    pub url: String,
+    /// Upgrade `ldap://` connections with StartTLS
+    #[serde(default)]
+    pub starttls: bool,
+    /// DN to bind as, with `{username}` replaced, e.g.
+    /// `uid={username},ou=people,dc=example,dc=com` or `{username}@corp.example.com`
+    pub bind_dn: String,
+    /// Where to search for the user's groups; groups are not looked up when unset
+    pub group_base_dn: Option<String>,
+    /// Group search filter; `{dn}` is the bind DN and `{username}` the login name
+    #[serde(default = "default_group_filter")]
+    pub group_filter: String,
+    /// Attribute holding the group name
+    #[serde(default = "default_group_attribute")]
+    pub group_attribute: String,
+    /// Maps group names to proxy roles, as for `auth.oidc.role_mapping`
+    #[serde(default)]
+    pub role_mapping: BTreeMap<String, String>,
+    #[serde(default = "default_ldap_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_group_filter() -> String {
+    "(&(objectClass=groupOfNames)(member={dn}))".to_string()
+}
+
+fn default_group_attribute() -> String {
+    "cn".to_string()
+}
+
+fn default_ldap_timeout_secs() -> u64 {
+    5
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec![
+        "openid".to_string(),
+        "profile".to_string(),
+        "email".to_string(),
+    ]
+}
+
+fn default_username_claim() -> String {
+    "preferred_username".to_string()
+}
+
+fn default_roles_claim() -> String {
+    "groups".to_string()
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -28,6 +229,20 @@ pub struct DomainFilterConfig {
     pub allowlist: Vec<String>,
     #[serde(default)]
     pub blocklist: Vec<String>,
+    /// `allow <pattern>` / `deny <pattern>` entries checked in order before
+    /// the lists; the first that matches decides
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<String>,
+    /// Blocklists in hosts or Adblock Plus domain format, by local path or
+    /// http(s) URL. Only read from `[domain_filter]`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub feeds: Vec<String>,
+    #[serde(default = "default_feed_refresh_minutes")]
+    pub feed_refresh_minutes: u64,
+}
+
+fn default_feed_refresh_minutes() -> u64 {
+    360
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -37,6 +252,225 @@ pub struct LoggingConfig {
     pub log_requests: bool,
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AccessLogConfig {
+    pub enabled: bool,
+    pub path: String,
+    /// Line format: common, combined or json
+    pub format: String,
+    /// Rotate once the file grows past this many megabytes (0 disables)
+    pub max_size_mb: u64,
+    /// Time-based rotation: never, hourly or daily
+    pub rotate: String,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "logs/access.log".to_string(),
+            format: "combined".to_string(),
+            max_size_mb: 100,
+            rotate: "daily".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub path: String,
+    /// Serve metrics on a separate port instead of the main listener
+    pub admin_port: Option<u16>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "/metrics".to_string(),
+            admin_port: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TelemetryConfig {
+    /// Export spans over OTLP/HTTP (requires the `otel` feature)
+    pub enabled: bool,
+    /// Collector base URL; `/v1/traces` is appended
+    pub otlp_endpoint: String,
+    pub service_name: String,
+    /// Upstream domains that receive `traceparent` headers (opt-in)
+    #[serde(default)]
+    pub propagate_domains: Vec<String>,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            otlp_endpoint: "http://localhost:4318".to_string(),
+            service_name: env!("CARGO_PKG_NAME").to_string(),
+            propagate_domains: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuditLogConfig {
+    /// Also write security events (logins, lockouts, ...) as JSON lines to
+    /// `path`; they are always logged under the `audit` target
+    pub enabled: bool,
+    pub path: String,
+}
+
+impl Default for AuditLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "logs/audit.log".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    /// Serve the `/api/admin/` endpoints for changing domain rules at runtime
+    pub enabled: bool,
+    /// Role a user (or their API token) needs to use them
+    pub role: String,
+    /// JSON file holding the global allowlist and blocklist as changed at
+    /// runtime. Once written it takes precedence over `[domain_filter]`.
+    pub policy_file: Option<String>,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            role: "admin".to_string(),
+            policy_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LearningConfig {
+    /// Queue domains refused by default-deny for review in the admin console
+    pub enabled: bool,
+    /// How long an admin's "allow everything this site needs" window lasts
+    pub auto_allow_minutes: u64,
+}
+
+impl Default for LearningConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            auto_allow_minutes: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SsrfConfig {
+    /// Refuse to connect to upstream addresses in `deny` unless in `allow`
+    pub enabled: bool,
+    /// CIDR ranges or addresses upstreams must not resolve to
+    pub deny: Vec<String>,
+    /// Exceptions to `deny`, e.g. an intranet range the proxy is meant to reach
+    pub allow: Vec<String>,
+}
+
+/// Loopback, private, link-local (including cloud metadata), carrier-grade
+/// NAT, multicast and reserved ranges
+pub fn default_ssrf_deny() -> Vec<String> {
+    [
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "172.16.0.0/12",
+        "192.0.0.0/24",
+        "192.168.0.0/16",
+        "198.18.0.0/15",
+        "224.0.0.0/4",
+        "240.0.0.0/4",
+        "::/128",
+        "::1/128",
+        "fc00::/7",
+        "fe80::/10",
+        "ff00::/8",
+    ]
+    .iter()
+    .map(|r| r.to_string())
+    .collect()
+}
+
+impl Default for SsrfConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            deny: default_ssrf_deny(),
+            allow: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// Upstream URL fetched by `/readyz` to confirm outbound connectivity
+    pub canary_url: Option<String>,
+    /// How long a canary result is reused before probes fetch it again
+    pub canary_cache_secs: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            canary_url: None,
+            canary_cache_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// Log out after this many seconds without a request
+    pub idle_timeout_secs: u64,
+    /// Log out this many seconds after login, however active the session
+    pub absolute_timeout_secs: u64,
+    /// Where sessions live: memory, sqlite or redis
+    pub store: String,
+    pub sqlite_path: String,
+    /// `redis://` or `rediss://` URL of a server shared by all instances
+    pub redis_url: Option<String>,
+    /// Base64 32-byte key encrypting sessions in sqlite and redis stores
+    pub encryption_key: Option<String>,
+    /// How often expired sessions are purged from persistent stores
+    pub cleanup_interval_secs: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout_secs: 1800,
+            absolute_timeout_secs: 43200,
+            store: "memory".to_string(),
+            sqlite_path: "sessions.db".to_string(),
+            redis_url: None,
+            encryption_key: None,
+            cleanup_interval_secs: 300,
+        }
+    }
+}
+
 impl Config {
     pub fn load(path: &str) -> Result<Self> {
         // Try to load from file first
@@ -66,20 +500,54 @@ impl Config {
             .map(|s| s.trim().to_string())
             .collect();
 
+        // Semicolon-separated, as port lists inside rules use commas
+        let rules: Vec<String> = env::var("DOMAIN_FILTER_RULES")
+            .unwrap_or_default()
+            .split(';')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        let feeds: Vec<String> = env::var("DOMAIN_FILTER_FEEDS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        let propagate_str = env::var("TELEMETRY_PROPAGATE_DOMAINS").unwrap_or_default();
+        let propagate_domains: Vec<String> = propagate_str
+            .split(',')
+            .filter(|s| !s.is_empty())
+            .map(|s| s.trim().to_string())
+            .collect();
+
         Ok(Config {
             server: ServerConfig {
                 host: env::var("SERVER_HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
                 port: env::var("SERVER_PORT")
                     .unwrap_or_else(|_| "3000".to_string())
                     .parse()?,
+                shutdown_timeout_secs: env::var("SERVER_SHUTDOWN_TIMEOUT_SECS")
+                    .map(|s| s.parse())
+                    .unwrap_or(Ok(default_shutdown_timeout_secs()))?,
+                drain_delay_secs: env::var("SERVER_DRAIN_DELAY_SECS")
+                    .map(|s| s.parse())
+                    .unwrap_or(Ok(default_drain_delay_secs()))?,
+                tls: Self::tls_from_env(),
             },
-            auth: AuthConfig {
-                username: env::var("AUTH_USERNAME").unwrap_or_else(|_| "admin".to_string()),
-                password: env::var("AUTH_PASSWORD").unwrap_or_else(|_| "changeme".to_string()),
-            },
+            auth: Self::auth_from_env(),
             domain_filter: DomainFilterConfig {
                 allowlist,
                 blocklist,
+                rules,
+                feeds,
+                feed_refresh_minutes: env::var("DOMAIN_FILTER_FEED_REFRESH_MINUTES")
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or_else(default_feed_refresh_minutes),
             },
             logging: LoggingConfig {
                 level: env::var("LOGGING_LEVEL").unwrap_or_else(|_| "info".to_string()),
@@ -89,6 +557,262 @@ impl Config {
                     .parse()
                     .unwrap_or(true),
             },
+            access_log: AccessLogConfig {
+                enabled: env::var("ACCESS_LOG_ENABLED")
+                    .unwrap_or_else(|_| "false".to_string())
+                    .parse()
+                    .unwrap_or(false),
+                path: env::var("ACCESS_LOG_PATH").unwrap_or_else(|_| "logs/access.log".to_string()),
+                format: env::var("ACCESS_LOG_FORMAT").unwrap_or_else(|_| "combined".to_string()),
+                max_size_mb: env::var("ACCESS_LOG_MAX_SIZE_MB")
+                    .unwrap_or_else(|_| "100".to_string())
+                    .parse()?,
+                rotate: env::var("ACCESS_LOG_ROTATE").unwrap_or_else(|_| "daily".to_string()),
+            },
+            metrics: MetricsConfig {
+                enabled: env::var("METRICS_ENABLED")
+                    .unwrap_or_else(|_| "false".to_string())
+                    .parse()
+                    .unwrap_or(false),
+                path: env::var("METRICS_PATH").unwrap_or_else(|_| "/metrics".to_string()),
+                admin_port: env::var("METRICS_ADMIN_PORT")
+                    .ok()
+                    .map(|p| p.parse())
+                    .transpose()?,
+            },
+            telemetry: TelemetryConfig {
+                enabled: env::var("TELEMETRY_ENABLED")
+                    .unwrap_or_else(|_| "false".to_string())
+                    .parse()
+                    .unwrap_or(false),
+                otlp_endpoint: env::var("TELEMETRY_OTLP_ENDPOINT")
+                    .unwrap_or_else(|_| "http://localhost:4318".to_string()),
+                service_name: env::var("TELEMETRY_SERVICE_NAME")
+                    .unwrap_or_else(|_| env!("CARGO_PKG_NAME").to_string()),
+                propagate_domains,
+            },
+            health: HealthConfig {
+                canary_url: env::var("HEALTH_CANARY_URL").ok().filter(|u| !u.is_empty()),
+                canary_cache_secs: env::var("HEALTH_CANARY_CACHE_SECS")
+                    .map(|s| s.parse())
+                    .unwrap_or(Ok(HealthConfig::default().canary_cache_secs))?,
+            },
+            audit_log: AuditLogConfig {
+                enabled: env::var("AUDIT_LOG_ENABLED")
+                    .unwrap_or_else(|_| "false".to_string())
+                    .parse()
+                    .unwrap_or(false),
+                path: env::var("AUDIT_LOG_PATH").unwrap_or_else(|_| "logs/audit.log".to_string()),
+            },
+            session: SessionConfig {
+                idle_timeout_secs: env::var("SESSION_IDLE_TIMEOUT_SECS")
+                    .unwrap_or_else(|_| "1800".to_string())
+                    .parse()?,
+                absolute_timeout_secs: env::var("SESSION_ABSOLUTE_TIMEOUT_SECS")
+                    .unwrap_or_else(|_| "43200".to_string())
+                    .parse()?,
+                store: env::var("SESSION_STORE").unwrap_or_else(|_| "memory".to_string()),
+                sqlite_path: env::var("SESSION_SQLITE_PATH")
+                    .unwrap_or_else(|_| "sessions.db".to_string()),
+                redis_url: env::var("SESSION_REDIS_URL").ok().filter(|u| !u.is_empty()),
+                encryption_key: env::var("SESSION_ENCRYPTION_KEY")
+                    .ok()
+                    .filter(|k| !k.is_empty()),
+                cleanup_interval_secs: env::var("SESSION_CLEANUP_INTERVAL_SECS")
+                    .unwrap_or_else(|_| "300".to_string())
+                    .parse()?,
+            },
+            admin: AdminConfig {
+                enabled: env::var("ADMIN_ENABLED")
+                    .unwrap_or_else(|_| "false".to_string())
+                    .parse()
+                    .unwrap_or(false),
+                role: env::var("ADMIN_ROLE").unwrap_or_else(|_| "admin".to_string()),
+                policy_file: env::var("ADMIN_POLICY_FILE").ok().filter(|p| !p.is_empty()),
+            },
+            ssrf: SsrfConfig {
+                enabled: env::var("SSRF_ENABLED")
+                    .unwrap_or_else(|_| "true".to_string())
+                    .parse()
+                    .unwrap_or(true),
+                deny: env::var("SSRF_DENY")
+                    .ok()
+                    .filter(|d| !d.is_empty())
+                    .map(|d| d.split(',').map(|r| r.trim().to_string()).collect())
+                    .unwrap_or_else(default_ssrf_deny),
+                allow: env::var("SSRF_ALLOW")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|r| !r.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            },
+            learning: LearningConfig {
+                enabled: env::var("LEARNING_ENABLED")
+                    .unwrap_or_else(|_| "false".to_string())
+                    .parse()
+                    .unwrap_or(false),
+                auto_allow_minutes: env::var("LEARNING_AUTO_ALLOW_MINUTES")
+                    .unwrap_or_else(|_| "10".to_string())
+                    .parse()?,
+            },
+            roles: BTreeMap::new(),
+        })
+    }
+
+    fn auth_from_env() -> AuthConfig {
+        let username = env::var("AUTH_USERNAME").unwrap_or_else(|_| "admin".to_string());
+        let users_file = env::var("AUTH_USERS_FILE").ok().filter(|f| !f.is_empty());
+        let oidc = Self::oidc_from_env();
+        let ldap = Self::ldap_from_env();
+        let require_2fa = env::var("AUTH_REQUIRE_2FA")
+            .unwrap_or_else(|_| "false".to_string())
+            .parse()
+            .unwrap_or(false);
+        let totp_file = env::var("AUTH_TOTP_FILE").ok().filter(|f| !f.is_empty());
+        let totp_issuer = env::var("AUTH_TOTP_ISSUER").unwrap_or_else(|_| default_totp_issuer());
+        let tokens_file = env::var("AUTH_TOKENS_FILE").ok().filter(|f| !f.is_empty());
+        let lockout = Self::lockout_from_env();
+        let client_cert = Self::client_cert_from_env();
+
+        // A pre-hashed password avoids keeping the plaintext in the environment
+        if let Ok(password_hash) = env::var("AUTH_PASSWORD_HASH") {
+            return AuthConfig {
+                username: None,
+                password: None,
+                users: vec![User {
+                    username,
+                    password_hash,
+                    enabled: true,
+                    roles: Vec::new(),
+                    require_2fa: false,
+                }],
+                users_file,
+                oidc,
+                ldap,
+                require_2fa,
+                totp_file,
+                totp_issuer,
+                tokens_file,
+                lockout,
+                client_cert,
+            };
+        }
+
+        // With SSO or a directory configured, only create a password user when
+        // explicitly asked to
+        let external = oidc.is_some() || ldap.is_some();
+        let password = match env::var("AUTH_PASSWORD") {
+            Ok(password) => Some(password),
+            Err(_) if external => None,
+            Err(_) => Some("changeme".to_string()),
+        };
+
+        AuthConfig {
+            username: password.as_ref().map(|_| username),
+            password,
+            users: Vec::new(),
+            users_file,
+            oidc,
+            ldap,
+            require_2fa,
+            totp_file,
+            totp_issuer,
+            tokens_file,
+            lockout,
+            client_cert,
+        }
+    }
+
+    fn lockout_from_env() -> LockoutConfig {
+        let defaults = LockoutConfig::default();
+        let number = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+        LockoutConfig {
+            enabled: env::var("AUTH_LOCKOUT_ENABLED")
+                .unwrap_or_else(|_| "true".to_string())
+                .parse()
+                .unwrap_or(true),
+            max_failures: number("AUTH_LOCKOUT_MAX_FAILURES", defaults.max_failures.into()) as u32,
+            max_failures_per_ip: number(
+                "AUTH_LOCKOUT_MAX_FAILURES_PER_IP",
+                defaults.max_failures_per_ip.into(),
+            ) as u32,
+            backoff_secs: number("AUTH_LOCKOUT_BACKOFF_SECS", defaults.backoff_secs),
+            lockout_secs: number("AUTH_LOCKOUT_SECS", defaults.lockout_secs),
+            trusted_ips: env::var("AUTH_LOCKOUT_TRUSTED_IPS")
+                .unwrap_or_default()
+                .split(',')
+                .filter(|s| !s.trim().is_empty())
+                .map(|s| s.trim().to_string())
+                .collect(),
+        }
+    }
+
+    fn tls_from_env() -> Option<TlsConfig> {
+        let cert_path = env::var("SERVER_TLS_CERT_PATH")
+            .ok()
+            .filter(|p| !p.is_empty())?;
+        Some(TlsConfig {
+            cert_path,
+            key_path: env::var("SERVER_TLS_KEY_PATH").unwrap_or_default(),
+            client_ca_path: env::var("SERVER_TLS_CLIENT_CA_PATH")
+                .ok()
+                .filter(|p| !p.is_empty()),
+        })
+    }
+
+    fn client_cert_from_env() -> Option<ClientCertConfig> {
+        let enabled: bool = env::var("AUTH_CLIENT_CERT_ENABLED")
+            .unwrap_or_else(|_| "false".to_string())
+            .parse()
+            .unwrap_or(false);
+        enabled.then(|| ClientCertConfig {
+            identity: env::var("AUTH_CLIENT_CERT_IDENTITY")
+                .unwrap_or_else(|_| default_cert_identity()),
+            users: BTreeMap::new(),
+        })
+    }
+
+    fn ldap_from_env() -> Option<LdapConfig> {
+        let url = env::var("AUTH_LDAP_URL").ok().filter(|u| !u.is_empty())?;
+        Some(LdapConfig {
+            url,
+            starttls: env::var("AUTH_LDAP_STARTTLS")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()
+                .unwrap_or(false),
+            bind_dn: env::var("AUTH_LDAP_BIND_DN").unwrap_or_default(),
+            group_base_dn: env::var("AUTH_LDAP_GROUP_BASE_DN")
+                .ok()
+                .filter(|d| !d.is_empty()),
+            group_filter: env::var("AUTH_LDAP_GROUP_FILTER")
+                .unwrap_or_else(|_| default_group_filter()),
+            group_attribute: default_group_attribute(),
+            role_mapping: BTreeMap::new(),
+            timeout_secs: default_ldap_timeout_secs(),
+        })
+    }
+
+    fn oidc_from_env() -> Option<OidcConfig> {
+        let issuer = env::var("AUTH_OIDC_ISSUER")
+            .ok()
+            .filter(|i| !i.is_empty())?;
+        Some(OidcConfig {
+            issuer,
+            client_id: env::var("AUTH_OIDC_CLIENT_ID").unwrap_or_default(),
+            client_secret: env::var("AUTH_OIDC_CLIENT_SECRET").unwrap_or_default(),
+            redirect_url: env::var("AUTH_OIDC_REDIRECT_URL").unwrap_or_default(),
+            scopes: default_oidc_scopes(),
+            username_claim: default_username_claim(),
+            roles_claim: env::var("AUTH_OIDC_ROLES_CLAIM")
+                .unwrap_or_else(|_| default_roles_claim()),
+            role_mapping: BTreeMap::new(),
         })
     }
 }
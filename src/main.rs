@@ -1,30 +1,172 @@
 use axum::{
     response::Redirect,
-    routing::{get, post},
+    routing::{delete, get, post},
     Router,
 };
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
-use tower_sessions::{MemoryStore, SessionManagerLayer};
+use std::time::Duration;
+use tower_sessions::{cookie::SameSite, Expiry, SessionManagerLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod audit;
+mod auth;
+mod cli;
 mod config;
+mod metrics;
 mod middleware;
 mod proxy;
 mod routes;
+mod sessions;
+mod shutdown;
+mod ssrf;
+mod telemetry;
+mod tls;
 
+use audit::AuditLog;
+use auth::{
+    Authenticator, Authenticators, ClientCertAuthenticator, CurrentUser, LdapAuthenticator,
+    LoginThrottle, OidcClient, StaticAuthenticator, TokenStore, TotpStore, UserStore,
+};
 use config::Config;
-use middleware::{logging_middleware, DomainFilter};
-use routes::{browse_handler, home_page, login_handler, login_page, proxy_handler, require_auth};
+use metrics::Metrics;
+use middleware::{
+    domain_filter::FilterDecision, logging, logging::LogFormat, logging_middleware,
+    policy::UserPolicy, verify_csrf, AccessLog, LearningQueue, PolicyStore, RecentRequests,
+    RolePolicies,
+};
+use routes::{
+    add_rule, admin_add_rule_handler, admin_learning_approve_handler,
+    admin_learning_dismiss_handler, admin_learning_page, admin_learning_window_handler,
+    admin_remove_rule_handler, admin_requests_page, admin_revoke_session_handler, admin_rules_page,
+    admin_save_user_handler, admin_sessions_page, admin_user_enabled_handler, admin_users_page,
+    browse_handler, create_token_handler, enroll_handler, enroll_page, healthz, home_page,
+    list_rules, login_handler, login_page, logout_everywhere_handler, logout_handler,
+    metrics_handler, oidc_callback, oidc_login, proxy_handler, readyz, remove_rule,
+    request_access_handler, require_admin, require_auth, revoke_token_handler,
+    second_factor_handler, second_factor_page, test_rule, tokens_page, version, CanaryCache,
+};
+use sessions::TrackedStore;
+use ssrf::{AddressPolicy, GuardedResolver};
+use telemetry::Telemetry;
 
 #[derive(Clone)]
 pub struct AppState {
     pub config: Config,
+    pub users: Arc<UserStore>,
+    pub oidc: Option<Arc<OidcClient>>,
+    pub authenticators: Arc<Authenticators>,
+    pub totp: Arc<TotpStore>,
+    pub tokens: Arc<TokenStore>,
+    pub throttle: Arc<LoginThrottle>,
     pub client: reqwest::Client,
-    pub domain_filter: Arc<DomainFilter>,
+    /// Upstream address checks, unless `ssrf.enabled` is off
+    pub addresses: Option<Arc<AddressPolicy>>,
+    /// Global allowlist and blocklist; the admin API swaps in new rules
+    pub domain_filter: Arc<PolicyStore>,
+    pub roles: Arc<RolePolicies>,
+    pub access_log: Option<Arc<AccessLog>>,
+    pub audit: Arc<AuditLog>,
+    /// Recent proxied requests for the admin console
+    pub recent: Arc<RecentRequests>,
+    /// Domains refused by default-deny, waiting for review
+    pub learning: Arc<LearningQueue>,
+    pub metrics: Arc<Metrics>,
+    pub sessions: TrackedStore,
+    pub telemetry: Arc<Telemetry>,
+    /// Set once a shutdown signal arrives; `/readyz` reports failure from then on
+    pub shutting_down: Arc<AtomicBool>,
+    pub canary: Arc<CanaryCache>,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// State for handler tests: a minimal config with in-memory stores and
+    /// no log files, adjusted by `configure`
+    pub async fn for_tests(configure: impl FnOnce(&mut Config)) -> Arc<Self> {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            host = "127.0.0.1"
+            port = 0
+            [auth]
+            [[auth.users]]
+            username = "admin"
+            password_hash = "$argon2id$v=19$m=19456,t=2,p=1$HVtYWb3e29inxtWzHeF/Vg$tF6J3/y5i15HVOrcZ+wkLKsAUecdADp+iVAXWQrt5n4"
+            roles = ["admin"]
+            [domain_filter]
+            allowlist = ["example.com"]
+            [logging]
+            level = "info"
+            format = "json"
+            log_requests = true
+            "#,
+        )
+        .unwrap();
+        configure(&mut config);
+
+        let users = Arc::new(UserStore::from_config(&config.auth).unwrap());
+        let sessions = sessions::open(&config.session).await.unwrap();
+        Arc::new(AppState {
+            authenticators: Arc::new(Authenticators::new(
+                vec![Arc::new(StaticAuthenticator::new(users.clone()))],
+                users.clone(),
+            )),
+            users,
+            oidc: None,
+            totp: Arc::new(TotpStore::new(&config.auth.totp_issuer, None).unwrap()),
+            tokens: Arc::new(TokenStore::new(None, config.session.absolute_timeout_secs).unwrap()),
+            throttle: Arc::new(
+                LoginThrottle::new(&config.auth.lockout, None)
+                    .await
+                    .unwrap(),
+            ),
+            client: reqwest::Client::new(),
+            addresses: None,
+            domain_filter: Arc::new(PolicyStore::new(&config.domain_filter, None).unwrap()),
+            roles: Arc::new(RolePolicies::new(&config.roles).unwrap()),
+            access_log: None,
+            audit: Arc::new(AuditLog::new(&Default::default()).unwrap()),
+            recent: Arc::new(RecentRequests::default()),
+            learning: Arc::new(LearningQueue::default()),
+            metrics: Arc::new(Metrics::new().unwrap()),
+            sessions,
+            telemetry: Arc::new(Telemetry::new(&Default::default()).unwrap()),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            canary: Arc::new(CanaryCache::new(&config.health)),
+            config,
+        })
+    }
+}
+
+impl AppState {
+    /// Domain policy for the given user's roles
+    pub fn policy<'a>(&'a self, user: &'a CurrentUser) -> UserPolicy<'a> {
+        self.roles
+            .for_user(self.domain_filter.filter(), &user.roles)
+            .restricted_to(user.token_domains.as_deref())
+    }
+
+    /// Whether the user may use the admin console and API
+    pub fn is_admin(&self, user: &CurrentUser) -> bool {
+        self.config.admin.enabled && user.roles.contains(&self.config.admin.role)
+    }
+
+    /// Whether a user refused by `decision` may ask for the domain through
+    /// the learning queue. Allowlisting cannot lift a blocklist refusal.
+    pub fn can_request_access(&self, decision: &FilterDecision) -> bool {
+        self.config.admin.enabled && !decision.allowed && decision.rule == "default-deny"
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    // Management commands (hash-password, add-user) run instead of the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&args);
+    }
+
     // 1. Load configuration
     let config = Config::load("config.toml")?;
 
@@ -32,71 +174,377 @@ async fn main() -> anyhow::Result<()> {
     if config.domain_filter.allowlist.is_empty() {
         anyhow::bail!("Error: Allowlist cannot be empty. Add at least one domain to config.toml");
     }
+    if config.session.idle_timeout_secs == 0 || config.session.absolute_timeout_secs == 0 {
+        anyhow::bail!("Error: Session timeouts must be greater than zero");
+    }
+    if config.auth.client_cert.is_some()
+        && config
+            .server
+            .tls
+            .as_ref()
+            .is_none_or(|tls| tls.client_ca_path.is_none())
+    {
+        anyhow::bail!("Error: Client certificate login requires server.tls.client_ca_path");
+    }
+    if config.metrics.enabled && config.metrics.admin_port.is_none() && !config.admin.enabled {
+        anyhow::bail!(
+            "Error: Metrics on the main listener are only served to admins. \
+             Enable [admin] or set metrics.admin_port"
+        );
+    }
 
-    // 3. Setup logging
+    // 3. Setup logging and trace export
+    let telemetry = Arc::new(Telemetry::new(&config.telemetry)?);
+    let (json_layer, pretty_layer) = match config.logging.format.parse()? {
+        LogFormat::Json => (Some(tracing_subscriber::fmt::layer().json()), None),
+        LogFormat::Pretty => (None, Some(tracing_subscriber::fmt::layer())),
+    };
     tracing_subscriber::registry()
+        .with(telemetry.layer())
         .with(
-            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-                format!("{}={}", env!("CARGO_PKG_NAME"), config.logging.level).into()
-            }),
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| logging::default_filter(&config.logging.level)),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(json_layer)
+        .with(pretty_layer)
         .init();
 
     tracing::info!("Starting browser_proxy server");
+    if config.telemetry.enabled {
+        if cfg!(feature = "otel") {
+            tracing::info!("Exporting traces to {}", config.telemetry.otlp_endpoint);
+        } else {
+            tracing::warn!("Telemetry is enabled but this build lacks the `otel` feature");
+        }
+    }
     tracing::info!("Loaded configuration:");
     tracing::info!("  Allowed domains: {:?}", config.domain_filter.allowlist);
     if !config.domain_filter.blocklist.is_empty() {
         tracing::info!("  Blocked domains: {:?}", config.domain_filter.blocklist);
     }
+    for (role, filter) in &config.roles {
+        tracing::info!("  Role '{}' allowed domains: {:?}", role, filter.allowlist);
+    }
+
+    // 4. Load user accounts and create domain filter
+    let users = Arc::new(UserStore::from_config(&config.auth)?);
+    tracing::info!("  Loaded {} user account(s)", users.len());
+    let mut authenticators: Vec<Arc<dyn Authenticator>> =
+        vec![Arc::new(StaticAuthenticator::new(users.clone()))];
+    if let Some(ldap_config) = &config.auth.ldap {
+        tracing::info!("  LDAP login via {}", ldap_config.url);
+        authenticators.push(Arc::new(LdapAuthenticator::new(ldap_config)?));
+    }
+    let oidc = match &config.auth.oidc {
+        Some(oidc_config) => {
+            tracing::info!("  Single sign-on via {}", oidc_config.issuer);
+            let oidc = Arc::new(OidcClient::new(oidc_config)?);
+            authenticators.push(oidc.clone());
+            Some(oidc)
+        }
+        None => None,
+    };
+    if let Some(client_cert_config) = &config.auth.client_cert {
+        tracing::info!(
+            "  Client certificate login by {}",
+            client_cert_config.identity
+        );
+        authenticators.push(Arc::new(ClientCertAuthenticator::new(
+            client_cert_config,
+            users.clone(),
+        )?));
+    }
+    let authenticators = Arc::new(Authenticators::new(authenticators, users.clone()));
+    let totp = Arc::new(TotpStore::new(
+        &config.auth.totp_issuer,
+        config.auth.totp_file.as_deref(),
+    )?);
+    let tokens = Arc::new(TokenStore::new(
+        config.auth.tokens_file.as_deref(),
+        config.session.absolute_timeout_secs,
+    )?);
+    if config.auth.require_2fa {
+        tracing::info!("  Two-factor authentication required for all users");
+    }
 
-    // 4. Create domain filter
-    let domain_filter = Arc::new(DomainFilter::new(&config.domain_filter)?);
+    let domain_filter = Arc::new(PolicyStore::new(
+        &config.domain_filter,
+        config.admin.policy_file.as_deref(),
+    )?);
+    if let Some(feeds) = domain_filter.feeds() {
+        feeds.refresh().await;
+        tokio::spawn(feeds.refresh_periodically());
+    }
+    let roles = Arc::new(RolePolicies::new(&config.roles)?);
+    if config.learning.enabled {
+        if config.admin.enabled {
+            tracing::info!("  Learning mode: refused domains are queued for review");
+        } else {
+            tracing::warn!("Learning mode needs [admin] enabled to review the queue");
+        }
+    }
 
     // 5. Create HTTP client for proxying
-    let client = reqwest::Client::builder()
-        .redirect(reqwest::redirect::Policy::none())
-        .build()?;
+    let mut client = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none());
+    let addresses = if config.ssrf.enabled {
+        let policy = Arc::new(AddressPolicy::new(&config.ssrf)?);
+        client = client.dns_resolver(Arc::new(GuardedResolver::new(policy.clone())));
+        tracing::info!(
+            "  Upstream addresses checked against {} denied and {} allowed range(s)",
+            config.ssrf.deny.len(),
+            config.ssrf.allow.len()
+        );
+        Some(policy)
+    } else {
+        tracing::warn!("SSRF protection disabled: upstreams may resolve to internal addresses");
+        None
+    };
+    let client = client.build()?;
+
+    // 6. Open access and audit logs
+    let access_log = if config.access_log.enabled {
+        let access_log = Arc::new(AccessLog::new(&config.access_log)?);
+        tracing::info!("Writing access log to {}", config.access_log.path);
+        #[cfg(unix)]
+        tokio::spawn(access_log.clone().reopen_on_sighup());
+        Some(access_log)
+    } else {
+        None
+    };
+
+    let audit = Arc::new(AuditLog::new(&config.audit_log)?);
+    if config.audit_log.enabled {
+        tracing::info!("Writing audit log to {}", config.audit_log.path);
+    }
 
-    // 6. Create application state
+    // 7. Create application state
+    let sessions = sessions::open(&config.session).await?;
+    let throttle = Arc::new(LoginThrottle::new(&config.auth.lockout, sessions.key_values()).await?);
     let state = Arc::new(AppState {
         config: config.clone(),
+        users,
+        oidc,
+        authenticators,
+        totp,
+        tokens,
+        throttle,
         client,
+        addresses,
         domain_filter,
+        roles,
+        access_log,
+        audit,
+        recent: Arc::new(RecentRequests::default()),
+        learning: Arc::new(LearningQueue::default()),
+        metrics: Arc::new(Metrics::new()?),
+        sessions: sessions.clone(),
+        telemetry: telemetry.clone(),
+        shutting_down: Arc::new(AtomicBool::new(false)),
+        canary: Arc::new(CanaryCache::new(&config.health)),
     });
 
-    // 7. Setup session layer
-    let session_store = MemoryStore::default();
-    let session_layer = SessionManagerLayer::new(session_store)
-        .with_secure(false); // Allow cookies over HTTP (set true if behind HTTPS proxy)
+    // 8. Setup session layer
+    // Allow cookies over HTTP unless we terminate TLS ourselves (set true
+    // if behind an HTTPS proxy)
+    let mut session_layer = SessionManagerLayer::new(sessions)
+        .with_secure(config.server.tls.is_some())
+        .with_expiry(Expiry::OnInactivity(time::Duration::seconds(
+            config.session.idle_timeout_secs as i64,
+        )));
+    if state.oidc.is_some() {
+        // The SSO callback is a cross-site navigation; a Strict cookie would
+        // not be sent with it and the pending login would be lost
+        session_layer = session_layer.with_same_site(SameSite::Lax);
+    }
 
-    // 8. Build router
+    // 9. Build router
     // Public routes
-    let public_routes = Router::new()
+    let mut public_routes = Router::new()
         .route("/", get(|| async { Redirect::to("/login") }))
-        .route("/login", get(login_page).post(login_handler));
+        .route("/login", get(login_page).post(login_handler))
+        // These check the session themselves: both serve users who have
+        // passed the password step but not yet the second factor
+        .route(
+            "/login/2fa",
+            get(second_factor_page).post(second_factor_handler),
+        )
+        .route("/2fa/enroll", get(enroll_page).post(enroll_handler))
+        .route("/logout", post(logout_handler));
+
+    if state.oidc.is_some() {
+        public_routes = public_routes
+            .route("/auth/oidc/login", get(oidc_login))
+            .route("/auth/oidc/callback", get(oidc_callback));
+    }
+
+    // Set once SIGTERM/SIGINT arrives; every listener stops on it
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+
+    // Metrics get a dedicated admin port if one is set; on the main listener
+    // they are for admins only
+    let mut metrics_routes = Router::new();
+    if config.metrics.enabled {
+        let routes = Router::new().route(&config.metrics.path, get(metrics_handler));
+        match config.metrics.admin_port {
+            Some(port) => {
+                let addr = format!("{}:{}", config.server.host, port);
+                let listener = tokio::net::TcpListener::bind(&addr).await?;
+                tracing::info!("Metrics listening on {}{}", addr, config.metrics.path);
+                let admin_app = routes.with_state(state.clone());
+                let stopped = shutdown::triggered(shutdown_rx.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = axum::serve(listener, admin_app)
+                        .with_graceful_shutdown(stopped)
+                        .await
+                    {
+                        tracing::error!("Metrics server failed: {}", e);
+                    }
+                });
+            }
+            None => {
+                metrics_routes = routes.route_layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    require_admin,
+                ))
+            }
+        }
+    }
 
     // Protected routes
+    // Admin console and rule changes through the admin API, for holders of
+    // the admin role
+    let admin_routes = if config.admin.enabled {
+        tracing::info!("Admin console enabled for role '{}'", config.admin.role);
+        Router::new()
+            .route("/admin", get(|| async { Redirect::to("/admin/sessions") }))
+            .route("/admin/sessions", get(admin_sessions_page))
+            .route("/admin/sessions/revoke", post(admin_revoke_session_handler))
+            .route("/admin/requests", get(admin_requests_page))
+            .route("/admin/rules", get(admin_rules_page))
+            .route("/admin/rules/add", post(admin_add_rule_handler))
+            .route("/admin/rules/remove", post(admin_remove_rule_handler))
+            .route(
+                "/admin/users",
+                get(admin_users_page).post(admin_save_user_handler),
+            )
+            .route("/admin/users/enabled", post(admin_user_enabled_handler))
+            .route("/admin/learning", get(admin_learning_page))
+            .route(
+                "/admin/learning/approve",
+                post(admin_learning_approve_handler),
+            )
+            .route(
+                "/admin/learning/dismiss",
+                post(admin_learning_dismiss_handler),
+            )
+            .route(
+                "/admin/learning/window",
+                post(admin_learning_window_handler),
+            )
+            .route("/api/admin/rules", get(list_rules))
+            .route("/api/admin/rules/:list", post(add_rule))
+            .route("/api/admin/rules/:list/:pattern", delete(remove_rule))
+            .route("/api/admin/test", get(test_rule))
+            .route_layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                require_admin,
+            ))
+    } else {
+        Router::new()
+    };
+
     let protected_routes = Router::new()
         .route("/home", get(home_page))
         .route("/browse", post(browse_handler))
+        .route("/request-access", post(request_access_handler))
         .route("/proxy/:scheme/*path", get(proxy_handler))
-        .route_layer(axum::middleware::from_fn(require_auth));
+        .route("/logout/all", post(logout_everywhere_handler))
+        .route(
+            "/settings/tokens",
+            get(tokens_page).post(create_token_handler),
+        )
+        .route("/settings/tokens/revoke", post(revoke_token_handler))
+        .merge(admin_routes)
+        .merge(metrics_routes)
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            require_auth,
+        ));
+
+    // Probe routes skip sessions, auth and request logging
+    let health_routes = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/version", get(version));
 
     let app = Router::new()
         .merge(public_routes)
         .merge(protected_routes)
+        // Inside the logging layer, so unknown paths are logged and counted
+        // under the `unmatched` route label
+        .fallback(|| async { axum::http::StatusCode::NOT_FOUND })
+        .layer(axum::middleware::from_fn(verify_csrf))
         .layer(session_layer)
-        .layer(axum::middleware::from_fn(logging_middleware))
-        .with_state(state);
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            logging_middleware,
+        ))
+        .merge(health_routes)
+        .with_state(state.clone());
 
-    // 9. Start server
+    // 10. Start server
     let addr = format!("{}:{}", config.server.host, config.server.port);
     let listener = tokio::net::TcpListener::bind(&addr).await?;
+    let acceptor = config.server.tls.as_ref().map(tls::acceptor).transpose()?;
+
+    tracing::info!(
+        "Server listening on {}{}",
+        addr,
+        if acceptor.is_some() { " (TLS)" } else { "" }
+    );
 
-    tracing::info!("Server listening on {}", addr);
+    // 11. On SIGTERM/SIGINT fail readiness, then stop accepting and drain
+    let shutting_down = state.shutting_down.clone();
+    let drain_delay = Duration::from_secs(config.server.drain_delay_secs);
+    tokio::spawn(async move {
+        shutdown::signal().await;
+        shutdown::begin(&shutting_down, drain_delay, &shutdown_tx).await;
+    });
+
+    let stopped = shutdown::triggered(shutdown_rx.clone());
+    let server = async move {
+        match acceptor {
+            Some(acceptor) => tls::serve(listener, acceptor, app, stopped).await,
+            None => {
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+                )
+                .with_graceful_shutdown(stopped)
+                .await
+            }
+        }
+    };
+
+    let drain_timeout = Duration::from_secs(config.server.shutdown_timeout_secs);
+    tokio::select! {
+        result = server => result?,
+        _ = async {
+            shutdown::triggered(shutdown_rx).await;
+            tracing::info!("Draining in-flight requests (up to {:?})", drain_timeout);
+            tokio::time::sleep(drain_timeout).await;
+        } => tracing::warn!("Drain deadline reached, dropping remaining connections"),
+    }
+
+    // 12. Flush buffered state before exit
+    if let Some(access_log) = &state.access_log
+        && let Err(e) = access_log.flush().await
+    {
+        tracing::error!("Failed to flush access log: {}", e);
+    }
+    tokio::task::spawn_blocking(move || telemetry.flush()).await?;
 
-    axum::serve(listener, app).await?;
+    tracing::info!("Shutdown complete");
 
     Ok(())
 }
@@ -0,0 +1,168 @@
+use anyhow::Result;
+use axum::extract::MatchedPath;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+/// Prometheus collectors for the proxy.
+///
+/// Request level metrics are recorded by `logging_middleware`, proxy pipeline
+/// metrics by `proxy_handler`.
+pub struct Metrics {
+    registry: Registry,
+    /// Requests by route, final status and handler type
+    pub requests: IntCounterVec,
+    /// Time until upstream response headers arrive, by domain
+    pub upstream_latency: HistogramVec,
+    /// Bytes proxied, by direction (`in` from upstream, `out` to clients)
+    pub bytes_proxied: IntCounterVec,
+    /// Time spent in a `ProxyHandler` reading and rewriting the body
+    pub handler_duration: HistogramVec,
+    /// Domain filter decisions by outcome and matching rule
+    pub filter_decisions: IntCounterVec,
+    pub active_sessions: IntGauge,
+    pub login_failures: IntCounter,
+    /// Lockouts started, by scope (`ip` or `user`)
+    pub login_lockouts: IntCounterVec,
+    /// Login attempts refused while backing off or locked out
+    pub login_throttled: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("browser_proxy".to_string()), None)?;
+
+        let requests = IntCounterVec::new(
+            Opts::new("requests_total", "HTTP requests handled"),
+            &["route", "status", "handler"],
+        )?;
+        let upstream_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_duration_seconds",
+                "Time until upstream response headers are received",
+            ),
+            &["domain"],
+        )?;
+        let bytes_proxied = IntCounterVec::new(
+            Opts::new("proxied_bytes_total", "Bytes transferred through the proxy"),
+            &["direction"],
+        )?;
+        let handler_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "handler_duration_seconds",
+                "Time spent processing upstream bodies, by proxy handler",
+            )
+            .buckets(vec![
+                0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+            ]),
+            &["handler"],
+        )?;
+        let filter_decisions = IntCounterVec::new(
+            Opts::new("domain_filter_decisions_total", "Domain filter decisions"),
+            &["decision", "rule"],
+        )?;
+        let active_sessions = IntGauge::new("active_sessions", "Unexpired login sessions")?;
+        let login_failures = IntCounter::new("login_failures_total", "Failed login attempts")?;
+        let login_lockouts = IntCounterVec::new(
+            Opts::new("login_lockouts_total", "Login lockouts started"),
+            &["scope"],
+        )?;
+        let login_throttled = IntCounter::new(
+            "login_throttled_total",
+            "Login attempts refused by backoff or lockout",
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(upstream_latency.clone()))?;
+        registry.register(Box::new(bytes_proxied.clone()))?;
+        registry.register(Box::new(handler_duration.clone()))?;
+        registry.register(Box::new(filter_decisions.clone()))?;
+        registry.register(Box::new(active_sessions.clone()))?;
+        registry.register(Box::new(login_failures.clone()))?;
+        registry.register(Box::new(login_lockouts.clone()))?;
+        registry.register(Box::new(login_throttled.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            upstream_latency,
+            bytes_proxied,
+            handler_duration,
+            filter_decisions,
+            active_sessions,
+            login_failures,
+            login_lockouts,
+            login_throttled,
+        })
+    }
+
+    /// Encode all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+/// Route label for a request: the route pattern it matched, so paths with
+/// parameters share one series, or `unmatched`
+pub fn route_label(matched: Option<&MatchedPath>) -> &str {
+    matched.map(MatchedPath::as_str).unwrap_or("unmatched")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_route_label() {
+        use axum::{body::Body, extract::Request, routing::get, Router};
+        use tower::Service;
+
+        let mut app = Router::new()
+            .route("/home", get(|| async {}))
+            .route("/api/admin/rules/:list/:pattern", get(|| async {}))
+            .route("/proxy/:scheme/*path", get(|| async {}))
+            .layer(axum::middleware::from_fn(
+                |request: Request, next: axum::middleware::Next| async move {
+                    let label = route_label(request.extensions().get::<MatchedPath>()).to_string();
+                    let mut response = next.run(request).await;
+                    response
+                        .headers_mut()
+                        .insert("x-route", label.parse().unwrap());
+                    response
+                },
+            ));
+
+        for (path, label) in [
+            ("/home?page=2", "/home"),
+            (
+                "/api/admin/rules/allow/example.com",
+                "/api/admin/rules/:list/:pattern",
+            ),
+            ("/proxy/https/example.com/a/b", "/proxy/:scheme/*path"),
+            ("/does-not-exist", "unmatched"),
+        ] {
+            let request = Request::get(path).body(Body::empty()).unwrap();
+            let response = app.call(request).await.unwrap();
+            assert_eq!(response.headers()["x-route"], label, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_render_contains_registered_metrics() {
+        let metrics = Metrics::new().unwrap();
+        metrics
+            .requests
+            .with_label_values(&["/home", "200", "-"])
+            .inc();
+        metrics.login_failures.inc();
+
+        let output = metrics.render().unwrap();
+        assert!(output.contains(
+            "browser_proxy_requests_total{handler=\"-\",route=\"/home\",status=\"200\"} 1"
+        ));
+        assert!(output.contains("browser_proxy_login_failures_total 1"));
+    }
+}
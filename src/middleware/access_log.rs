@@ -0,0 +1,507 @@
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Local, Timelike};
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::config::AccessLogConfig;
+
+/// Lines waiting for the writer thread. Beyond this, entries are dropped
+/// rather than holding up requests while the disk catches up.
+const QUEUE_CAPACITY: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessLogFormat {
+    Common,
+    Combined,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Rotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+/// One completed request, as recorded in the access log
+pub struct AccessLogEntry<'a> {
+    pub remote_addr: &'a str,
+    pub user: Option<&'a str>,
+    pub time: DateTime<Local>,
+    pub method: &'a str,
+    pub uri: &'a str,
+    pub version: &'a str,
+    pub status: u16,
+    pub bytes: u64,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    /// Target URL decoded from `/proxy/...`, for proxied requests
+    pub target_url: Option<&'a str>,
+    pub upstream_status: Option<u16>,
+    pub duration_ms: u128,
+    pub request_id: &'a str,
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+    opened_at: DateTime<Local>,
+}
+
+enum Message {
+    Line { line: String, time: DateTime<Local> },
+    Reopen(oneshot::Sender<Result<()>>),
+    Flush(oneshot::Sender<Result<()>>),
+}
+
+/// Dedicated access log sink with size/time based rotation.
+///
+/// Entries are formatted on the calling task and written by a background
+/// thread, so requests never wait on the file. `reopen` lets external tools
+/// such as logrotate move the file away and signal the proxy (SIGHUP) to
+/// start writing a fresh one.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    tx: mpsc::Sender<Message>,
+}
+
+/// Owns the file on the writer thread
+struct Writer {
+    path: PathBuf,
+    max_size: u64,
+    rotation: Rotation,
+    current: LogFile,
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig) -> Result<Self> {
+        let format = match config.format.as_str() {
+            "common" | "clf" => AccessLogFormat::Common,
+            "combined" => AccessLogFormat::Combined,
+            "json" => AccessLogFormat::Json,
+            other => bail!(
+                "Unknown access log format '{}'. Use common, combined or json",
+                other
+            ),
+        };
+        let rotation = match config.rotate.as_str() {
+            "never" => Rotation::Never,
+            "hourly" => Rotation::Hourly,
+            "daily" => Rotation::Daily,
+            other => bail!(
+                "Unknown access log rotation '{}'. Use never, hourly or daily",
+                other
+            ),
+        };
+
+        let path = PathBuf::from(&config.path);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let writer = Writer {
+            current: Writer::open(&path)?,
+            path,
+            max_size: config.max_size_mb * 1024 * 1024,
+            rotation,
+        };
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || writer.run(rx))?;
+
+        Ok(Self { format, tx })
+    }
+
+    /// Queue an entry for the writer thread
+    pub fn log(&self, entry: &AccessLogEntry) {
+        let mut line = format_entry(self.format, entry);
+        line.push('\n');
+
+        let message = Message::Line {
+            line,
+            time: entry.time,
+        };
+        if let Err(e) = self.tx.try_send(message) {
+            match e {
+                mpsc::error::TrySendError::Full(_) => {
+                    tracing::warn!("Access log writer is behind, dropping entry")
+                }
+                mpsc::error::TrySendError::Closed(_) => {
+                    tracing::error!("Access log writer has stopped")
+                }
+            }
+        }
+    }
+
+    /// Reopen the log file at its configured path (after external rotation)
+    pub async fn reopen(&self) -> Result<()> {
+        self.request(Message::Reopen).await
+    }
+
+    /// Write out everything queued so far
+    pub async fn flush(&self) -> Result<()> {
+        self.request(Message::Flush).await
+    }
+
+    async fn request(&self, message: fn(oneshot::Sender<Result<()>>) -> Message) -> Result<()> {
+        let (reply, done) = oneshot::channel();
+        self.tx
+            .send(message(reply))
+            .await
+            .map_err(|_| anyhow!("access log writer has stopped"))?;
+        done.await
+            .map_err(|_| anyhow!("access log writer has stopped"))?
+    }
+
+    /// Reopen the log whenever the process receives SIGHUP
+    #[cfg(unix)]
+    pub async fn reopen_on_sighup(self: Arc<Self>) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!("Failed to install SIGHUP handler: {}", e);
+                return;
+            }
+        };
+
+        while hangup.recv().await.is_some() {
+            if let Err(e) = self.reopen().await {
+                tracing::error!("Failed to reopen access log: {}", e);
+            }
+        }
+    }
+}
+
+impl Writer {
+    fn open(path: &Path) -> Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile {
+            file,
+            size,
+            opened_at: Local::now(),
+        })
+    }
+
+    /// Handle messages until every `AccessLog` handle is gone
+    fn run(mut self, mut rx: mpsc::Receiver<Message>) {
+        while let Some(message) = rx.blocking_recv() {
+            match message {
+                Message::Line { line, time } => self.write(&line, &time),
+                Message::Reopen(reply) => {
+                    let result = Self::open(&self.path).map(|file| {
+                        self.current = file;
+                        tracing::info!("Reopened access log {}", self.path.display());
+                    });
+                    let _ = reply.send(result);
+                }
+                Message::Flush(reply) => {
+                    let _ = reply.send(self.current.file.flush().map_err(Into::into));
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, line: &str, time: &DateTime<Local>) {
+        if self.should_rotate(&self.current, line.len() as u64, time)
+            && let Err(e) = self.rotate()
+        {
+            tracing::error!("Failed to rotate access log {}: {}", self.path.display(), e);
+        }
+
+        match self.current.file.write_all(line.as_bytes()) {
+            Ok(()) => self.current.size += line.len() as u64,
+            Err(e) => tracing::error!("Failed to write access log: {}", e),
+        }
+    }
+
+    fn should_rotate(&self, current: &LogFile, incoming: u64, now: &DateTime<Local>) -> bool {
+        if self.max_size > 0 && current.size > 0 && current.size + incoming > self.max_size {
+            return true;
+        }
+
+        let opened = current.opened_at;
+        match self.rotation {
+            Rotation::Never => false,
+            Rotation::Daily => now.date_naive() != opened.date_naive(),
+            Rotation::Hourly => {
+                now.date_naive() != opened.date_naive() || now.hour() != opened.hour()
+            }
+        }
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.current.file.flush()?;
+
+        let suffix = Local::now().format("%Y%m%d-%H%M%S%.3f").to_string();
+        std::fs::rename(&self.path, rotated_path(&self.path, &suffix))?;
+
+        self.current = Self::open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// `path.suffix`, or `path.suffix.N` for the first N not already taken when
+/// several rotations land in the same millisecond
+fn rotated_path(path: &Path, suffix: &str) -> OsString {
+    let mut base = path.as_os_str().to_owned();
+    base.push(format!(".{}", suffix));
+
+    let mut rotated = base.clone();
+    let mut n = 1;
+    while Path::new(&rotated).exists() {
+        rotated = base.clone();
+        rotated.push(format!(".{}", n));
+        n += 1;
+    }
+    rotated
+}
+
+pub fn format_entry(format: AccessLogFormat, entry: &AccessLogEntry) -> String {
+    match format {
+        AccessLogFormat::Json => serde_json::json!({
+            "time": entry.time.to_rfc3339(),
+            "remote_addr": entry.remote_addr,
+            "user": entry.user,
+            "method": entry.method,
+            "uri": entry.uri,
+            "protocol": entry.version,
+            "status": entry.status,
+            "bytes": entry.bytes,
+            "referer": entry.referer,
+            "user_agent": entry.user_agent,
+            "target_url": entry.target_url,
+            "upstream_status": entry.upstream_status,
+            "duration_ms": entry.duration_ms,
+            "request_id": entry.request_id,
+        })
+        .to_string(),
+        AccessLogFormat::Common | AccessLogFormat::Combined => {
+            // %h %l %u %t "%r" %>s %b
+            let mut line = format!(
+                "{} - {} [{}] \"{} {} {}\" {} {}",
+                entry.remote_addr,
+                entry.user.map(escape).unwrap_or_else(|| "-".to_string()),
+                entry.time.format("%d/%b/%Y:%H:%M:%S %z"),
+                entry.method,
+                escape(entry.uri),
+                entry.version,
+                entry.status,
+                if entry.bytes == 0 {
+                    "-".to_string()
+                } else {
+                    entry.bytes.to_string()
+                },
+            );
+
+            if format == AccessLogFormat::Combined {
+                line.push_str(&format!(
+                    " \"{}\" \"{}\"",
+                    entry.referer.map(escape).unwrap_or_else(|| "-".to_string()),
+                    entry
+                        .user_agent
+                        .map(escape)
+                        .unwrap_or_else(|| "-".to_string()),
+                ));
+            }
+
+            // Proxy specific fields are appended after the standard ones so
+            // existing CLF/combined parsers keep working
+            line.push_str(&format!(
+                " \"{}\" {}",
+                entry
+                    .target_url
+                    .map(escape)
+                    .unwrap_or_else(|| "-".to_string()),
+                entry
+                    .upstream_status
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+            ));
+
+            line
+        }
+    }
+}
+
+/// Escape quotes, backslashes and control characters so a field cannot
+/// break out of its quoted section
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn entry() -> AccessLogEntry<'static> {
+        AccessLogEntry {
+            remote_addr: "10.0.0.1",
+            user: Some("admin"),
+            time: Local.with_ymd_and_hms(2024, 3, 5, 14, 7, 9).unwrap(),
+            method: "GET",
+            uri: "/proxy/https/example.com/index.html",
+            version: "HTTP/1.1",
+            status: 200,
+            bytes: 512,
+            referer: Some("http://localhost:3000/home"),
+            user_agent: Some("curl/8.0 \"quoted\""),
+            target_url: Some("https://example.com/index.html"),
+            upstream_status: Some(304),
+            duration_ms: 12,
+            request_id: "abc-123",
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "browser_proxy_access_log_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.join("access.log")
+    }
+
+    #[test]
+    fn test_common_format() {
+        let line = format_entry(AccessLogFormat::Common, &entry());
+        assert!(line.starts_with("10.0.0.1 - admin [05/Mar/2024:14:07:09 "));
+        assert!(line.contains("\"GET /proxy/https/example.com/index.html HTTP/1.1\" 200 512"));
+        assert!(line.ends_with(" \"https://example.com/index.html\" 304"));
+        assert!(!line.contains("curl"));
+    }
+
+    #[test]
+    fn test_combined_format_escapes_quotes() {
+        let line = format_entry(AccessLogFormat::Combined, &entry());
+        assert!(
+            line.contains(" 200 512 \"http://localhost:3000/home\" \"curl/8.0 \\\"quoted\\\"\"")
+        );
+    }
+
+    #[test]
+    fn test_missing_fields_use_dash() {
+        let mut e = entry();
+        e.user = None;
+        e.bytes = 0;
+        e.target_url = None;
+        e.upstream_status = None;
+        e.referer = None;
+
+        let line = format_entry(AccessLogFormat::Combined, &e);
+        assert!(line.starts_with("10.0.0.1 - - ["));
+        assert!(line.contains(" 200 - \"-\" "));
+        assert!(line.ends_with(" \"-\" -"));
+    }
+
+    #[test]
+    fn test_json_format() {
+        let line = format_entry(AccessLogFormat::Json, &entry());
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["user"], "admin");
+        assert_eq!(value["status"], 200);
+        assert_eq!(value["upstream_status"], 304);
+        assert_eq!(value["target_url"], "https://example.com/index.html");
+    }
+
+    #[test]
+    fn test_unknown_format_rejected() {
+        let config = AccessLogConfig {
+            format: "apache".to_string(),
+            path: temp_path("unknown").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        assert!(AccessLog::new(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rotates_when_size_exceeded() {
+        let path = temp_path("rotate");
+        let config = AccessLogConfig {
+            enabled: true,
+            path: path.to_string_lossy().to_string(),
+            format: "common".to_string(),
+            max_size_mb: 1,
+            rotate: "never".to_string(),
+        };
+        let log = AccessLog::new(&config).unwrap();
+
+        let long_uri = format!("/{}", "a".repeat(600 * 1024));
+        let mut e = entry();
+        e.uri = &long_uri;
+        log.log(&e);
+        log.log(&e);
+        log.flush().await.unwrap();
+
+        let files = std::fs::read_dir(path.parent().unwrap()).unwrap().count();
+        assert_eq!(files, 2);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reopen_after_external_move() {
+        let path = temp_path("reopen");
+        let config = AccessLogConfig {
+            enabled: true,
+            path: path.to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let log = AccessLog::new(&config).unwrap();
+        log.log(&entry());
+        log.flush().await.unwrap();
+
+        std::fs::rename(&path, path.with_extension("log.1")).unwrap();
+        log.reopen().await.unwrap();
+        log.log(&entry());
+        log.flush().await.unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), 1);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_rotated_name_not_reused() {
+        let path = temp_path("collide");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+        let first = rotated_path(&path, "20240305-140709.123");
+        assert!(first
+            .to_string_lossy()
+            .ends_with("access.log.20240305-140709.123"));
+        std::fs::write(&first, "").unwrap();
+
+        let second = rotated_path(&path, "20240305-140709.123");
+        assert!(second
+            .to_string_lossy()
+            .ends_with("access.log.20240305-140709.123.1"));
+        std::fs::write(&second, "").unwrap();
+
+        let third = rotated_path(&path, "20240305-140709.123");
+        assert!(third
+            .to_string_lossy()
+            .ends_with("access.log.20240305-140709.123.2"));
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
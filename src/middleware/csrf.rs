@@ -0,0 +1,271 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::{header, Method, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use tower_sessions::Session;
+
+/// Session key holding the synchronizer token
+pub const CSRF_KEY: &str = "csrf_token";
+/// Form field carrying the token back
+const CSRF_FIELD: &str = "csrf_token";
+/// Header carrying the token back on requests without a form body
+const CSRF_HEADER: &str = "x-csrf-token";
+/// Largest form body buffered to find the token
+const MAX_FORM_BYTES: usize = 64 * 1024;
+
+/// The session's CSRF token, created on first use. Forms embed it as a
+/// hidden `csrf_token` field.
+pub async fn csrf_token(session: &Session) -> String {
+    if let Ok(Some(token)) = session.get::<String>(CSRF_KEY).await {
+        return token;
+    }
+
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+    if let Err(e) = session.insert(CSRF_KEY, &token).await {
+        tracing::error!("Failed to store CSRF token: {}", e);
+    }
+    token
+}
+
+fn is_safe(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// `host[:port]` of an Origin or Referer value
+fn origin_host(value: &str) -> Option<String> {
+    let uri: Uri = value.parse().ok()?;
+    let authority = uri.authority()?;
+    Some(authority.as_str().rsplit('@').next()?.to_ascii_lowercase())
+}
+
+/// Whether the request's Origin (or, failing that, Referer) names this host.
+/// `None` when the browser sent neither.
+fn same_origin(request: &Request) -> Option<bool> {
+    let headers = request.headers();
+    let host = headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_ascii_lowercase);
+    let source = headers
+        .get(header::ORIGIN)
+        .or_else(|| headers.get(header::REFERER))?
+        .to_str()
+        .ok();
+
+    Some(match (source.and_then(origin_host), host) {
+        (Some(source), Some(host)) => source == host,
+        // "Origin: null" (sandboxed frames, some redirects) or no Host
+        _ => false,
+    })
+}
+
+/// Whether the request authenticates with an API token
+fn has_bearer_token(request: &Request) -> bool {
+    request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split_once(' '))
+        .is_some_and(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+}
+
+/// Constant-time comparison, so response timing does not leak the token
+fn tokens_match(expected: &str, actual: &str) -> bool {
+    expected.len() == actual.len()
+        && expected
+            .bytes()
+            .zip(actual.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+fn forbidden(reason: &str) -> Response {
+    (
+        StatusCode::FORBIDDEN,
+        format!("{}. Reload the page and try again.", reason),
+    )
+        .into_response()
+}
+
+/// Rejects cross-site state-changing requests.
+///
+/// Every POST (or other unsafe method) must come from a page on this origin
+/// according to `Origin`/`Referer`. Requests to the proxy's own forms must
+/// also carry the session's synchronizer token, as a `csrf_token` form field
+/// or, for JSON requests, an `X-CSRF-Token` header; requests under `/proxy/`
+/// are bound for upstream sites, which have their own forms and tokens, so
+/// for them an Origin or Referer is required instead.
+pub async fn verify_csrf(session: Session, request: Request, next: Next) -> Response {
+    if is_safe(request.method()) {
+        return next.run(request).await;
+    }
+    // Token-authenticated requests ignore the session cookie, so there is
+    // nothing for a forged request to ride on
+    if has_bearer_token(&request) {
+        return next.run(request).await;
+    }
+
+    let proxied = request.uri().path().starts_with("/proxy/");
+    match same_origin(&request) {
+        Some(true) => {}
+        Some(false) => {
+            tracing::warn!(
+                "Blocked cross-site {} to {}",
+                request.method(),
+                request.uri().path()
+            );
+            return forbidden("Cross-site request blocked");
+        }
+        None if proxied => {
+            tracing::warn!(
+                "Blocked {} to {} without Origin or Referer",
+                request.method(),
+                request.uri().path()
+            );
+            return forbidden("Cross-site request blocked");
+        }
+        None => {}
+    }
+    if proxied {
+        return next.run(request).await;
+    }
+
+    let expected: Option<String> = session.get(CSRF_KEY).await.ok().flatten();
+    if let Some(submitted) = request.headers().get(CSRF_HEADER) {
+        let valid = expected
+            .zip(submitted.to_str().ok())
+            .is_some_and(|(expected, submitted)| tokens_match(&expected, submitted));
+        if !valid {
+            tracing::warn!("Invalid CSRF token header on {}", request.uri().path());
+            return forbidden("Invalid or missing CSRF token");
+        }
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let Ok(bytes) = to_bytes(body, MAX_FORM_BYTES).await else {
+        return (StatusCode::PAYLOAD_TOO_LARGE, "Form too large").into_response();
+    };
+
+    let submitted = url::form_urlencoded::parse(&bytes)
+        .find(|(name, _)| name == CSRF_FIELD)
+        .map(|(_, value)| value.into_owned());
+    match (expected, submitted) {
+        (Some(expected), Some(submitted)) if tokens_match(&expected, &submitted) => {}
+        _ => {
+            tracing::warn!("Missing or invalid CSRF token on {}", parts.uri.path());
+            return forbidden("Invalid or missing CSRF token");
+        }
+    }
+
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, headers: &[(header::HeaderName, &str)]) -> Request {
+        let mut builder = Request::builder()
+            .method(method)
+            .uri("/browse")
+            .header(header::HOST, "proxy.example.com:3000");
+        for (name, value) in headers {
+            builder = builder.header(name, *value);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_same_origin() {
+        let ok = request(
+            Method::POST,
+            &[(header::ORIGIN, "https://proxy.example.com:3000")],
+        );
+        assert_eq!(same_origin(&ok), Some(true));
+
+        let evil = request(Method::POST, &[(header::ORIGIN, "https://evil.example")]);
+        assert_eq!(same_origin(&evil), Some(false));
+
+        let null = request(Method::POST, &[(header::ORIGIN, "null")]);
+        assert_eq!(same_origin(&null), Some(false));
+
+        assert_eq!(same_origin(&request(Method::POST, &[])), None);
+    }
+
+    #[test]
+    fn test_referer_used_without_origin() {
+        let ok = request(
+            Method::POST,
+            &[(header::REFERER, "http://proxy.example.com:3000/home?x=1")],
+        );
+        assert_eq!(same_origin(&ok), Some(true));
+
+        // Port is part of the origin
+        let other_port = request(
+            Method::POST,
+            &[(header::REFERER, "http://proxy.example.com/home")],
+        );
+        assert_eq!(same_origin(&other_port), Some(false));
+    }
+
+    #[tokio::test]
+    async fn test_token_accepted_in_header() {
+        use axum::{routing::get, Router};
+        use tower::Service;
+        use tower_sessions::{MemoryStore, SessionManagerLayer};
+
+        let mut app = Router::new()
+            .route(
+                "/api/rules",
+                get(|session: Session| async move { csrf_token(&session).await })
+                    .post(|| async { "ok" }),
+            )
+            .layer(axum::middleware::from_fn(verify_csrf))
+            .layer(SessionManagerLayer::new(MemoryStore::default()));
+
+        let response = app
+            .clone()
+            .call(Request::get("/api/rules").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let cookie = response.headers()[header::SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_string();
+        let token = to_bytes(response.into_body(), 1024).await.unwrap();
+        let token = std::str::from_utf8(&token).unwrap();
+
+        let post = |csrf: &str| {
+            Request::post("/api/rules")
+                .header(header::COOKIE, &cookie)
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(CSRF_HEADER, csrf)
+                .body(Body::from("{}"))
+                .unwrap()
+        };
+        let accepted = app.clone().call(post(token)).await.unwrap();
+        assert_eq!(accepted.status(), StatusCode::OK);
+        let rejected = app.call(post("forged")).await.unwrap();
+        assert_eq!(rejected.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match("abc123", "abc123"));
+        assert!(!tokens_match("abc123", "abc124"));
+        assert!(!tokens_match("abc123", "abc12"));
+        assert!(!tokens_match("abc123", ""));
+    }
+}
@@ -1,42 +1,471 @@
 use anyhow::{bail, Result};
+use regex::Regex;
+use std::borrow::Cow;
+use std::sync::Arc;
 use url::Url;
 use wildmatch::WildMatch;
 
+use super::feeds::BlocklistFeeds;
+use super::public_suffix::is_public_suffix;
 use crate::config::DomainFilterConfig;
 
+/// Outcome of a domain check along with the rule that decided it
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterDecision {
+    pub allowed: bool,
+    /// Pattern that matched, or `default-deny` when nothing in the allowlist matched
+    pub rule: String,
+}
+
+/// Lowercase a host name, drop a trailing dot and convert internationalized
+/// labels to punycode, so `Bücher.Example.` matches `xn--bcher-kva.example`
+pub fn normalize_host(host: &str) -> Cow<'_, str> {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    if host
+        .bytes()
+        .all(|b| b.is_ascii() && !b.is_ascii_uppercase())
+    {
+        return Cow::Borrowed(host);
+    }
+    let labels: Vec<String> = host
+        .split('.')
+        .map(|label| match label.is_ascii() {
+            true => label.to_ascii_lowercase(),
+            false => idna::domain_to_ascii(label).unwrap_or_else(|_| label.to_lowercase()),
+        })
+        .collect();
+    Cow::Owned(labels.join("."))
+}
+
+/// What a request is for, as far as the filter is concerned
+#[derive(Debug, Clone)]
+pub struct Target<'a> {
+    scheme: &'a str,
+    host: Cow<'a, str>,
+    port: Option<u16>,
+    path: &'a str,
+}
+
+impl<'a> Target<'a> {
+    pub fn url(url: &'a Url) -> Self {
+        Self {
+            scheme: url.scheme(),
+            host: normalize_host(url.host_str().unwrap_or("")),
+            port: url.port_or_known_default(),
+            path: url.path(),
+        }
+    }
+
+    /// A bare host, checked as an https request for its root
+    pub fn host(host: &'a str) -> Self {
+        Self {
+            scheme: "https",
+            host: normalize_host(host),
+            port: Some(443),
+            path: "/",
+        }
+    }
+}
+
+/// How the host part of a pattern matches
+#[derive(Debug)]
+enum HostPattern {
+    /// `example.com`: that host only
+    Exact(String),
+    /// `.example.com`: the domain and all of its subdomains
+    Apex(String),
+    /// `*.example.com`, `www?.example.com`
+    Glob(WildMatch),
+    /// `re:<regex>`, anchored at both ends
+    Regex(Regex),
+}
+
+impl HostPattern {
+    fn parse(text: &str, host: &str) -> Result<Self> {
+        if let Some(regex) = host.strip_prefix("re:") {
+            return Regex::new(&format!("^(?:{})$", regex))
+                .map(HostPattern::Regex)
+                .map_err(|e| anyhow::anyhow!("Pattern '{}' is not a valid regex: {}", text, e));
+        }
+        let host = normalize_host(host);
+        if let Some(c) = host.chars().find(|c| {
+            !(c.is_ascii_alphanumeric()
+                || matches!(c, '.' | '-' | '_' | '*' | '?' | '[' | ']' | ':'))
+        }) {
+            bail!(
+                "Pattern '{}' is not a valid host pattern (unexpected '{}')",
+                text,
+                c
+            );
+        }
+        if let Some(apex) = host.strip_prefix('.') {
+            if apex.is_empty() || apex.contains(['*', '?']) {
+                bail!("Pattern '{}' needs a domain after the leading dot", text);
+            }
+            return Ok(HostPattern::Apex(apex.to_string()));
+        }
+        if host.contains(['*', '?']) {
+            return Ok(HostPattern::Glob(WildMatch::new(&host)));
+        }
+        Ok(HostPattern::Exact(host.into_owned()))
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(exact) => host == exact,
+            HostPattern::Apex(apex) => host
+                .strip_suffix(apex.as_str())
+                .is_some_and(|sub| sub.is_empty() || sub.ends_with('.')),
+            HostPattern::Glob(glob) => glob.matches(host),
+            HostPattern::Regex(regex) => regex.is_match(host),
+        }
+    }
+
+    /// The public suffix whose registrable domains this would all match, if
+    /// any. Regexes can only be probed, with a few well-known suffixes.
+    fn spanned_suffix(&self) -> Option<String> {
+        match self {
+            HostPattern::Exact(_) => None,
+            HostPattern::Apex(apex) => is_public_suffix(apex).then(|| apex.clone()),
+            HostPattern::Glob(glob) => {
+                let glob = glob.to_string();
+                // Address ranges like 10.0.0.* have no public suffix
+                if glob.contains(|c: char| c.is_ascii_digit())
+                    && glob
+                        .chars()
+                        .all(|c| c.is_ascii_digit() || matches!(c, '.' | '*' | '?'))
+                {
+                    return None;
+                }
+                // The fixed part after the last wildcard, minus any partly
+                // matched label: `*.co.uk` and `*co.uk` both span `co.uk`/`uk`
+                let fixed = &glob[glob.rfind(['*', '?']).map_or(0, |i| i + 1)..];
+                let tail = match fixed.strip_prefix('.') {
+                    Some(tail) => tail,
+                    None => fixed.split_once('.').map_or("", |(_, tail)| tail),
+                };
+                (tail.is_empty() || is_public_suffix(tail)).then(|| tail.to_string())
+            }
+            // Matching unrelated names under a well-known suffix gives it away
+            HostPattern::Regex(regex) => ["com", "co.uk", "github.io"]
+                .into_iter()
+                .find(|suffix| {
+                    ["psl-probe", "xq", "zz"]
+                        .iter()
+                        .filter(|label| regex.is_match(&format!("{}.{}", label, suffix)))
+                        .count()
+                        >= 2
+                })
+                .map(str::to_string),
+        }
+    }
+}
+
+/// An allowlist, blocklist or rules pattern: `[scheme://]host[:ports][/path]`
+/// or `re:<host regex>`.
+///
+/// A bare host pattern matches every scheme, port and path, as before. Ports
+/// are a comma-separated list, `!` in front to match every other port. A
+/// path ending in `/*` also matches the path without it.
+#[derive(Debug)]
+struct Pattern {
+    text: String,
+    /// `text` with the scheme lowercased and the host normalized
+    normalized: String,
+    scheme: Option<String>,
+    host: HostPattern,
+    ports: Option<(bool, Vec<u16>)>,
+    path: Option<WildMatch>,
+    path_prefix: Option<String>,
+}
+
+impl Pattern {
+    fn parse(text: &str) -> Result<Self> {
+        let text = text.trim();
+        // A regex may contain anything, so it stands for the host alone
+        if text.starts_with("re:") {
+            return Ok(Self {
+                text: text.to_string(),
+                normalized: text.to_string(),
+                scheme: None,
+                host: HostPattern::parse(text, text)?,
+                ports: None,
+                path: None,
+                path_prefix: None,
+            });
+        }
+        let (scheme, rest) = match text.split_once("://") {
+            Some(("*", rest)) => (None, rest),
+            Some((scheme, rest)) => (Some(scheme.to_ascii_lowercase()), rest),
+            None => (None, text),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], Some(&rest[i..])),
+            None => (rest, None),
+        };
+        // Skip past the brackets of an IPv6 address before looking for a port
+        let port_from = authority.find(']').unwrap_or(0);
+        let (host, ports) = match authority[port_from..].rfind(':') {
+            Some(i) => (
+                &authority[..port_from + i],
+                Some(&authority[port_from + i + 1..]),
+            ),
+            None => (authority, None),
+        };
+        if host.is_empty() {
+            bail!("Pattern '{}' has no host", text);
+        }
+
+        let ports = match ports {
+            None | Some("*") => None,
+            Some(ports) => {
+                let (negated, list) = match ports.strip_prefix('!') {
+                    Some(list) => (true, list),
+                    None => (false, ports),
+                };
+                let list = list
+                    .split(',')
+                    .map(|p| p.trim().parse::<u16>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| anyhow::anyhow!("Pattern '{}' has an invalid port", text))?;
+                Some((negated, list))
+            }
+        };
+
+        let mut normalized = String::new();
+        if let Some(scheme) = &scheme {
+            normalized.push_str(scheme);
+            normalized.push_str("://");
+        }
+        normalized.push_str(&normalize_host(host));
+        if let Some((negated, list)) = &ports {
+            let list: Vec<String> = list.iter().map(u16::to_string).collect();
+            normalized.push(':');
+            if *negated {
+                normalized.push('!');
+            }
+            normalized.push_str(&list.join(","));
+        }
+        normalized.push_str(path.unwrap_or(""));
+
+        Ok(Self {
+            text: text.to_string(),
+            normalized,
+            scheme,
+            host: HostPattern::parse(text, host)?,
+            ports,
+            path: path.map(WildMatch::new),
+            path_prefix: path.and_then(|p| p.strip_suffix("/*")).map(str::to_string),
+        })
+    }
+
+    /// Refuse an allow pattern that would cover everything under a public
+    /// suffix, like `*.co.uk` or `.github.io`
+    fn check_registrable(&self) -> Result<()> {
+        match self.host.spanned_suffix() {
+            Some(suffix) if suffix.is_empty() => {
+                bail!("Pattern '{}' would allow every domain", self.text)
+            }
+            Some(suffix) => bail!(
+                "Pattern '{}' would allow every domain under the public suffix '{}'",
+                self.text,
+                suffix
+            ),
+            None => Ok(()),
+        }
+    }
+
+    fn matches(&self, target: &Target) -> bool {
+        if let Some(scheme) = &self.scheme
+            && scheme != target.scheme
+        {
+            return false;
+        }
+        if !self.host.matches(&target.host) {
+            return false;
+        }
+        if let Some((negated, ports)) = &self.ports {
+            let listed = target.port.is_some_and(|port| ports.contains(&port));
+            if listed == *negated {
+                return false;
+            }
+        }
+        match &self.path {
+            Some(path) => {
+                path.matches(target.path) || self.path_prefix.as_deref() == Some(target.path)
+            }
+            None => true,
+        }
+    }
+}
+
+/// Check an allowlist or blocklist pattern and return it in the form the
+/// filter compares it in: scheme lowercased, host as by [`normalize_host`].
+/// Regexes and paths are kept as written.
+pub fn normalize_pattern(text: &str) -> Result<String> {
+    Ok(Pattern::parse(text)?.normalized)
+}
+
+/// Like [`normalize_pattern`], for an entry of the ordered `rules` list
+pub fn normalize_rule(text: &str) -> Result<String> {
+    let rule = Rule::parse(text)?;
+    let action = if rule.allow { "allow" } else { "deny" };
+    Ok(format!("{} {}", action, rule.pattern.normalized))
+}
+
+/// An entry of the ordered `rules` list: `allow <pattern>` or `deny <pattern>`
+#[derive(Debug)]
+struct Rule {
+    text: String,
+    allow: bool,
+    pattern: Pattern,
+}
+
+impl Rule {
+    fn parse(text: &str) -> Result<Self> {
+        let text = text.trim();
+        let (action, pattern) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let allow = match action.to_ascii_lowercase().as_str() {
+            "allow" => true,
+            "deny" => false,
+            _ => bail!("Rule '{}' must start with 'allow' or 'deny'", text),
+        };
+        Ok(Self {
+            text: text.to_string(),
+            allow,
+            pattern: Pattern::parse(pattern)?,
+        })
+    }
+}
+
+/// Decides which requests may be proxied.
+///
+/// The ordered `rules` are checked first and the first one that matches
+/// decides. Otherwise the blocklist and blocklist feeds win over the
+/// allowlist, and anything neither list matches is denied.
+#[derive(Debug)]
 pub struct DomainFilter {
-    allowlist: Vec<WildMatch>,
-    blocklist: Vec<WildMatch>,
+    rules: Vec<Rule>,
+    allowlist: Vec<Pattern>,
+    blocklist: Vec<Pattern>,
+    feeds: Option<Arc<BlocklistFeeds>>,
 }
 
 impl DomainFilter {
     pub fn new(config: &DomainFilterConfig) -> Result<Self> {
-        // Validate: allowlist must not be empty
-        if config.allowlist.is_empty() {
+        let rules = config
+            .rules
+            .iter()
+            .map(|r| Rule::parse(r))
+            .collect::<Result<Vec<_>>>()?;
+
+        // Validate: something must be allowed
+        if config.allowlist.is_empty() && !rules.iter().any(|r| r.allow) {
             bail!("Allowlist cannot be empty. Add at least one domain to config.toml");
         }
 
+        let allowlist: Vec<Pattern> = config
+            .allowlist
+            .iter()
+            .map(|p| Pattern::parse(p))
+            .collect::<Result<_>>()?;
+
+        // Blocking a whole suffix is fine; allowing one almost never is
+        for pattern in rules
+            .iter()
+            .filter(|r| r.allow)
+            .map(|r| &r.pattern)
+            .chain(&allowlist)
+        {
+            pattern.check_registrable()?;
+        }
+
         Ok(Self {
-            allowlist: config.allowlist.iter().map(|p| WildMatch::new(p)).collect(),
-            blocklist: config.blocklist.iter().map(|p| WildMatch::new(p)).collect(),
+            rules,
+            allowlist,
+            blocklist: config
+                .blocklist
+                .iter()
+                .map(|p| Pattern::parse(p))
+                .collect::<Result<_>>()?,
+            feeds: None,
         })
     }
 
+    /// Also block the domains listed by `feeds`, which are loaded and
+    /// refreshed separately and shared between rebuilt filters
+    pub fn with_feeds(mut self, feeds: Option<Arc<BlocklistFeeds>>) -> Self {
+        self.feeds = feeds;
+        self
+    }
+
     pub fn is_allowed(&self, domain: &str) -> bool {
-        // 1. Check blocklist first - blocklist always takes precedence
-        if self.blocklist.iter().any(|pattern| pattern.matches(domain)) {
-            tracing::warn!("Domain blocked by blocklist: {}", domain);
-            return false;
+        self.check(domain).allowed
+    }
+
+    /// Check a bare domain; see [`Target::host`]
+    pub fn check(&self, domain: &str) -> FilterDecision {
+        self.decide(&Target::host(domain))
+    }
+
+    pub fn check_url(&self, url: &Url) -> FilterDecision {
+        self.decide(&Target::url(url))
+    }
+
+    pub fn decide(&self, target: &Target) -> FilterDecision {
+        // 1. Ordered rules and the blocklist
+        if let Some(rule) = self.blocked_by(target) {
+            tracing::warn!("Domain blocked by {}: {}", rule, target.host);
+            return FilterDecision {
+                allowed: false,
+                rule,
+            };
+        }
+        if let Some(rule) = self.rules.iter().find(|r| r.pattern.matches(target)) {
+            return FilterDecision {
+                allowed: true,
+                rule: rule.text.clone(),
+            };
         }
 
         // 2. Check if domain is in allowlist (required)
-        let allowed = self.allowlist.iter().any(|pattern| pattern.matches(domain));
+        match self.allowlist.iter().find(|p| p.matches(target)) {
+            Some(pattern) => FilterDecision {
+                allowed: true,
+                rule: pattern.text.clone(),
+            },
+            None => {
+                tracing::warn!("Domain not in allowlist: {}", target.host);
+                FilterDecision {
+                    allowed: false,
+                    rule: "default-deny".to_string(),
+                }
+            }
+        }
+    }
 
-        if !allowed {
-            tracing::warn!("Domain not in allowlist: {}", domain);
+    /// Deny rule, blocklist pattern or feed refusing the target whatever
+    /// allowlist applies, if any. An allow rule listed first shields it from
+    /// all of them.
+    pub fn blocked_by(&self, target: &Target) -> Option<String> {
+        if let Some(rule) = self.rules.iter().find(|r| r.pattern.matches(target)) {
+            return (!rule.allow).then(|| rule.text.clone());
+        }
+        if let Some(pattern) = self.blocklist.iter().find(|p| p.matches(target)) {
+            return Some(pattern.text.clone());
         }
+        let source = self.feeds.as_ref()?.blocked(&target.host)?;
+        Some(format!("feed:{}", source))
+    }
 
-        allowed
+    /// Patterns of the allowlist and allow rules
+    pub fn allowlist_patterns(&self) -> Vec<String> {
+        self.rules
+            .iter()
+            .filter(|r| r.allow)
+            .map(|r| r.pattern.text.clone())
+            .chain(self.allowlist.iter().map(|p| p.text.clone()))
+            .collect()
     }
 
     pub fn validate_start_url(&self, url: &Url) -> Result<()> {
@@ -44,7 +473,7 @@ impl DomainFilter {
             .host_str()
             .ok_or_else(|| anyhow::anyhow!("Invalid URL: no host"))?;
 
-        if !self.is_allowed(domain) {
+        if !self.check_url(url).allowed {
             bail!(
                 "Domain '{}' is not in allowlist. Add it to config.toml to proxy this site.",
                 domain
@@ -64,6 +493,9 @@ mod tests {
         let config = DomainFilterConfig {
             allowlist: vec![],
             blocklist: vec![],
+            rules: vec![],
+            feeds: vec![],
+            feed_refresh_minutes: 360,
         };
 
         let result = DomainFilter::new(&config);
@@ -75,6 +507,9 @@ mod tests {
         let config = DomainFilterConfig {
             allowlist: vec!["example.com".to_string()],
             blocklist: vec![],
+            rules: vec![],
+            feeds: vec![],
+            feed_refresh_minutes: 360,
         };
 
         let filter = DomainFilter::new(&config).unwrap();
@@ -88,6 +523,9 @@ mod tests {
         let config = DomainFilterConfig {
             allowlist: vec!["*.example.com".to_string()],
             blocklist: vec![],
+            rules: vec![],
+            feeds: vec![],
+            feed_refresh_minutes: 360,
         };
 
         let filter = DomainFilter::new(&config).unwrap();
@@ -102,6 +540,9 @@ mod tests {
         let config = DomainFilterConfig {
             allowlist: vec!["*.example.com".to_string()],
             blocklist: vec!["ads.example.com".to_string()],
+            rules: vec![],
+            feeds: vec![],
+            feed_refresh_minutes: 360,
         };
 
         let filter = DomainFilter::new(&config).unwrap();
@@ -110,11 +551,31 @@ mod tests {
         assert!(!filter.is_allowed("ads.example.com")); // Blocked even though matches allowlist
     }
 
+    #[test]
+    fn test_check_reports_matching_rule() {
+        let config = DomainFilterConfig {
+            allowlist: vec!["*.example.com".to_string()],
+            blocklist: vec!["ads.example.com".to_string()],
+            rules: vec![],
+            feeds: vec![],
+            feed_refresh_minutes: 360,
+        };
+
+        let filter = DomainFilter::new(&config).unwrap();
+
+        assert_eq!(filter.check("www.example.com").rule, "*.example.com");
+        assert_eq!(filter.check("ads.example.com").rule, "ads.example.com");
+        assert_eq!(filter.check("other.com").rule, "default-deny");
+    }
+
     #[test]
     fn test_validate_start_url() {
         let config = DomainFilterConfig {
             allowlist: vec!["example.com".to_string()],
             blocklist: vec![],
+            rules: vec![],
+            feeds: vec![],
+            feed_refresh_minutes: 360,
         };
 
         let filter = DomainFilter::new(&config).unwrap();
@@ -125,4 +586,208 @@ mod tests {
         let blocked_url = Url::parse("https://other.com/path").unwrap();
         assert!(filter.validate_start_url(&blocked_url).is_err());
     }
+
+    fn filter(allowlist: &[&str], blocklist: &[&str], rules: &[&str]) -> DomainFilter {
+        DomainFilter::new(&DomainFilterConfig {
+            allowlist: allowlist.iter().map(|s| s.to_string()).collect(),
+            blocklist: blocklist.iter().map(|s| s.to_string()).collect(),
+            rules: rules.iter().map(|s| s.to_string()).collect(),
+            feeds: vec![],
+            feed_refresh_minutes: 360,
+        })
+        .unwrap()
+    }
+
+    fn check(filter: &DomainFilter, url: &str) -> FilterDecision {
+        filter.check_url(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn test_bare_domain_matches_any_scheme_port_and_path() {
+        let filter = filter(&["example.com"], &[], &[]);
+
+        assert!(check(&filter, "https://example.com/a/b?c=d").allowed);
+        assert!(check(&filter, "http://example.com:8080/").allowed);
+        assert!(!check(&filter, "https://other.com/").allowed);
+    }
+
+    #[test]
+    fn test_path_rules() {
+        let filter = filter(&["github.com/ourorg/*", "docs.rs/"], &[], &[]);
+
+        assert!(check(&filter, "https://github.com/ourorg/repo/issues").allowed);
+        assert!(check(&filter, "https://github.com/ourorg").allowed);
+        assert!(!check(&filter, "https://github.com/otherorg/repo").allowed);
+        assert!(!check(&filter, "https://github.com/ourorgx").allowed);
+        assert!(check(&filter, "https://docs.rs/").allowed);
+        assert!(!check(&filter, "https://docs.rs/serde").allowed);
+        assert_eq!(
+            check(&filter, "https://github.com/ourorg/repo").rule,
+            "github.com/ourorg/*"
+        );
+    }
+
+    #[test]
+    fn test_scheme_and_port_rules() {
+        let filter = filter(
+            &["*.example.com", "intranet.local:8443"],
+            &["http://*", "*:!80,443,8443"],
+            &[],
+        );
+
+        assert!(check(&filter, "https://www.example.com/").allowed);
+        assert!(!check(&filter, "http://www.example.com/").allowed);
+        assert_eq!(check(&filter, "http://www.example.com/").rule, "http://*");
+        assert!(!check(&filter, "https://www.example.com:8080/").allowed);
+        assert!(check(&filter, "https://intranet.local:8443/").allowed);
+        assert!(!check(&filter, "https://intranet.local/").allowed);
+    }
+
+    #[test]
+    fn test_ordered_rules_first_match_wins() {
+        let filter = filter(
+            &["*.example.com"],
+            &["github.com"],
+            &[
+                "allow github.com/ourorg/*",
+                "deny *.example.com/admin/*",
+                "allow *.partner.com",
+            ],
+        );
+
+        // An allow rule listed first overrides the blocklist
+        assert!(check(&filter, "https://github.com/ourorg/repo").allowed);
+        assert!(!check(&filter, "https://github.com/other").allowed);
+        // A deny rule overrides the allowlist
+        let decision = check(&filter, "https://www.example.com/admin/users");
+        assert!(!decision.allowed);
+        assert_eq!(decision.rule, "deny *.example.com/admin/*");
+        assert!(check(&filter, "https://www.example.com/home").allowed);
+        // Allow rules count as an allowlist on their own
+        assert!(check(&filter, "https://api.partner.com/").allowed);
+        assert_eq!(
+            filter.allowlist_patterns(),
+            vec!["github.com/ourorg/*", "*.partner.com", "*.example.com"]
+        );
+        assert!(DomainFilter::new(&DomainFilterConfig {
+            allowlist: vec![],
+            blocklist: vec![],
+            rules: vec!["allow example.com".to_string()],
+            feeds: vec![],
+            feed_refresh_minutes: 360,
+        })
+        .is_ok());
+    }
+
+    #[test]
+    fn test_invalid_patterns_rejected() {
+        for (allowlist, rules) in [
+            ("example.com:http", ""),
+            ("https://", ""),
+            (":443", ""),
+            ("exa mple.com", ""),
+            ("example.com", "permit example.org"),
+            ("example.com", "allow"),
+        ] {
+            let config = DomainFilterConfig {
+                allowlist: vec![allowlist.to_string()],
+                blocklist: vec![],
+                rules: [rules]
+                    .iter()
+                    .filter(|r| !r.is_empty())
+                    .map(|r| r.to_string())
+                    .collect(),
+                feeds: vec![],
+                feed_refresh_minutes: 360,
+            };
+            assert!(DomainFilter::new(&config).is_err(), "{}", allowlist);
+        }
+    }
+
+    #[test]
+    fn test_ipv6_host_with_port() {
+        let filter = filter(&["[::1]:8080"], &[], &[]);
+
+        assert!(check(&filter, "http://[::1]:8080/").allowed);
+        assert!(!check(&filter, "http://[::1]:9090/").allowed);
+    }
+
+    #[test]
+    fn test_apex_and_regex_patterns() {
+        let filter = filter(&[".example.com", r"re:(www|api)\.example\.org"], &[], &[]);
+
+        assert!(filter.is_allowed("example.com"));
+        assert!(filter.is_allowed("deep.sub.example.com"));
+        assert!(!filter.is_allowed("notexample.com"));
+        assert!(filter.is_allowed("api.example.org"));
+        // Regexes are anchored at both ends
+        assert!(!filter.is_allowed("api.example.org.evil.com"));
+        assert!(!filter.is_allowed("xapi.example.org"));
+        assert_eq!(
+            check(&filter, "https://www.example.org/").rule,
+            r"re:(www|api)\.example\.org"
+        );
+    }
+
+    #[test]
+    fn test_domains_normalized() {
+        let filter = filter(&["Bücher.de", "*.Example.COM."], &[], &[]);
+
+        assert!(filter.is_allowed("xn--bcher-kva.de"));
+        assert!(filter.is_allowed("BÜCHER.de."));
+        assert!(filter.is_allowed("WWW.example.com."));
+        assert!(check(&filter, "https://www.EXAMPLE.com./").allowed);
+        assert_eq!(normalize_host("Www.Bücher.DE."), "www.xn--bcher-kva.de");
+    }
+
+    #[test]
+    fn test_normalize_admin_input() {
+        assert_eq!(
+            normalize_pattern(" HTTPS://Bücher.Example.com:!80, 8080/Docs/* ").unwrap(),
+            "https://xn--bcher-kva.example.com:!80,8080/Docs/*"
+        );
+        assert_eq!(
+            normalize_pattern("*://.Example.com.").unwrap(),
+            ".example.com"
+        );
+        assert_eq!(
+            normalize_pattern(r"re:^API\.example\.com$").unwrap(),
+            r"re:^API\.example\.com$"
+        );
+        assert_eq!(
+            normalize_rule("DENY  Example.com/admin").unwrap(),
+            "deny example.com/admin"
+        );
+        assert!(normalize_pattern("example.com:http").is_err());
+        assert!(normalize_rule("permit example.com").is_err());
+    }
+
+    #[test]
+    fn test_patterns_spanning_public_suffix_rejected() {
+        for pattern in [
+            "*",
+            "*.co.uk",
+            "*co.uk",
+            ".com",
+            ".github.io",
+            "www.*.com",
+            "re:.*",
+            r"re:[a-z]+\.co\.uk",
+        ] {
+            let config = DomainFilterConfig {
+                allowlist: vec![pattern.to_string()],
+                blocklist: vec![],
+                rules: vec![],
+                feeds: vec![],
+                feed_refresh_minutes: 360,
+            };
+            assert!(DomainFilter::new(&config).is_err(), "{}", pattern);
+        }
+        // Fine below a public suffix, in the blocklist, and for addresses
+        filter(
+            &["*.example.co.uk", ".ourorg.github.io", "10.0.0.*"],
+            &["*.ru", "re:.*\\.tk"],
+            &["deny .xyz"],
+        );
+    }
 }
@@ -0,0 +1,366 @@
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use super::domain_filter::normalize_host;
+
+/// How long one feed download may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How a feed lists a domain
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Entry {
+    /// Hosts files and plain lists: that name only
+    Exact,
+    /// `||example.com^`: the domain and all of its subdomains
+    Subtree,
+    /// `@@||example.com^`: never blocked by a feed, nor its subdomains
+    Exception,
+}
+
+/// Domains parsed from one feed
+#[derive(Debug, Default)]
+struct FeedList {
+    entries: HashMap<String, Entry>,
+}
+
+impl FeedList {
+    /// Read a hosts file (`0.0.0.0 ads.example.com`), an Adblock Plus domain
+    /// list (`||ads.example.com^`) or one domain per line. Anything else, such
+    /// as element hiding or path rules, is skipped.
+    fn parse(text: &str) -> Self {
+        let mut entries = HashMap::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(['#', '!', '[']) {
+                continue;
+            }
+
+            if let Some(rule) = line.strip_prefix("@@||") {
+                if let Some(domain) = adblock_domain(rule) {
+                    entries.insert(domain, Entry::Exception);
+                }
+            } else if let Some(rule) = line.strip_prefix("||") {
+                if let Some(domain) = adblock_domain(rule) {
+                    // An exception from the same list wins
+                    entries.entry(domain).or_insert(Entry::Subtree);
+                }
+            } else {
+                // A comment needs whitespace before it; `example.com##.ad`
+                // hides page elements and is not a domain entry
+                let line = match line.find('#') {
+                    Some(i) if line[..i].ends_with(char::is_whitespace) => &line[..i],
+                    Some(_) => continue,
+                    None => line,
+                };
+                let mut fields = line.split_whitespace();
+                let names: Vec<&str> = match fields.next() {
+                    Some(first) if first.parse::<IpAddr>().is_ok() => fields.collect(),
+                    Some(first) if fields.next().is_none() => vec![first],
+                    _ => continue,
+                };
+                for domain in names.into_iter().filter_map(domain) {
+                    entries.entry(domain).or_insert(Entry::Exact);
+                }
+            }
+        }
+        Self { entries }
+    }
+}
+
+/// The domain of `example.com^` or `example.com^$important`
+fn adblock_domain(rule: &str) -> Option<String> {
+    let (domain, options) = rule.split_once('^')?;
+    // Options narrowing the rule to some request types or pages would block
+    // more than intended once applied to the whole domain
+    let options = options.strip_prefix('$').unwrap_or(options);
+    if !options
+        .split(',')
+        .all(|o| matches!(o, "" | "important" | "all" | "third-party" | "3p"))
+    {
+        return None;
+    }
+    self::domain(domain)
+}
+
+/// A normalized host name, or None for `localhost`, addresses and anything
+/// that is not a name
+fn domain(name: &str) -> Option<String> {
+    let name = normalize_host(name);
+    let valid = name.contains('.')
+        && name.parse::<IpAddr>().is_err()
+        && name != "localhost.localdomain"
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        });
+    valid.then(|| name.into_owned())
+}
+
+/// Every feed's domains in one table, so a lookup costs one hash probe per
+/// label of the host instead of a scan of every entry
+#[derive(Debug, Default)]
+struct DomainSet {
+    /// Domain to entry and index of the feed listing it
+    entries: HashMap<String, (Entry, usize)>,
+}
+
+impl DomainSet {
+    fn build<'a>(lists: impl Iterator<Item = Option<&'a FeedList>>) -> Self {
+        let mut entries = HashMap::new();
+        for (feed, list) in lists.enumerate() {
+            let Some(list) = list else { continue };
+            for (domain, entry) in &list.entries {
+                match entries.get(domain) {
+                    // Exceptions win over blocks, and subtrees over exact names
+                    Some((Entry::Exception, _)) => {}
+                    Some((Entry::Subtree, _)) if *entry == Entry::Exact => {}
+                    _ => {
+                        entries.insert(domain.clone(), (*entry, feed));
+                    }
+                }
+            }
+        }
+        Self { entries }
+    }
+
+    /// Index of the feed blocking the host. The most specific listed name
+    /// decides, so `@@||cdn.example.com^` lets through what
+    /// `||example.com^` would block.
+    fn blocked(&self, host: &str) -> Option<usize> {
+        let mut name = host;
+        let mut exact = true;
+        loop {
+            match self.entries.get(name) {
+                Some((Entry::Exception, _)) => return None,
+                Some((Entry::Subtree, feed)) => return Some(*feed),
+                Some((Entry::Exact, feed)) if exact => return Some(*feed),
+                _ => {}
+            }
+            name = name.split_once('.')?.1;
+            exact = false;
+        }
+    }
+}
+
+/// Where one feed stands, for the admin console
+pub struct FeedStatus {
+    pub source: String,
+    pub domains: usize,
+    pub updated: Option<DateTime<Local>>,
+    pub error: Option<String>,
+}
+
+/// The last load of one source
+#[derive(Debug, Default)]
+struct Loaded {
+    /// From the last load that succeeded
+    list: Option<FeedList>,
+    updated: Option<DateTime<Local>>,
+    error: Option<String>,
+}
+
+/// Blocklists loaded from `domain_filter.feeds`: local files or URLs in hosts
+/// or Adblock Plus domain format, refreshed in the background.
+///
+/// A feed that fails to load keeps the domains from its last good load.
+/// Feeds are fetched directly, not through the upstream address checks, so a
+/// list may be served from the internal network.
+#[derive(Debug)]
+pub struct BlocklistFeeds {
+    sources: Vec<String>,
+    interval: Duration,
+    client: reqwest::Client,
+    /// Last good parse of each source
+    loaded: Mutex<Vec<Loaded>>,
+    compiled: RwLock<Arc<DomainSet>>,
+}
+
+impl BlocklistFeeds {
+    pub fn new(sources: &[String], interval: Duration) -> Result<Self> {
+        Ok(Self {
+            sources: sources.to_vec(),
+            interval,
+            client: reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?,
+            loaded: Mutex::new(sources.iter().map(|_| Loaded::default()).collect()),
+            compiled: RwLock::new(Arc::default()),
+        })
+    }
+
+    /// The feed blocking a normalized host, if any
+    pub fn blocked(&self, host: &str) -> Option<&str> {
+        let feed = self.compiled.read().unwrap().blocked(host)?;
+        Some(&self.sources[feed])
+    }
+
+    /// Load every feed again and swap in the new domains
+    pub async fn refresh(&self) {
+        let mut loaded = Vec::with_capacity(self.sources.len());
+        for source in &self.sources {
+            loaded.push(self.fetch(source).await.map(|text| FeedList::parse(&text)));
+        }
+
+        let mut current = self.loaded.lock().unwrap();
+        for (index, result) in loaded.into_iter().enumerate() {
+            let source = &self.sources[index];
+            match result {
+                Ok(list) => {
+                    tracing::info!(
+                        "Loaded {} domain(s) from blocklist feed {}",
+                        list.entries.len(),
+                        source
+                    );
+                    current[index] = Loaded {
+                        list: Some(list),
+                        updated: Some(Local::now()),
+                        error: None,
+                    };
+                }
+                Err(e) => {
+                    tracing::error!("Failed to load blocklist feed {}: {:#}", source, e);
+                    current[index].error = Some(format!("{:#}", e));
+                }
+            }
+        }
+        *self.compiled.write().unwrap() =
+            Arc::new(DomainSet::build(current.iter().map(|l| l.list.as_ref())));
+    }
+
+    /// Refresh every `domain_filter.feed_refresh_minutes`
+    pub async fn refresh_periodically(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            self.refresh().await;
+        }
+    }
+
+    pub fn status(&self) -> Vec<FeedStatus> {
+        let loaded = self.loaded.lock().unwrap();
+        self.sources
+            .iter()
+            .zip(loaded.iter())
+            .map(|(source, loaded)| FeedStatus {
+                source: source.clone(),
+                domains: loaded.list.as_ref().map_or(0, |l| l.entries.len()),
+                updated: loaded.updated,
+                error: loaded.error.clone(),
+            })
+            .collect()
+    }
+
+    async fn fetch(&self, source: &str) -> Result<String> {
+        if source.starts_with("http://") || source.starts_with("https://") {
+            let response = self.client.get(source).send().await?.error_for_status()?;
+            Ok(response.text().await?)
+        } else {
+            Ok(tokio::fs::read_to_string(source).await?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DomainFilterConfig;
+    use crate::middleware::DomainFilter;
+
+    #[test]
+    fn test_hosts_and_adblock_formats() {
+        let list = FeedList::parse(
+            "# hosts\n\
+             127.0.0.1 localhost\n\
+             0.0.0.0 ads.example.com tracker.example.net # inline\n\
+             ::1 ip6-localhost\n\
+             plain.example.org\n\
+             [Adblock Plus 2.0]\n\
+             ! comment\n\
+             ||doubleclick.net^\n\
+             ||metrics.example.com^$important\n\
+             ||example.com/path^\n\
+             ||images.example.com^$image\n\
+             @@||ok.doubleclick.net^\n\
+             example.com##.banner\n",
+        );
+        let set = DomainSet::build([Some(&list)].into_iter());
+
+        assert_eq!(set.blocked("ads.example.com"), Some(0));
+        assert_eq!(set.blocked("tracker.example.net"), Some(0));
+        assert_eq!(set.blocked("plain.example.org"), Some(0));
+        // Hosts entries are exact, Adblock entries cover subdomains
+        assert_eq!(set.blocked("sub.ads.example.com"), None);
+        assert_eq!(set.blocked("doubleclick.net"), Some(0));
+        assert_eq!(set.blocked("ad.g.doubleclick.net"), Some(0));
+        assert_eq!(set.blocked("metrics.example.com"), Some(0));
+        assert_eq!(set.blocked("ok.doubleclick.net"), None);
+        assert_eq!(set.blocked("cdn.ok.doubleclick.net"), None);
+        // Path, type-specific and cosmetic rules are skipped
+        assert_eq!(set.blocked("example.com"), None);
+        assert_eq!(set.blocked("images.example.com"), None);
+        assert_eq!(set.blocked("localhost"), None);
+    }
+
+    #[tokio::test]
+    async fn test_feeds_load_from_file_and_url() {
+        let dir = std::env::temp_dir().join(format!("browser_proxy_feeds_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hosts");
+        std::fs::write(&path, "0.0.0.0 ads.example.com\n").unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/list.txt", listener.local_addr().unwrap());
+        let app = axum::Router::new().route(
+            "/list.txt",
+            axum::routing::get(|| async { "||tracker.example.net^\n" }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let sources = vec![
+            path.to_string_lossy().into_owned(),
+            url.clone(),
+            dir.join("missing").to_string_lossy().into_owned(),
+        ];
+        let feeds = BlocklistFeeds::new(&sources, Duration::from_secs(60)).unwrap();
+        assert_eq!(feeds.blocked("ads.example.com"), None);
+
+        feeds.refresh().await;
+        assert_eq!(feeds.blocked("ads.example.com"), Some(sources[0].as_str()));
+        assert_eq!(feeds.blocked("www.tracker.example.net"), Some(url.as_str()));
+
+        // Feeds block like the blocklist, and an allow rule listed first wins
+        let feeds = Arc::new(feeds);
+        let filter = DomainFilter::new(&DomainFilterConfig {
+            allowlist: vec![".example.com".to_string(), ".example.net".to_string()],
+            blocklist: vec![],
+            rules: vec!["allow www.tracker.example.net".to_string()],
+            feeds: sources.clone(),
+            feed_refresh_minutes: 360,
+        })
+        .unwrap()
+        .with_feeds(Some(feeds.clone()));
+        let decision = filter.check("ADS.example.com.");
+        assert!(!decision.allowed);
+        assert_eq!(decision.rule, format!("feed:{}", sources[0]));
+        assert!(filter.is_allowed("www.tracker.example.net"));
+        assert!(!filter.is_allowed("api.tracker.example.net"));
+        assert!(filter.is_allowed("www.example.com"));
+
+        // A feed that stops loading keeps its last good domains
+        std::fs::remove_file(&path).unwrap();
+        feeds.refresh().await;
+        assert!(feeds.blocked("ads.example.com").is_some());
+        let status = feeds.status();
+        assert_eq!(status[0].domains, 1);
+        assert!(status[0].error.is_some());
+        assert!(status[1].error.is_none());
+        assert!(status[2].updated.is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
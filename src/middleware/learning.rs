@@ -0,0 +1,274 @@
+use axum::http::{header, HeaderMap};
+use chrono::{DateTime, Local};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tower_sessions::Session;
+use url::Url;
+
+use super::logging::proxied_url;
+
+/// Session key holding the host of the last page the user navigated to
+pub const SITE_KEY: &str = "learning_site";
+
+/// Sites kept in the queue; denials for further sites are dropped
+const MAX_SITES: usize = 500;
+/// Domains kept per site
+const MAX_DOMAINS_PER_SITE: usize = 200;
+/// Referring pages kept per domain, as examples
+const MAX_PAGES: usize = 5;
+
+/// A domain some site needed but the policy refused
+#[derive(Debug, Clone)]
+pub struct LearnedDomain {
+    pub domain: String,
+    pub count: u64,
+    /// Up to `MAX_PAGES` pages that requested it
+    pub pages: Vec<String>,
+    pub users: Vec<String>,
+    pub last_seen: DateTime<Local>,
+    /// Let through by an auto-allow window at least once
+    pub auto_allowed: bool,
+}
+
+/// Refused domains grouped under the top-level site that needed them
+#[derive(Debug, Clone)]
+pub struct LearnedSite {
+    pub site: String,
+    pub domains: Vec<LearnedDomain>,
+}
+
+/// Queue of domains refused by default-deny, waiting for an admin to approve
+/// or dismiss them. Kept in memory only.
+///
+/// An admin can also open a window on a site: for a few minutes that admin's
+/// own requests from the site are let through instead of refused, so the
+/// queue fills with everything the site needs in one visit.
+#[derive(Default)]
+pub struct LearningQueue {
+    sites: Mutex<BTreeMap<String, Vec<LearnedDomain>>>,
+    /// Open windows by (username, site)
+    windows: Mutex<HashMap<(String, String), Instant>>,
+}
+
+impl LearningQueue {
+    pub fn record(
+        &self,
+        site: &str,
+        domain: &str,
+        page: Option<&str>,
+        user: &str,
+        auto_allowed: bool,
+    ) {
+        let mut sites = self.sites.lock().unwrap();
+        if !sites.contains_key(site) && sites.len() >= MAX_SITES {
+            return;
+        }
+        let domains = sites.entry(site.to_string()).or_default();
+
+        let entry = match domains.iter_mut().position(|d| d.domain == domain) {
+            Some(index) => &mut domains[index],
+            None if domains.len() < MAX_DOMAINS_PER_SITE => {
+                domains.push(LearnedDomain {
+                    domain: domain.to_string(),
+                    count: 0,
+                    pages: Vec::new(),
+                    users: Vec::new(),
+                    last_seen: Local::now(),
+                    auto_allowed: false,
+                });
+                domains.last_mut().unwrap()
+            }
+            None => return,
+        };
+        entry.count += 1;
+        entry.last_seen = Local::now();
+        entry.auto_allowed |= auto_allowed;
+        if let Some(page) = page
+            && entry.pages.len() < MAX_PAGES
+            && !entry.pages.iter().any(|p| p == page)
+        {
+            entry.pages.push(page.to_string());
+        }
+        if !entry.users.iter().any(|u| u == user) {
+            entry.users.push(user.to_string());
+        }
+    }
+
+    /// Everything waiting for review, by site, most requested domains first
+    pub fn pending(&self) -> Vec<LearnedSite> {
+        self.sites
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(site, domains)| {
+                let mut domains = domains.clone();
+                domains.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.domain.cmp(&b.domain)));
+                LearnedSite {
+                    site: site.clone(),
+                    domains,
+                }
+            })
+            .collect()
+    }
+
+    /// Domains waiting for review under one site
+    pub fn domains(&self, site: &str) -> Vec<String> {
+        self.sites
+            .lock()
+            .unwrap()
+            .get(site)
+            .map(|domains| domains.iter().map(|d| d.domain.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Drop a domain from one site's queue without allowing it
+    pub fn dismiss(&self, site: &str, domain: &str) {
+        let mut sites = self.sites.lock().unwrap();
+        if let Some(domains) = sites.get_mut(site) {
+            domains.retain(|d| d.domain != domain);
+            if domains.is_empty() {
+                sites.remove(site);
+            }
+        }
+    }
+
+    /// Drop an allowed domain from every site's queue
+    pub fn approved(&self, domain: &str) {
+        let mut sites = self.sites.lock().unwrap();
+        for domains in sites.values_mut() {
+            domains.retain(|d| d.domain != domain);
+        }
+        sites.retain(|_, domains| !domains.is_empty());
+    }
+
+    pub fn open_window(&self, user: &str, site: &str, duration: Duration) {
+        self.windows.lock().unwrap().insert(
+            (user.to_string(), site.to_string()),
+            Instant::now() + duration,
+        );
+    }
+
+    /// Whether the user has a window open on the site right now
+    pub fn window_open(&self, user: &str, site: &str) -> bool {
+        let mut windows = self.windows.lock().unwrap();
+        windows.retain(|_, ends| *ends > Instant::now());
+        windows.contains_key(&(user.to_string(), site.to_string()))
+    }
+
+    /// The user's open windows with the time left on each
+    pub fn windows(&self, user: &str) -> Vec<(String, Duration)> {
+        let now = Instant::now();
+        let mut windows: Vec<(String, Duration)> = self
+            .windows
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((owner, _), ends)| owner == user && **ends > now)
+            .map(|((_, site), ends)| (site.clone(), *ends - now))
+            .collect();
+        windows.sort();
+        windows
+    }
+}
+
+/// Proxied page named by the request's Referer, if it came from one
+pub fn referring_page(headers: &HeaderMap) -> Option<String> {
+    let referer = Url::parse(headers.get(header::REFERER)?.to_str().ok()?).ok()?;
+    referer
+        .path()
+        .starts_with("/proxy/")
+        .then(|| proxied_url(referer.path()))
+}
+
+/// Whether the request loads a top-level page rather than a subresource
+pub fn is_navigation(headers: &HeaderMap) -> bool {
+    headers
+        .get("sec-fetch-dest")
+        .is_some_and(|dest| dest == "document")
+}
+
+/// Top-level site the user is on: the last page they navigated to, or failing
+/// that the host of the referring page
+pub async fn current_site(session: &Session, page: Option<&str>) -> Option<String> {
+    if let Ok(Some(site)) = session.get::<String>(SITE_KEY).await {
+        return Some(site);
+    }
+    Url::parse(page?).ok()?.host_str().map(str::to_string)
+}
+
+/// Note the site the user just navigated to
+pub async fn remember_site(session: &Session, site: &str) {
+    if current_site(session, None).await.as_deref() == Some(site) {
+        return;
+    }
+    if let Err(e) = session.insert(SITE_KEY, site).await {
+        tracing::error!("Failed to store current site in session: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_denials_grouped_by_site() {
+        let queue = LearningQueue::default();
+        let page = "https://news.example.com/story";
+        queue.record("news.example.com", "cdn.net", Some(page), "alice", false);
+        queue.record("news.example.com", "fonts.io", Some(page), "alice", false);
+        queue.record("news.example.com", "cdn.net", Some(page), "bob", false);
+        queue.record("shop.example.com", "cdn.net", None, "alice", false);
+
+        let pending = queue.pending();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].site, "news.example.com");
+        let cdn = &pending[0].domains[0];
+        assert_eq!(cdn.domain, "cdn.net");
+        assert_eq!(cdn.count, 2);
+        assert_eq!(cdn.pages, vec![page]);
+        assert_eq!(cdn.users, vec!["alice", "bob"]);
+
+        // Approving a domain clears it from every site
+        queue.approved("cdn.net");
+        let pending = queue.pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(queue.domains("news.example.com"), vec!["fonts.io"]);
+
+        queue.dismiss("news.example.com", "fonts.io");
+        assert!(queue.pending().is_empty());
+    }
+
+    #[test]
+    fn test_window_belongs_to_user_and_expires() {
+        let queue = LearningQueue::default();
+        queue.open_window("root", "news.example.com", Duration::from_secs(60));
+        queue.open_window("root", "old.example.com", Duration::ZERO);
+
+        assert!(queue.window_open("root", "news.example.com"));
+        assert!(!queue.window_open("alice", "news.example.com"));
+        assert!(!queue.window_open("root", "old.example.com"));
+        assert_eq!(queue.windows("root").len(), 1);
+    }
+
+    #[test]
+    fn test_referring_page_from_proxy_path() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::REFERER,
+            "http://proxy.local:3000/proxy/https/news.example.com/story?id=1"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            referring_page(&headers).as_deref(),
+            Some("https://news.example.com/story")
+        );
+
+        headers.insert(
+            header::REFERER,
+            "http://proxy.local:3000/home".parse().unwrap(),
+        );
+        assert_eq!(referring_page(&headers), None);
+    }
+}
@@ -1,29 +1,388 @@
-use axum::{extract::Request, middleware::Next, response::Response};
+use axum::{
+    body::HttpBody,
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use chrono::Local;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Instant;
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
 
-pub async fn logging_middleware(request: Request, next: Next) -> Response {
-    let method = request.method().clone();
-    let uri = request.uri().clone();
-    let start = Instant::now();
+use super::access_log::AccessLogEntry;
+use super::recent::{BlockedDomain, RecentRequest};
+use crate::audit;
+use crate::metrics::route_label;
+use crate::telemetry;
+use crate::AppState;
 
-    tracing::info!(
-        method = %method,
-        uri = %uri,
-        "Incoming request"
-    );
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Output format of the application log (`logging.format`)
+#[derive(Debug, PartialEq)]
+pub enum LogFormat {
+    /// One JSON object per line, with the request span's fields
+    Json,
+    /// Human-readable lines for a terminal
+    Pretty,
+}
 
-    let response = next.run(request).await;
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
 
-    let duration = start.elapsed();
-    let status = response.status();
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "json" => Ok(LogFormat::Json),
+            "pretty" => Ok(LogFormat::Pretty),
+            other => anyhow::bail!(
+                "Error: Unknown logging format '{}'. Use json or pretty",
+                other
+            ),
+        }
+    }
+}
+
+/// Log filter used when `RUST_LOG` is not set: the crate's events at
+/// `logging.level`, and audit events at info so none are lost
+pub fn default_filter(level: &str) -> EnvFilter {
+    EnvFilter::new(format!(
+        "{}={},{}=info",
+        env!("CARGO_PKG_NAME"),
+        level,
+        audit::TARGET
+    ))
+}
 
-    tracing::info!(
-        method = %method,
-        uri = %uri,
-        status = %status,
-        duration_ms = %duration.as_millis(),
-        "Request completed"
+/// Username of the session that made the request, attached to the response
+/// by `require_auth` so the access log can report it.
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser(pub String);
+
+/// Details about a proxied exchange, attached to the response by `proxy_handler`.
+#[derive(Clone, Debug)]
+pub struct ProxyOutcome {
+    /// Target URL decoded from `/proxy/...`
+    pub target_url: String,
+    pub upstream_host: String,
+    pub upstream_status: u16,
+    /// Name of the `ProxyHandler` that processed the upstream response
+    pub handler: &'static str,
+    /// Bytes received from the upstream server
+    pub bytes_in: u64,
+}
+
+pub async fn logging_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let request_id = request_id(&request);
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        otel.kind = "server",
     );
+    telemetry::set_parent_from_headers(&span, request.headers());
+
+    async move {
+        let log_requests = state.config.logging.log_requests;
+        let method = request.method().clone();
+        let uri = request.uri().clone();
+        let route = route_label(request.extensions().get::<MatchedPath>()).to_string();
+        let version = request.version();
+        let remote_addr = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ci| ci.0.ip().to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let referer = header_string(request.headers(), header::REFERER);
+        let user_agent = header_string(request.headers(), header::USER_AGENT);
+        let time = Local::now();
+        let start = Instant::now();
+
+        if log_requests {
+            tracing::info!(
+                method = %method,
+                uri = %uri,
+                "Incoming request"
+            );
+        }
+
+        let mut response = next.run(request).await;
+
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            response.headers_mut().insert(REQUEST_ID_HEADER, value);
+        }
+
+        let duration = start.elapsed();
+        let status = response.status();
+        let user = response
+            .extensions()
+            .get::<AuthenticatedUser>()
+            .map(|u| u.0.as_str());
+        let outcome = response.extensions().get::<ProxyOutcome>();
+        let bytes_out = response.body().size_hint().exact().unwrap_or(0);
+
+        state
+            .metrics
+            .requests
+            .with_label_values(&[
+                &route,
+                status.as_str(),
+                outcome.map(|o| o.handler).unwrap_or("-"),
+            ])
+            .inc();
+        if outcome.is_some() {
+            state
+                .metrics
+                .bytes_proxied
+                .with_label_values(&["out"])
+                .inc_by(bytes_out);
+        }
+
+        if let Some(access_log) = &state.access_log {
+            access_log.log(&AccessLogEntry {
+                remote_addr: &remote_addr,
+                user,
+                time,
+                method: method.as_str(),
+                uri: &uri.to_string(),
+                version: &format!("{:?}", version),
+                status: status.as_u16(),
+                bytes: bytes_out,
+                referer: referer.as_deref(),
+                user_agent: user_agent.as_deref(),
+                target_url: outcome.map(|o| o.target_url.as_str()),
+                upstream_status: outcome.map(|o| o.upstream_status),
+                duration_ms: duration.as_millis(),
+                request_id: &request_id,
+            });
+        }
+
+        if uri.path().starts_with("/proxy/") {
+            let blocked = response.extensions().get::<BlockedDomain>();
+            state.recent.record(
+                RecentRequest {
+                    time,
+                    user: user.unwrap_or("-").to_string(),
+                    remote_addr: remote_addr.clone(),
+                    target_url: outcome
+                        .map(|o| o.target_url.clone())
+                        .unwrap_or_else(|| proxied_url(uri.path())),
+                    status: status.as_u16(),
+                    blocked_by: blocked.map(|b| b.rule.clone()),
+                    duration_ms: duration.as_millis(),
+                },
+                blocked.map(|b| b.domain.as_str()),
+            );
+        }
+
+        if log_requests {
+            tracing::info!(
+                method = %method,
+                uri = %uri,
+                status = %status,
+                duration_ms = %duration.as_millis(),
+                user = %user.unwrap_or("-"),
+                upstream_host = %outcome.map(|o| o.upstream_host.as_str()).unwrap_or("-"),
+                handler = %outcome.map(|o| o.handler).unwrap_or("-"),
+                bytes_in = outcome.map(|o| o.bytes_in).unwrap_or(0),
+                bytes_out = bytes_out,
+                "Request completed"
+            );
+        }
+
+        response
+    }
+    .instrument(span)
+    .await
+}
+
+fn header_string(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Target URL of a `/proxy/<scheme>/<rest>` path
+pub(super) fn proxied_url(path: &str) -> String {
+    let rest = path.strip_prefix("/proxy/").unwrap_or(path);
+    match rest.split_once('/') {
+        Some((scheme, rest)) => format!("{}://{}", scheme, rest),
+        None => rest.to_string(),
+    }
+}
+
+/// Reuse the caller's request ID when it looks sane, otherwise mint a new one
+fn request_id(request: &Request) -> String {
+    request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= 128
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, response::IntoResponse, routing::get, Router};
+    use std::sync::Mutex;
+    use tower::Service;
+
+    /// Log lines written while the guard is held
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        fn lines(&self) -> Vec<serde_json::Value> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_default_filter_keeps_audit_events() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(default_filter("warn"))
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_writer(move || writer.clone()),
+            );
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("filtered out at warn");
+            crate::audit::AuditLog::new(&Default::default())
+                .unwrap()
+                .record("login_lockout", serde_json::json!({"key": "alice"}));
+        });
+
+        let lines = captured.lines();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["target"], "browser_proxy::audit");
+        assert_eq!(lines[0]["fields"]["event"], "login_lockout");
+    }
+
+    /// Run one proxied request through the middleware and return the JSON
+    /// log lines it produced
+    async fn logged(log_requests: bool) -> (Response, Vec<serde_json::Value>) {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let state = AppState::for_tests(|config| config.logging.log_requests = log_requests).await;
+        let mut app = Router::new()
+            .route(
+                "/proxy/https/example.com/",
+                get(|| async {
+                    let mut response = "proxied".into_response();
+                    response
+                        .extensions_mut()
+                        .insert(AuthenticatedUser("alice".to_string()));
+                    response.extensions_mut().insert(ProxyOutcome {
+                        target_url: "https://example.com/".to_string(),
+                        upstream_host: "example.com".to_string(),
+                        upstream_status: 200,
+                        handler: "html",
+                        bytes_in: 1234,
+                    });
+                    response
+                }),
+            )
+            .layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                logging_middleware,
+            ));
+
+        let request = Request::get("/proxy/https/example.com/")
+            .header(REQUEST_ID_HEADER, "req-42")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        (response, captured.lines())
+    }
+
+    #[tokio::test]
+    async fn test_completed_request_has_context() {
+        let (response, lines) = logged(true).await;
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "req-42");
+
+        let messages: Vec<&str> = lines
+            .iter()
+            .filter_map(|l| l["fields"]["message"].as_str())
+            .collect();
+        assert_eq!(messages, vec!["Incoming request", "Request completed"]);
+
+        let completed = &lines[1];
+        assert_eq!(completed["span"]["request_id"], "req-42");
+        let fields = &completed["fields"];
+        assert_eq!(fields["status"], "200 OK");
+        assert_eq!(fields["user"], "alice");
+        assert_eq!(fields["upstream_host"], "example.com");
+        assert_eq!(fields["handler"], "html");
+        assert_eq!(fields["bytes_in"], 1234);
+        assert_eq!(fields["bytes_out"], 7);
+    }
+
+    #[tokio::test]
+    async fn test_log_requests_off_skips_request_lines() {
+        let (response, lines) = logged(false).await;
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "req-42");
+        assert!(lines.is_empty(), "{:?}", lines);
+    }
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!("pretty".parse::<LogFormat>().unwrap(), LogFormat::Pretty);
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
 
-    response
+    #[test]
+    fn test_request_id_reused_only_when_sane() {
+        let with_id = |id: &str| {
+            Request::get("/")
+                .header(REQUEST_ID_HEADER, id)
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert_eq!(request_id(&with_id("abc_DEF-1")), "abc_DEF-1");
+        assert_ne!(request_id(&with_id("has space")), "has space");
+        assert_ne!(request_id(&with_id(&"x".repeat(129))), "x".repeat(129));
+    }
 }
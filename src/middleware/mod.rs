@@ -1,5 +1,19 @@
+pub mod access_log;
+pub mod csrf;
 pub mod domain_filter;
+pub mod feeds;
+pub mod learning;
 pub mod logging;
+pub mod policy;
+pub mod policy_store;
+pub mod public_suffix;
+pub mod recent;
 
+pub use access_log::AccessLog;
+pub use csrf::verify_csrf;
 pub use domain_filter::DomainFilter;
-pub use logging::logging_middleware;
+pub use learning::LearningQueue;
+pub use logging::{logging_middleware, AuthenticatedUser, ProxyOutcome};
+pub use policy::RolePolicies;
+pub use policy_store::PolicyStore;
+pub use recent::{BlockedDomain, RecentRequests};
@@ -0,0 +1,256 @@
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use url::Url;
+
+use super::domain_filter::{DomainFilter, FilterDecision, Target};
+use crate::config::DomainFilterConfig;
+
+/// Per-role domain filters, built from the `[roles.<name>]` config sections
+pub struct RolePolicies {
+    filters: BTreeMap<String, DomainFilter>,
+}
+
+impl RolePolicies {
+    pub fn new(roles: &BTreeMap<String, DomainFilterConfig>) -> Result<Self> {
+        let mut filters = BTreeMap::new();
+        for (name, config) in roles {
+            if !config.feeds.is_empty() {
+                bail!(
+                    "Blocklist feeds for role '{}' are not supported; list them in [domain_filter]",
+                    name
+                );
+            }
+            match DomainFilter::new(config) {
+                Ok(filter) => filters.insert(name.clone(), filter),
+                Err(e) => bail!("Invalid domain policy for role '{}': {}", name, e),
+            };
+        }
+        Ok(Self { filters })
+    }
+
+    /// Resolve the domain policy for a user holding the given roles
+    pub fn for_user(&self, global: Arc<DomainFilter>, roles: &[String]) -> UserPolicy<'_> {
+        UserPolicy {
+            global,
+            roles: roles
+                .iter()
+                .filter_map(|role| self.filters.get(role))
+                .collect(),
+            restriction: None,
+        }
+    }
+}
+
+/// The domain rules that apply to one user.
+///
+/// Users without a role that has a domain policy get the global filter.
+/// Otherwise a domain is allowed if any of the user's role filters allow it.
+/// The global blocklist applies to everyone. Requests made with an API token
+/// that is limited to some domains are further restricted to those.
+pub struct UserPolicy<'a> {
+    global: Arc<DomainFilter>,
+    roles: Vec<&'a DomainFilter>,
+    restriction: Option<&'a DomainFilter>,
+}
+
+impl<'a> UserPolicy<'a> {
+    /// Narrow the policy to domains the filter also allows
+    pub fn restricted_to(mut self, restriction: Option<&'a DomainFilter>) -> Self {
+        self.restriction = restriction;
+        self
+    }
+
+    /// Check a bare domain; see [`Target::host`]
+    pub fn check(&self, domain: &str) -> FilterDecision {
+        self.decide(&Target::host(domain))
+    }
+
+    pub fn check_url(&self, url: &Url) -> FilterDecision {
+        self.decide(&Target::url(url))
+    }
+
+    fn decide(&self, target: &Target) -> FilterDecision {
+        let decision = self.check_roles(target);
+        match self.restriction {
+            Some(restriction) if decision.allowed && !restriction.decide(target).allowed => {
+                FilterDecision {
+                    allowed: false,
+                    rule: "token-scope".to_string(),
+                }
+            }
+            _ => decision,
+        }
+    }
+
+    fn check_roles(&self, target: &Target) -> FilterDecision {
+        if self.roles.is_empty() {
+            return self.global.decide(target);
+        }
+
+        if let Some(rule) = self.global.blocked_by(target) {
+            tracing::warn!("Domain blocked by {}", rule);
+            return FilterDecision {
+                allowed: false,
+                rule,
+            };
+        }
+
+        let mut denied = None;
+        for filter in &self.roles {
+            let decision = filter.decide(target);
+            if decision.allowed {
+                return decision;
+            }
+            // Prefer reporting an explicit block over the default deny
+            if denied.is_none() || decision.rule != "default-deny" {
+                denied = Some(decision);
+            }
+        }
+        denied.expect("roles is not empty")
+    }
+
+    pub fn is_allowed(&self, domain: &str) -> bool {
+        self.check(domain).allowed
+    }
+
+    pub fn validate_start_url(&self, url: &Url) -> Result<()> {
+        let domain = url
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid URL: no host"))?;
+
+        if !self.check_url(url).allowed {
+            bail!(
+                "Domain '{}' is not in your allowlist. Ask an administrator for access.",
+                domain
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Allowlist patterns to show to the user
+    pub fn allowed_domains(&self) -> Vec<String> {
+        if let Some(restriction) = self.restriction {
+            return restriction.allowlist_patterns();
+        }
+        if self.roles.is_empty() {
+            return self.global.allowlist_patterns();
+        }
+
+        let mut domains: Vec<String> = Vec::new();
+        for filter in &self.roles {
+            for pattern in filter.allowlist_patterns() {
+                if !domains.contains(&pattern) {
+                    domains.push(pattern);
+                }
+            }
+        }
+        domains
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(allowlist: &[&str], blocklist: &[&str]) -> DomainFilterConfig {
+        DomainFilterConfig {
+            allowlist: allowlist.iter().map(|s| s.to_string()).collect(),
+            blocklist: blocklist.iter().map(|s| s.to_string()).collect(),
+            rules: Vec::new(),
+            feeds: Vec::new(),
+            feed_refresh_minutes: 360,
+        }
+    }
+
+    fn setup() -> (Arc<DomainFilter>, RolePolicies) {
+        let global = DomainFilter::new(&filter(
+            &["*.example.com", "vendor.com"],
+            &["ads.example.com"],
+        ))
+        .unwrap();
+        let mut roles = BTreeMap::new();
+        roles.insert("contractors".to_string(), filter(&["vendor.com"], &[]));
+        roles.insert(
+            "partners".to_string(),
+            filter(
+                &["*.partner.com", "ads.example.com"],
+                &["secret.partner.com"],
+            ),
+        );
+        (Arc::new(global), RolePolicies::new(&roles).unwrap())
+    }
+
+    #[test]
+    fn test_user_without_roles_gets_global_policy() {
+        let (global, roles) = setup();
+        let policy = roles.for_user(global, &[]);
+
+        assert!(policy.is_allowed("www.example.com"));
+        assert!(policy.is_allowed("vendor.com"));
+        assert_eq!(
+            policy.allowed_domains(),
+            vec!["*.example.com", "vendor.com"]
+        );
+    }
+
+    #[test]
+    fn test_contractor_limited_to_role_allowlist() {
+        let (global, roles) = setup();
+        let policy = roles.for_user(global, &["contractors".to_string()]);
+
+        assert!(policy.is_allowed("vendor.com"));
+        assert!(!policy.is_allowed("www.example.com"));
+        assert_eq!(policy.allowed_domains(), vec!["vendor.com"]);
+    }
+
+    #[test]
+    fn test_roles_without_policy_fall_back_to_global() {
+        let (global, roles) = setup();
+        let policy = roles.for_user(global, &["admin".to_string()]);
+
+        assert!(policy.is_allowed("www.example.com"));
+    }
+
+    #[test]
+    fn test_multiple_roles_are_combined() {
+        let (global, roles) = setup();
+        let policy = roles.for_user(global, &["contractors".to_string(), "partners".to_string()]);
+
+        assert!(policy.is_allowed("vendor.com"));
+        assert!(policy.is_allowed("portal.partner.com"));
+        assert!(!policy.is_allowed("secret.partner.com"));
+    }
+
+    #[test]
+    fn test_global_blocklist_applies_to_roles() {
+        let (global, roles) = setup();
+        let policy = roles.for_user(global, &["partners".to_string()]);
+
+        let decision = policy.check("ads.example.com");
+        assert!(!decision.allowed);
+        assert_eq!(decision.rule, "ads.example.com");
+    }
+
+    #[test]
+    fn test_token_restriction_narrows_policy() {
+        let (global, roles) = setup();
+        let token = DomainFilter::new(&filter(&["docs.example.com", "other.org"], &[])).unwrap();
+        let policy = roles.for_user(global, &[]).restricted_to(Some(&token));
+
+        assert!(policy.is_allowed("docs.example.com"));
+        assert!(!policy.is_allowed("other.org"));
+        let decision = policy.check("www.example.com");
+        assert!(!decision.allowed);
+        assert_eq!(decision.rule, "token-scope");
+    }
+
+    #[test]
+    fn test_empty_role_allowlist_rejected() {
+        let mut roles = BTreeMap::new();
+        roles.insert("broken".to_string(), filter(&[], &[]));
+
+        assert!(RolePolicies::new(&roles).is_err());
+    }
+}
@@ -0,0 +1,400 @@
+use anyhow::anyhow;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use super::domain_filter::{normalize_pattern, normalize_rule, DomainFilter};
+use super::feeds::BlocklistFeeds;
+use crate::config::DomainFilterConfig;
+
+/// One of the global rule lists
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleList {
+    Allowlist,
+    Blocklist,
+    /// The ordered `allow <pattern>` / `deny <pattern>` rules
+    Rules,
+}
+
+impl RuleList {
+    fn patterns<'a>(&self, rules: &'a mut DomainFilterConfig) -> &'a mut Vec<String> {
+        match self {
+            RuleList::Allowlist => &mut rules.allowlist,
+            RuleList::Blocklist => &mut rules.blocklist,
+            RuleList::Rules => &mut rules.rules,
+        }
+    }
+
+    /// Check an entry for this list and bring it into the form it is stored
+    /// in, so the same pattern is not added twice and can be removed however
+    /// it is written
+    pub fn normalize(&self, entry: &str) -> Result<String, RuleError> {
+        if entry.trim().is_empty() {
+            return Err(RuleError::Invalid("Pattern is required".to_string()));
+        }
+        let normalized = match self {
+            RuleList::Rules => normalize_rule(entry),
+            _ => normalize_pattern(entry),
+        };
+        normalized.map_err(|e| RuleError::Invalid(e.to_string()))
+    }
+}
+
+impl fmt::Display for RuleList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RuleList::Allowlist => "allowlist",
+            RuleList::Blocklist => "blocklist",
+            RuleList::Rules => "rules",
+        })
+    }
+}
+
+/// Why a rule change was refused
+#[derive(Debug)]
+pub enum RuleError {
+    /// The change itself is not acceptable; the message is for the caller
+    Invalid(String),
+    /// The change could not be saved
+    Storage(anyhow::Error),
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleError::Invalid(message) => f.write_str(message),
+            RuleError::Storage(e) => write!(f, "Failed to save domain rules: {:#}", e),
+        }
+    }
+}
+
+/// Contents of `admin.policy_file`
+#[derive(Deserialize, Serialize)]
+struct PolicyFile {
+    #[serde(flatten)]
+    rules: DomainFilterConfig,
+    updated_by: String,
+    updated_at: String,
+}
+
+/// The global allowlist and blocklist, changeable at runtime.
+///
+/// Requests take a snapshot of the current filter with [`filter`]; a change
+/// builds a new filter and swaps it in, so requests already running finish
+/// under the rules they started with. Changes are written to
+/// `admin.policy_file`, which replaces `[domain_filter]` on the next start,
+/// except for the blocklist feeds, which always come from the config.
+///
+/// [`filter`]: PolicyStore::filter
+pub struct PolicyStore {
+    current: RwLock<(DomainFilterConfig, Arc<DomainFilter>)>,
+    file: Option<PathBuf>,
+    feeds: Option<Arc<BlocklistFeeds>>,
+}
+
+impl PolicyStore {
+    pub fn new(config: &DomainFilterConfig, file: Option<&str>) -> anyhow::Result<Self> {
+        let file = file.map(PathBuf::from);
+        let mut rules = match &file {
+            Some(path) if path.is_file() => {
+                let content = std::fs::read_to_string(path)?;
+                let stored: PolicyFile = serde_json::from_str(&content).map_err(|e| {
+                    anyhow!("Failed to parse policy file {}: {}", path.display(), e)
+                })?;
+                tracing::info!(
+                    "Domain rules from {} (changed by {} at {}) replace [domain_filter]",
+                    path.display(),
+                    stored.updated_by,
+                    stored.updated_at
+                );
+                stored.rules
+            }
+            _ => config.clone(),
+        };
+        // Feeds cannot be changed at runtime, so config.toml stays in charge
+        rules.feeds = config.feeds.clone();
+        rules.feed_refresh_minutes = config.feed_refresh_minutes;
+        let feeds = match rules.feeds.is_empty() {
+            true => None,
+            false => Some(Arc::new(BlocklistFeeds::new(
+                &rules.feeds,
+                Duration::from_secs(rules.feed_refresh_minutes.max(1) * 60),
+            )?)),
+        };
+        let filter = Arc::new(DomainFilter::new(&rules)?.with_feeds(feeds.clone()));
+
+        Ok(Self {
+            current: RwLock::new((rules, filter)),
+            file,
+            feeds,
+        })
+    }
+
+    /// Blocklist feeds of the filter, to be loaded and refreshed by the caller
+    pub fn feeds(&self) -> Option<Arc<BlocklistFeeds>> {
+        self.feeds.clone()
+    }
+
+    /// The filter in force right now
+    pub fn filter(&self) -> Arc<DomainFilter> {
+        self.current.read().unwrap().1.clone()
+    }
+
+    /// The patterns in force right now
+    pub fn rules(&self) -> DomainFilterConfig {
+        self.current.read().unwrap().0.clone()
+    }
+
+    /// Add a pattern to a list, at the end of it for the ordered rules.
+    /// Returns false if it was already there.
+    pub fn add(&self, list: RuleList, pattern: &str, username: &str) -> Result<bool, RuleError> {
+        let pattern = list.normalize(pattern)?;
+        self.update(username, |rules| {
+            let patterns = list.patterns(rules);
+            if patterns.contains(&pattern) {
+                return false;
+            }
+            patterns.push(pattern);
+            true
+        })
+    }
+
+    /// Remove a pattern from a list. Returns false if it was not there.
+    pub fn remove(&self, list: RuleList, pattern: &str, username: &str) -> Result<bool, RuleError> {
+        let pattern = list.normalize(pattern)?;
+        self.update(username, |rules| {
+            let patterns = list.patterns(rules);
+            let before = patterns.len();
+            patterns.retain(|p| *p != pattern);
+            patterns.len() != before
+        })
+    }
+
+    /// Apply `change` to a copy of the rules and, if it changed anything,
+    /// save and swap them in
+    fn update(
+        &self,
+        username: &str,
+        change: impl FnOnce(&mut DomainFilterConfig) -> bool,
+    ) -> Result<bool, RuleError> {
+        let mut current = self.current.write().unwrap();
+        let mut rules = current.0.clone();
+        if !change(&mut rules) {
+            return Ok(false);
+        }
+        if rules.allowlist.is_empty() && !rules.rules.iter().any(|r| r.starts_with("allow ")) {
+            return Err(RuleError::Invalid(
+                "The allowlist cannot be empty unless a rule allows something".to_string(),
+            ));
+        }
+        let filter = DomainFilter::new(&rules)
+            .map_err(|e| RuleError::Invalid(e.to_string()))?
+            .with_feeds(self.feeds.clone());
+
+        self.persist(&rules, username).map_err(RuleError::Storage)?;
+        *current = (rules, Arc::new(filter));
+        Ok(true)
+    }
+
+    fn persist(&self, rules: &DomainFilterConfig, username: &str) -> anyhow::Result<()> {
+        let Some(path) = &self.file else {
+            tracing::warn!(
+                "No admin.policy_file configured; rule changes will not survive restart"
+            );
+            return Ok(());
+        };
+
+        let stored = PolicyFile {
+            rules: rules.clone(),
+            updated_by: username.to_string(),
+            updated_at: Utc::now().to_rfc3339(),
+        };
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(&stored)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use url::Url;
+
+    fn config() -> DomainFilterConfig {
+        DomainFilterConfig {
+            allowlist: vec!["example.com".to_string()],
+            blocklist: Vec::new(),
+            rules: Vec::new(),
+            feeds: Vec::new(),
+            feed_refresh_minutes: 360,
+        }
+    }
+
+    #[test]
+    fn test_changes_swap_filter() {
+        let store = PolicyStore::new(&config(), None).unwrap();
+        let before = store.filter();
+
+        assert!(store
+            .add(RuleList::Allowlist, "*.Example.org", "root")
+            .unwrap());
+        assert!(!store
+            .add(RuleList::Allowlist, "*.example.org", "root")
+            .unwrap());
+        assert!(store
+            .add(RuleList::Blocklist, "ads.example.org", "root")
+            .unwrap());
+
+        let after = store.filter();
+        assert!(after.is_allowed("www.example.org"));
+        assert!(!after.is_allowed("ads.example.org"));
+        // A snapshot taken earlier keeps the old rules
+        assert!(!before.is_allowed("www.example.org"));
+
+        assert!(store
+            .remove(RuleList::Blocklist, "ads.example.org", "root")
+            .unwrap());
+        assert!(!store
+            .remove(RuleList::Blocklist, "ads.example.org", "root")
+            .unwrap());
+        assert!(store.filter().is_allowed("ads.example.org"));
+    }
+
+    #[test]
+    fn test_patterns_removed_as_added() {
+        let store = PolicyStore::new(&config(), None).unwrap();
+
+        store
+            .add(RuleList::Blocklist, "Bücher.example.com", "root")
+            .unwrap();
+        store
+            .add(RuleList::Blocklist, r"re:^Ads\d+\.example\.com$", "root")
+            .unwrap();
+        assert_eq!(
+            store.rules().blocklist,
+            vec!["xn--bcher-kva.example.com", r"re:^Ads\d+\.example\.com$"]
+        );
+
+        assert!(store
+            .remove(RuleList::Blocklist, "BÜCHER.example.com", "root")
+            .unwrap());
+        assert!(!store
+            .remove(RuleList::Blocklist, r"re:^ads\d+\.example\.com$", "root")
+            .unwrap());
+        assert!(store
+            .remove(RuleList::Blocklist, r" re:^Ads\d+\.example\.com$ ", "root")
+            .unwrap());
+        assert!(store.rules().blocklist.is_empty());
+    }
+
+    #[test]
+    fn test_scheme_path_patterns_and_rules() {
+        let store = PolicyStore::new(&config(), None).unwrap();
+
+        assert!(store
+            .add(
+                RuleList::Allowlist,
+                "HTTPS://Docs.Example.org/Guide/*",
+                "root"
+            )
+            .unwrap());
+        assert!(store
+            .add(RuleList::Rules, "Deny example.com:8080", "root")
+            .unwrap());
+        assert!(store
+            .add(RuleList::Rules, "allow example.com", "root")
+            .unwrap());
+        assert_eq!(
+            store.rules().allowlist,
+            vec!["example.com", "https://docs.example.org/Guide/*"]
+        );
+        assert_eq!(
+            store.rules().rules,
+            vec!["deny example.com:8080", "allow example.com"]
+        );
+
+        let filter = store.filter();
+        assert!(filter.is_allowed("example.com"));
+        assert!(
+            !filter
+                .check_url(&Url::parse("http://example.com:8080/").unwrap())
+                .allowed
+        );
+        assert!(
+            filter
+                .check_url(&Url::parse("https://docs.example.org/Guide/intro").unwrap())
+                .allowed
+        );
+        assert!(
+            !filter
+                .check_url(&Url::parse("http://docs.example.org/Guide/intro").unwrap())
+                .allowed
+        );
+
+        // An allow rule stands in for an empty allowlist
+        store
+            .remove(
+                RuleList::Allowlist,
+                "https://docs.example.org/Guide/*",
+                "root",
+            )
+            .unwrap();
+        assert!(store
+            .remove(RuleList::Allowlist, "example.com", "root")
+            .unwrap());
+        assert!(matches!(
+            store.remove(RuleList::Rules, "ALLOW Example.com", "root"),
+            Err(RuleError::Invalid(_))
+        ));
+        assert!(matches!(
+            store.add(RuleList::Rules, "permit example.org", "root"),
+            Err(RuleError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_changes_rejected() {
+        let store = PolicyStore::new(&config(), None).unwrap();
+
+        for pattern in ["", "example.org:http", "https://", "a b", "*.co.uk", "re:("] {
+            assert!(matches!(
+                store.add(RuleList::Allowlist, pattern, "root"),
+                Err(RuleError::Invalid(_))
+            ));
+        }
+        assert!(matches!(
+            store.remove(RuleList::Allowlist, "example.com", "root"),
+            Err(RuleError::Invalid(_))
+        ));
+        assert_eq!(store.rules().allowlist, vec!["example.com"]);
+    }
+
+    #[test]
+    fn test_rules_survive_reload() {
+        let dir = std::env::temp_dir().join(format!("browser_proxy_policy_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("policy.json");
+        let path = path.to_str().unwrap();
+
+        let store = PolicyStore::new(&config(), Some(path)).unwrap();
+        store
+            .add(RuleList::Allowlist, "example.org", "root")
+            .unwrap();
+
+        let reloaded = PolicyStore::new(&config(), Some(path)).unwrap();
+        assert_eq!(
+            reloaded.rules().allowlist,
+            vec!["example.com", "example.org"]
+        );
+        let stored: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(stored["updated_by"], "root");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+/// Mozilla's Public Suffix List, ICANN and private sections, as shipped with
+/// this build. Update by replacing the file from
+/// https://publicsuffix.org/list/public_suffix_list.dat
+const LIST: &str = include_str!("../../data/public_suffix_list.dat");
+
+struct PublicSuffixes {
+    rules: HashSet<String>,
+    /// `*.<parent>` rules, by parent
+    wildcards: HashSet<String>,
+    /// `!<name>` exceptions to wildcards
+    exceptions: HashSet<String>,
+}
+
+fn suffixes() -> &'static PublicSuffixes {
+    static SUFFIXES: OnceLock<PublicSuffixes> = OnceLock::new();
+    SUFFIXES.get_or_init(|| {
+        let mut suffixes = PublicSuffixes {
+            rules: HashSet::new(),
+            wildcards: HashSet::new(),
+            exceptions: HashSet::new(),
+        };
+        for line in LIST.lines() {
+            let Some(rule) = line.split_whitespace().next() else {
+                continue;
+            };
+            if rule.starts_with("//") {
+                continue;
+            }
+            // Entries for internationalized suffixes are in Unicode
+            let (set, name) = if let Some(parent) = rule.strip_prefix("*.") {
+                (&mut suffixes.wildcards, parent)
+            } else if let Some(name) = rule.strip_prefix('!') {
+                (&mut suffixes.exceptions, name)
+            } else {
+                (&mut suffixes.rules, rule)
+            };
+            if let Ok(name) = idna::domain_to_ascii(name) {
+                set.insert(name);
+            }
+        }
+        suffixes
+    })
+}
+
+/// Whether names are registered directly under `domain` (`com`, `co.uk`,
+/// `github.io`), so one pattern covering all of its subdomains would span
+/// unrelated owners. Expects a normalized, punycode domain.
+pub fn is_public_suffix(domain: &str) -> bool {
+    let suffixes = suffixes();
+    if suffixes.exceptions.contains(domain) {
+        return false;
+    }
+    if suffixes.rules.contains(domain) {
+        return true;
+    }
+    match domain.split_once('.') {
+        Some((_, parent)) => suffixes.wildcards.contains(parent),
+        // Every top-level domain is a public suffix, listed or not
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_public_suffixes() {
+        for suffix in [
+            "com",
+            "uk",
+            "co.uk",
+            "github.io",
+            "xn--p1ai",
+            "anything.ck",
+            "internal",
+        ] {
+            assert!(is_public_suffix(suffix), "{}", suffix);
+        }
+        for domain in ["example.com", "example.co.uk", "ourorg.github.io", "www.ck"] {
+            assert!(!is_public_suffix(domain), "{}", domain);
+        }
+    }
+}
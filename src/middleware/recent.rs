@@ -0,0 +1,138 @@
+use chrono::{DateTime, Local};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Proxied requests kept for the admin console's live tail
+const CAPACITY: usize = 200;
+/// Distinct blocked domains counted; later ones are ignored so a flood of
+/// random subdomains cannot grow the table without bound
+const MAX_BLOCKED_DOMAINS: usize = 10_000;
+
+/// A proxied request as shown in the live tail
+#[derive(Debug, Clone)]
+pub struct RecentRequest {
+    pub time: DateTime<Local>,
+    pub user: String,
+    pub remote_addr: String,
+    pub target_url: String,
+    pub status: u16,
+    /// Rule that refused the target, if the domain policy blocked it
+    pub blocked_by: Option<String>,
+    pub duration_ms: u128,
+}
+
+/// Set on the response by `proxy_handler` when the user's domain policy
+/// refused the target
+#[derive(Debug, Clone)]
+pub struct BlockedDomain {
+    pub domain: String,
+    pub rule: String,
+}
+
+/// In-memory record of recent proxied requests and blocked domains since
+/// startup
+#[derive(Default)]
+pub struct RecentRequests {
+    entries: Mutex<VecDeque<RecentRequest>>,
+    blocked: Mutex<HashMap<String, u64>>,
+}
+
+impl RecentRequests {
+    pub fn record(&self, request: RecentRequest, blocked_domain: Option<&str>) {
+        if let Some(domain) = blocked_domain {
+            let mut blocked = self.blocked.lock().unwrap();
+            if let Some(count) = blocked.get_mut(domain) {
+                *count += 1;
+            } else if blocked.len() < MAX_BLOCKED_DOMAINS {
+                blocked.insert(domain.to_string(), 1);
+            }
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == CAPACITY {
+            entries.pop_back();
+        }
+        entries.push_front(request);
+    }
+
+    /// Up to `limit` requests, newest first
+    pub fn latest(&self, limit: usize) -> Vec<RecentRequest> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// The `limit` most often blocked domains with their counts
+    pub fn top_blocked(&self, limit: usize) -> Vec<(String, u64)> {
+        let mut blocked: Vec<(String, u64)> = self
+            .blocked
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(domain, count)| (domain.clone(), *count))
+            .collect();
+        blocked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        blocked.truncate(limit);
+        blocked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(target_url: &str, blocked_by: Option<&str>) -> RecentRequest {
+        RecentRequest {
+            time: Local::now(),
+            user: "alice".to_string(),
+            remote_addr: "10.0.0.1".to_string(),
+            target_url: target_url.to_string(),
+            status: if blocked_by.is_some() { 403 } else { 200 },
+            blocked_by: blocked_by.map(str::to_string),
+            duration_ms: 5,
+        }
+    }
+
+    #[test]
+    fn test_latest_newest_first_and_bounded() {
+        let recent = RecentRequests::default();
+        for i in 0..CAPACITY + 10 {
+            recent.record(request(&format!("https://example.com/{}", i), None), None);
+        }
+
+        let latest = recent.latest(3);
+        assert_eq!(latest.len(), 3);
+        assert_eq!(
+            latest[0].target_url,
+            format!("https://example.com/{}", CAPACITY + 9)
+        );
+        assert_eq!(recent.latest(usize::MAX).len(), CAPACITY);
+    }
+
+    #[test]
+    fn test_top_blocked_counts() {
+        let recent = RecentRequests::default();
+        for domain in [
+            "ads.com",
+            "tracker.io",
+            "ads.com",
+            "ads.com",
+            "tracker.io",
+            "cdn.net",
+        ] {
+            recent.record(
+                request(&format!("https://{}/", domain), Some("default-deny")),
+                Some(domain),
+            );
+        }
+
+        assert_eq!(
+            recent.top_blocked(2),
+            vec![("ads.com".to_string(), 3), ("tracker.io".to_string(), 2)]
+        );
+    }
+}
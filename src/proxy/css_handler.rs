@@ -10,6 +10,10 @@ pub struct CssProxyHandler;
 
 #[async_trait]
 impl ProxyHandler for CssProxyHandler {
+    fn name(&self) -> &'static str {
+        "css"
+    }
+
     async fn handle(
         &self,
         response: Response,
@@ -69,6 +73,7 @@ impl CssProxyHandler {
         }
 
         // Sort by position descending to maintain correct indices during replacement
+        #[allow(clippy::unnecessary_sort_by)]
         replacements.sort_by(|a, b| b.0.cmp(&a.0));
 
         let num_replacements = replacements.len();
@@ -9,6 +9,10 @@ pub struct DefaultProxyHandler;
 
 #[async_trait]
 impl ProxyHandler for DefaultProxyHandler {
+    fn name(&self) -> &'static str {
+        "default"
+    }
+
     async fn handle(
         &self,
         response: Response,
@@ -5,6 +5,9 @@ use url::Url;
 
 #[async_trait]
 pub trait ProxyHandler: Send + Sync {
+    /// Short name used in logs to identify which handler processed a response
+    fn name(&self) -> &'static str;
+
     async fn handle(
         &self,
         response: Response,
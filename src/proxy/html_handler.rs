@@ -10,6 +10,10 @@ pub struct HtmlProxyHandler;
 
 #[async_trait]
 impl ProxyHandler for HtmlProxyHandler {
+    fn name(&self) -> &'static str {
+        "html"
+    }
+
     async fn handle(
         &self,
         response: Response,
@@ -97,6 +101,7 @@ impl HtmlProxyHandler {
         }
 
         // Sort replacements by position (descending) to maintain positions
+        #[allow(clippy::unnecessary_sort_by)]
         replacements.sort_by(|a, b| b.0.cmp(&a.0));
 
         let num_replacements = replacements.len();
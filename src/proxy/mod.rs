@@ -3,6 +3,7 @@ pub mod default_handler;
 pub mod factory;
 pub mod handler;
 pub mod html_handler;
+pub mod placeholder;
 
 pub use css_handler::CssProxyHandler;
 pub use default_handler::DefaultProxyHandler;
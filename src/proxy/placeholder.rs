@@ -0,0 +1,80 @@
+use axum::http::HeaderMap;
+
+/// GIF89a, 1x1, one fully transparent pixel
+const TRANSPARENT_GIF: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+/// What a refused request gets instead of the resource it asked for
+#[derive(Debug, PartialEq)]
+pub enum Placeholder {
+    /// A page or frame: an explanation the user can act on
+    Page,
+    /// A body that loads cleanly as the expected type
+    Body {
+        content_type: &'static str,
+        body: &'static [u8],
+    },
+    /// Nothing, for fetches, fonts, media and anything else
+    NoContent,
+}
+
+/// Pick a placeholder from the request's `Sec-Fetch-Dest` header. Clients
+/// that do not send it, such as scripts using API tokens, get `None` and
+/// should be answered with a plain 403.
+pub fn for_request(headers: &HeaderMap) -> Option<Placeholder> {
+    let dest = headers.get("sec-fetch-dest")?.to_str().ok()?;
+    Some(match dest {
+        "document" | "iframe" | "frame" => Placeholder::Page,
+        "image" => Placeholder::Body {
+            content_type: "image/gif",
+            body: TRANSPARENT_GIF,
+        },
+        "style" => Placeholder::Body {
+            content_type: "text/css",
+            body: b"",
+        },
+        "script" => Placeholder::Body {
+            content_type: "text/javascript",
+            body: b"",
+        },
+        _ => Placeholder::NoContent,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placeholder(dest: Option<&str>) -> Option<Placeholder> {
+        let mut headers = HeaderMap::new();
+        if let Some(dest) = dest {
+            headers.insert("sec-fetch-dest", dest.parse().unwrap());
+        }
+        for_request(&headers)
+    }
+
+    #[test]
+    fn test_placeholder_by_destination() {
+        assert_eq!(placeholder(Some("document")), Some(Placeholder::Page));
+        assert_eq!(placeholder(Some("iframe")), Some(Placeholder::Page));
+        assert_eq!(
+            placeholder(Some("style")),
+            Some(Placeholder::Body {
+                content_type: "text/css",
+                body: b"",
+            })
+        );
+        assert_eq!(placeholder(Some("font")), Some(Placeholder::NoContent));
+        assert_eq!(placeholder(Some("empty")), Some(Placeholder::NoContent));
+        assert_eq!(placeholder(None), None);
+
+        let Some(Placeholder::Body { content_type, body }) = placeholder(Some("image")) else {
+            panic!("expected an image");
+        };
+        assert_eq!(content_type, "image/gif");
+        assert!(body.starts_with(b"GIF89a") && body.ends_with(b";"));
+    }
+}
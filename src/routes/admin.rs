@@ -0,0 +1,137 @@
+use axum::{
+    extract::{Extension, Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use url::Url;
+
+use crate::auth::CurrentUser;
+use crate::middleware::policy_store::{RuleError, RuleList};
+use crate::AppState;
+
+#[derive(Deserialize)]
+pub struct RuleRequest {
+    pattern: String,
+}
+
+#[derive(Deserialize)]
+pub struct TestQuery {
+    url: String,
+}
+
+fn error(status: StatusCode, message: impl Into<String>) -> Response {
+    (status, Json(json!({ "error": message.into() }))).into_response()
+}
+
+/// Only users holding `admin.role` get past this. Runs after `require_auth`;
+/// API callers get a JSON error, console pages plain text.
+pub async fn require_admin(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    if !state.is_admin(&user) {
+        tracing::warn!(
+            "User {} without role {} denied {}",
+            user.username,
+            state.config.admin.role,
+            request.uri().path()
+        );
+        if !request.uri().path().starts_with("/api/") {
+            return (StatusCode::FORBIDDEN, "Admin role required").into_response();
+        }
+        return error(StatusCode::FORBIDDEN, "Admin role required");
+    }
+    next.run(request).await
+}
+
+pub async fn list_rules(State(state): State<Arc<AppState>>) -> Response {
+    Json(state.domain_filter.rules()).into_response()
+}
+
+fn rule_error(e: RuleError) -> Response {
+    match e {
+        RuleError::Invalid(message) => error(StatusCode::BAD_REQUEST, message),
+        RuleError::Storage(_) => {
+            tracing::error!("{}", e);
+            error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        }
+    }
+}
+
+pub async fn add_rule(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+    Path(list): Path<RuleList>,
+    Json(rule): Json<RuleRequest>,
+) -> Response {
+    let pattern = match list.normalize(&rule.pattern) {
+        Ok(pattern) => pattern,
+        Err(e) => return rule_error(e),
+    };
+    match state.domain_filter.add(list, &pattern, &user.username) {
+        Ok(true) => {
+            tracing::info!("User {} added {} to the {}", user.username, pattern, list);
+            state.audit.record(
+                "domain_rule_added",
+                json!({ "username": user.username, "list": list, "pattern": pattern }),
+            );
+            (StatusCode::CREATED, Json(state.domain_filter.rules())).into_response()
+        }
+        Ok(false) => Json(state.domain_filter.rules()).into_response(),
+        Err(e) => rule_error(e),
+    }
+}
+
+pub async fn remove_rule(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+    Path((list, pattern)): Path<(RuleList, String)>,
+) -> Response {
+    match state.domain_filter.remove(list, &pattern, &user.username) {
+        Ok(true) => {
+            tracing::info!(
+                "User {} removed {} from the {}",
+                user.username,
+                pattern,
+                list
+            );
+            state.audit.record(
+                "domain_rule_removed",
+                json!({ "username": user.username, "list": list, "pattern": pattern }),
+            );
+            Json(state.domain_filter.rules()).into_response()
+        }
+        Ok(false) => error(
+            StatusCode::NOT_FOUND,
+            format!("'{}' is not in the {}", pattern, list),
+        ),
+        Err(e) => rule_error(e),
+    }
+}
+
+/// Check a URL, or a bare host name, against the global rules
+pub async fn test_rule(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<TestQuery>,
+) -> Response {
+    let url = Url::parse(&query.url).or_else(|_| Url::parse(&format!("https://{}", query.url)));
+    let Some(url) = url.ok().filter(|u| u.host_str().is_some()) else {
+        return error(StatusCode::BAD_REQUEST, "Not a URL or host name");
+    };
+    let domain = url.host_str().unwrap_or_default();
+
+    let decision = state.domain_filter.filter().check_url(&url);
+    Json(json!({
+        "url": url.as_str(),
+        "domain": domain,
+        "allowed": decision.allowed,
+        "rule": decision.rule,
+    }))
+    .into_response()
+}
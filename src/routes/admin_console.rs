@@ -0,0 +1,698 @@
+use askama::Template;
+use axum::{
+    extract::{Extension, Form, Query, State},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use chrono::DateTime;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tower_sessions::Session;
+use url::Url;
+
+use crate::auth::password::hash_password;
+use crate::auth::{CurrentUser, User};
+use crate::middleware::csrf::csrf_token;
+use crate::middleware::policy_store::{RuleError, RuleList};
+use crate::sessions::session_handle;
+use crate::AppState;
+
+/// Requests shown in the live tail
+const TAIL_LENGTH: usize = 100;
+/// Blocked domains shown next to it
+const TOP_BLOCKED: usize = 20;
+
+fn format_time(unix: Option<i64>) -> String {
+    unix.and_then(|t| DateTime::from_timestamp(t, 0))
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// A session as listed on the sessions page
+struct SessionRow {
+    handle: String,
+    username: String,
+    client_ip: String,
+    logged_in: String,
+    last_seen: String,
+    current: bool,
+}
+
+#[derive(Template)]
+#[template(path = "admin_sessions.html")]
+struct SessionsTemplate {
+    sessions: Vec<SessionRow>,
+    error: String,
+    csrf_token: String,
+}
+
+/// A proxied request as listed in the live tail
+struct RequestRow {
+    time: String,
+    user: String,
+    remote_addr: String,
+    target_url: String,
+    status: u16,
+    blocked_by: String,
+    duration_ms: u128,
+}
+
+#[derive(Template)]
+#[template(path = "admin_requests.html")]
+struct RequestsTemplate {
+    requests: Vec<RequestRow>,
+    top_blocked: Vec<(String, u64)>,
+    csrf_token: String,
+}
+
+/// Result of checking a URL on the rules page
+struct RuleTest {
+    url: String,
+    domain: String,
+    allowed: bool,
+    rule: String,
+}
+
+#[derive(Template)]
+#[template(path = "admin_rules.html")]
+struct RulesTemplate {
+    /// Ordered `allow`/`deny` rules
+    ordered: Vec<String>,
+    allowlist: Vec<String>,
+    blocklist: Vec<String>,
+    feeds: Vec<FeedRow>,
+    role_count: usize,
+    test: Option<RuleTest>,
+    error: String,
+    csrf_token: String,
+}
+
+/// A blocklist feed as listed on the rules page
+struct FeedRow {
+    source: String,
+    domains: usize,
+    updated: String,
+    error: String,
+}
+
+/// A queued domain as listed on the learning page
+struct LearnedRow {
+    domain: String,
+    count: u64,
+    pages: Vec<String>,
+    users: String,
+    last_seen: String,
+    auto_allowed: bool,
+}
+
+/// Queued domains under the site that needed them
+struct LearnedSiteRow {
+    site: String,
+    domains: Vec<LearnedRow>,
+}
+
+#[derive(Template)]
+#[template(path = "admin_learning.html")]
+struct LearningTemplate {
+    enabled: bool,
+    window_minutes: u64,
+    sites: Vec<LearnedSiteRow>,
+    /// This admin's open windows with minutes left
+    windows: Vec<(String, u64)>,
+    error: String,
+    csrf_token: String,
+}
+
+/// A local account as listed on the users page
+struct UserRow {
+    username: String,
+    /// Defined in config.toml, so not editable here
+    configured: bool,
+    roles: String,
+    enabled: bool,
+    require_2fa: bool,
+    sessions: usize,
+}
+
+#[derive(Template)]
+#[template(path = "admin_users.html")]
+struct UsersTemplate {
+    users: Vec<UserRow>,
+    error: String,
+    csrf_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeSessionForm {
+    handle: String,
+}
+
+#[derive(Deserialize)]
+pub struct RuleForm {
+    list: RuleList,
+    pattern: String,
+}
+
+#[derive(Deserialize)]
+pub struct RuleTestQuery {
+    test: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LearnedForm {
+    site: String,
+    /// Absent to approve everything queued under the site
+    domain: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct WindowForm {
+    site: String,
+}
+
+#[derive(Deserialize)]
+pub struct SaveUserForm {
+    username: String,
+    #[serde(default)]
+    password: String,
+    #[serde(default)]
+    roles: String,
+    /// Checkbox; present when ticked
+    require_2fa: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UserEnabledForm {
+    username: String,
+    enabled: bool,
+}
+
+pub async fn admin_sessions_page(session: Session, State(state): State<Arc<AppState>>) -> Response {
+    render_sessions(&session, &state, String::new()).await
+}
+
+async fn render_sessions(session: &Session, state: &AppState, mut error: String) -> Response {
+    let current = session.id().map(|id| session_handle(&id));
+    let sessions = match state.sessions.logged_in().await {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            tracing::error!("Failed to list sessions: {}", e);
+            error = "Failed to list sessions".to_string();
+            Vec::new()
+        }
+    };
+    let template = SessionsTemplate {
+        sessions: sessions
+            .into_iter()
+            .map(|s| SessionRow {
+                current: current.as_deref() == Some(s.handle.as_str()),
+                handle: s.handle,
+                username: s.username,
+                client_ip: s.client_ip.unwrap_or_else(|| "-".to_string()),
+                logged_in: format_time(s.logged_in_at),
+                last_seen: format_time(s.last_seen),
+            })
+            .collect(),
+        error,
+        csrf_token: csrf_token(session).await,
+    };
+    Html(template.render().unwrap()).into_response()
+}
+
+pub async fn admin_revoke_session_handler(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+    Form(form): Form<RevokeSessionForm>,
+) -> Response {
+    match state.sessions.revoke_handle(&form.handle).await {
+        Ok(Some(owner)) => {
+            tracing::info!("User {} revoked a session of {}", user.username, owner);
+            state.audit.record(
+                "session_revoked",
+                json!({ "username": user.username, "session_user": owner, "session": form.handle }),
+            );
+            Redirect::to("/admin/sessions").into_response()
+        }
+        Ok(None) => Redirect::to("/admin/sessions").into_response(),
+        Err(e) => {
+            tracing::error!("Failed to revoke session: {}", e);
+            render_sessions(&session, &state, "Failed to revoke session".to_string()).await
+        }
+    }
+}
+
+pub async fn admin_requests_page(session: Session, State(state): State<Arc<AppState>>) -> Response {
+    let template = RequestsTemplate {
+        requests: state
+            .recent
+            .latest(TAIL_LENGTH)
+            .into_iter()
+            .map(|r| RequestRow {
+                time: r.time.format("%H:%M:%S").to_string(),
+                user: r.user,
+                remote_addr: r.remote_addr,
+                target_url: r.target_url,
+                status: r.status,
+                blocked_by: r.blocked_by.unwrap_or_default(),
+                duration_ms: r.duration_ms,
+            })
+            .collect(),
+        top_blocked: state.recent.top_blocked(TOP_BLOCKED),
+        csrf_token: csrf_token(&session).await,
+    };
+    Html(template.render().unwrap()).into_response()
+}
+
+pub async fn admin_rules_page(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<RuleTestQuery>,
+) -> Response {
+    let test = query.test.filter(|url| !url.trim().is_empty()).map(|url| {
+        let parsed = Url::parse(url.trim())
+            .or_else(|_| Url::parse(&format!("https://{}", url.trim())))
+            .ok()
+            .filter(|u| u.host_str().is_some());
+        let filter = state.domain_filter.filter();
+        // An empty domain shows as "not a URL" whatever the decision
+        let (domain, decision) = match parsed {
+            Some(u) => (
+                u.host_str().unwrap_or_default().to_string(),
+                filter.check_url(&u),
+            ),
+            None => (String::new(), filter.check("")),
+        };
+        RuleTest {
+            url,
+            domain,
+            allowed: decision.allowed,
+            rule: decision.rule,
+        }
+    });
+    render_rules(&session, &state, test, String::new()).await
+}
+
+async fn render_rules(
+    session: &Session,
+    state: &AppState,
+    test: Option<RuleTest>,
+    error: String,
+) -> Response {
+    let rules = state.domain_filter.rules();
+    let feeds = state
+        .domain_filter
+        .feeds()
+        .map(|feeds| feeds.status())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|f| FeedRow {
+            source: f.source,
+            domains: f.domains,
+            updated: f
+                .updated
+                .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_else(|| "never".to_string()),
+            error: f.error.unwrap_or_default(),
+        })
+        .collect();
+    let template = RulesTemplate {
+        ordered: rules.rules,
+        allowlist: rules.allowlist,
+        blocklist: rules.blocklist,
+        feeds,
+        role_count: state.config.roles.len(),
+        test,
+        error,
+        csrf_token: csrf_token(session).await,
+    };
+    Html(template.render().unwrap()).into_response()
+}
+
+pub async fn admin_add_rule_handler(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+    Form(form): Form<RuleForm>,
+) -> Response {
+    let pattern = match form.list.normalize(&form.pattern) {
+        Ok(pattern) => pattern,
+        Err(e) => return rule_error(&session, &state, e).await,
+    };
+    match state.domain_filter.add(form.list, &pattern, &user.username) {
+        Ok(added) => {
+            if added {
+                tracing::info!(
+                    "User {} added {} to the {}",
+                    user.username,
+                    pattern,
+                    form.list
+                );
+                state.audit.record(
+                    "domain_rule_added",
+                    json!({ "username": user.username, "list": form.list, "pattern": pattern }),
+                );
+            }
+            Redirect::to("/admin/rules").into_response()
+        }
+        Err(e) => rule_error(&session, &state, e).await,
+    }
+}
+
+pub async fn admin_remove_rule_handler(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+    Form(form): Form<RuleForm>,
+) -> Response {
+    match state
+        .domain_filter
+        .remove(form.list, &form.pattern, &user.username)
+    {
+        Ok(removed) => {
+            if removed {
+                tracing::info!(
+                    "User {} removed {} from the {}",
+                    user.username,
+                    form.pattern,
+                    form.list
+                );
+                state.audit.record(
+                    "domain_rule_removed",
+                    json!({ "username": user.username, "list": form.list, "pattern": form.pattern }),
+                );
+            }
+            Redirect::to("/admin/rules").into_response()
+        }
+        Err(e) => rule_error(&session, &state, e).await,
+    }
+}
+
+async fn rule_error(session: &Session, state: &AppState, e: RuleError) -> Response {
+    if let RuleError::Storage(_) = e {
+        tracing::error!("{}", e);
+    }
+    render_rules(session, state, None, e.to_string()).await
+}
+
+pub async fn admin_learning_page(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+) -> Response {
+    render_learning(&session, &state, &user, String::new()).await
+}
+
+async fn render_learning(
+    session: &Session,
+    state: &AppState,
+    user: &CurrentUser,
+    error: String,
+) -> Response {
+    let template = LearningTemplate {
+        enabled: state.config.learning.enabled,
+        window_minutes: state.config.learning.auto_allow_minutes,
+        sites: state
+            .learning
+            .pending()
+            .into_iter()
+            .map(|s| LearnedSiteRow {
+                site: s.site,
+                domains: s
+                    .domains
+                    .into_iter()
+                    .map(|d| LearnedRow {
+                        domain: d.domain,
+                        count: d.count,
+                        pages: d.pages,
+                        users: d.users.join(", "),
+                        last_seen: d.last_seen.format("%Y-%m-%d %H:%M:%S").to_string(),
+                        auto_allowed: d.auto_allowed,
+                    })
+                    .collect(),
+            })
+            .collect(),
+        windows: state
+            .learning
+            .windows(&user.username)
+            .into_iter()
+            .map(|(site, left)| (site, left.as_secs().div_ceil(60)))
+            .collect(),
+        error,
+        csrf_token: csrf_token(session).await,
+    };
+    Html(template.render().unwrap()).into_response()
+}
+
+/// Add one queued domain, or all of a site's, to the allowlist
+pub async fn admin_learning_approve_handler(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+    Form(form): Form<LearnedForm>,
+) -> Response {
+    let domains = match form.domain {
+        Some(domain) => vec![domain],
+        None => state.learning.domains(&form.site),
+    };
+    for domain in domains {
+        match state
+            .domain_filter
+            .add(RuleList::Allowlist, &domain, &user.username)
+        {
+            Ok(added) => {
+                if added {
+                    tracing::info!(
+                        "User {} approved {} for {}",
+                        user.username,
+                        domain,
+                        form.site
+                    );
+                    state.audit.record(
+                        "domain_rule_added",
+                        json!({
+                            "username": user.username,
+                            "list": RuleList::Allowlist,
+                            "pattern": domain,
+                            "site": form.site,
+                        }),
+                    );
+                }
+                state.learning.approved(&domain);
+            }
+            Err(e) => {
+                if let RuleError::Storage(_) = e {
+                    tracing::error!("{}", e);
+                }
+                return render_learning(&session, &state, &user, e.to_string()).await;
+            }
+        }
+    }
+    Redirect::to("/admin/learning").into_response()
+}
+
+pub async fn admin_learning_dismiss_handler(
+    State(state): State<Arc<AppState>>,
+    Form(form): Form<LearnedForm>,
+) -> Response {
+    if let Some(domain) = form.domain {
+        state.learning.dismiss(&form.site, &domain);
+    }
+    Redirect::to("/admin/learning").into_response()
+}
+
+/// Let the admin's own requests from a site through for a while, queueing
+/// what it needs
+pub async fn admin_learning_window_handler(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+    Form(form): Form<WindowForm>,
+) -> Response {
+    let site = form.site.trim().to_ascii_lowercase();
+    if site.is_empty() {
+        let error = "Enter the site to open a window on".to_string();
+        return render_learning(&session, &state, &user, error).await;
+    }
+    let minutes = state.config.learning.auto_allow_minutes;
+    state
+        .learning
+        .open_window(&user.username, &site, Duration::from_secs(minutes * 60));
+
+    tracing::info!(
+        "User {} opened a {} minute auto-allow window on {}",
+        user.username,
+        minutes,
+        site
+    );
+    state.audit.record(
+        "learning_window_opened",
+        json!({ "username": user.username, "site": site, "minutes": minutes }),
+    );
+    Redirect::to("/admin/learning").into_response()
+}
+
+pub async fn admin_users_page(session: Session, State(state): State<Arc<AppState>>) -> Response {
+    render_users(&session, &state, String::new()).await
+}
+
+async fn render_users(session: &Session, state: &AppState, error: String) -> Response {
+    let sessions = state.sessions.logged_in().await.unwrap_or_default();
+    let template = UsersTemplate {
+        users: state
+            .users
+            .list()
+            .into_iter()
+            .map(|u| UserRow {
+                sessions: sessions.iter().filter(|s| s.username == u.username).count(),
+                configured: state.users.is_configured(&u.username),
+                username: u.username,
+                roles: u.roles.join(", "),
+                enabled: u.enabled,
+                require_2fa: u.require_2fa,
+            })
+            .collect(),
+        error,
+        csrf_token: csrf_token(session).await,
+    };
+    Html(template.render().unwrap()).into_response()
+}
+
+/// Create a local user, or update one. An empty password keeps the
+/// existing one.
+pub async fn admin_save_user_handler(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+    Form(form): Form<SaveUserForm>,
+) -> Response {
+    let username = form.username.trim().to_string();
+    if username.is_empty() || username.chars().any(char::is_whitespace) {
+        return render_users(
+            &session,
+            &state,
+            "Usernames must not be empty or contain spaces".to_string(),
+        )
+        .await;
+    }
+    let existing = state.users.get(&username);
+    let password_changed = !form.password.is_empty();
+    let password_hash = match (&existing, password_changed) {
+        (Some(existing), false) => existing.password_hash.clone(),
+        (None, false) => {
+            let error = "A password is required for new users".to_string();
+            return render_users(&session, &state, error).await;
+        }
+        (_, true) => {
+            // Argon2 is CPU heavy, keep it off the async workers
+            let password = form.password;
+            match tokio::task::spawn_blocking(move || hash_password(&password)).await {
+                Ok(Ok(hash)) => hash,
+                _ => {
+                    let error = "Failed to hash password".to_string();
+                    return render_users(&session, &state, error).await;
+                }
+            }
+        }
+    };
+    let roles: Vec<String> = form
+        .roles
+        .split([',', ' '])
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+        .map(str::to_string)
+        .collect();
+
+    let saved = User {
+        username: username.clone(),
+        password_hash,
+        enabled: existing.as_ref().is_none_or(|u| u.enabled),
+        roles: roles.clone(),
+        require_2fa: form.require_2fa.is_some(),
+    };
+    if let Err(e) = state.users.upsert(saved) {
+        tracing::error!("Failed to save user {}: {:#}", username, e);
+        return render_users(&session, &state, format!("Failed to save user: {}", e)).await;
+    }
+
+    // Sessions carry the roles they were created with, so end them rather
+    // than leave the old roles in force. A password reset ends them too, as
+    // it often follows a compromise.
+    let mut revoked = 0;
+    if existing
+        .as_ref()
+        .is_some_and(|u| u.roles != roles || password_changed)
+    {
+        match state.sessions.revoke_user(&username).await {
+            Ok(count) => revoked = count,
+            Err(e) => tracing::error!("Failed to end sessions of {}: {}", username, e),
+        }
+    }
+
+    tracing::info!("User {} saved user {}", user.username, username);
+    state.audit.record(
+        if existing.is_some() {
+            "user_updated"
+        } else {
+            "user_created"
+        },
+        json!({
+            "username": user.username,
+            "target_user": username,
+            "roles": roles,
+            "password_changed": password_changed,
+            "require_2fa": form.require_2fa.is_some(),
+            "sessions_revoked": revoked,
+        }),
+    );
+    Redirect::to("/admin/users").into_response()
+}
+
+/// Enable or disable a local user. Disabling also ends their sessions.
+pub async fn admin_user_enabled_handler(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+    Form(form): Form<UserEnabledForm>,
+) -> Response {
+    if !form.enabled && form.username == user.username {
+        let error = "You cannot disable your own account".to_string();
+        return render_users(&session, &state, error).await;
+    }
+    let Some(mut target) = state.users.get(&form.username) else {
+        return render_users(&session, &state, "No such user".to_string()).await;
+    };
+    target.enabled = form.enabled;
+    if let Err(e) = state.users.upsert(target) {
+        tracing::error!("Failed to save user {}: {:#}", form.username, e);
+        return render_users(&session, &state, format!("Failed to save user: {}", e)).await;
+    }
+
+    let mut revoked = 0;
+    if !form.enabled {
+        match state.sessions.revoke_user(&form.username).await {
+            Ok(count) => revoked = count,
+            Err(e) => tracing::error!("Failed to end sessions of {}: {}", form.username, e),
+        }
+    }
+    tracing::info!(
+        "User {} {} user {}",
+        user.username,
+        if form.enabled { "enabled" } else { "disabled" },
+        form.username
+    );
+    state.audit.record(
+        if form.enabled {
+            "user_enabled"
+        } else {
+            "user_disabled"
+        },
+        json!({
+            "username": user.username,
+            "target_user": form.username,
+            "sessions_revoked": revoked,
+        }),
+    );
+    Redirect::to("/admin/users").into_response()
+}
@@ -1,36 +1,82 @@
 use askama::Template;
 use axum::{
-    extract::{Form, State},
-    http::StatusCode,
-    response::{Html, IntoResponse, Redirect},
+    extract::{ConnectInfo, Extension, Form, State},
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
 };
 use serde::Deserialize;
+use serde_json::json;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use time::OffsetDateTime;
 use tower_sessions::Session;
 use url::Url;
 
+use super::two_factor::{begin_second_factor, PENDING_2FA_KEY};
+use crate::auth::{
+    authenticator::{Identity, IdentitySource},
+    throttle::Lockout,
+    tokens::TokenError,
+    ClientCert, Credentials, CurrentUser,
+};
+use crate::middleware::csrf::{csrf_token, CSRF_KEY};
+use crate::middleware::{learning, AuthenticatedUser};
+pub(super) use crate::sessions::USER_ID_KEY;
+use crate::sessions::{CLIENT_IP_KEY, LAST_SEEN_KEY, LOGGED_IN_AT_KEY};
 use crate::AppState;
 
-const USER_ID_KEY: &str = "user_id";
+const ROLES_KEY: &str = "roles";
+const SOURCE_KEY: &str = "identity_source";
+/// Only rewrite the session when `LAST_SEEN_KEY` is at least this stale, so
+/// most requests do not cost a store write
+const LAST_SEEN_RESOLUTION_SECS: i64 = 60;
 
 #[derive(Template)]
 #[template(path = "login.html")]
-struct LoginTemplate {
-    error: String,
+pub(super) struct LoginTemplate {
+    pub(super) error: String,
+    /// Show the username/password form
+    pub(super) password_login: bool,
+    /// Show the single sign-on button
+    pub(super) sso_login: bool,
+    pub(super) csrf_token: String,
+}
+
+impl LoginTemplate {
+    pub(super) fn new(state: &AppState, error: &str, csrf_token: String) -> Self {
+        Self {
+            error: error.to_string(),
+            password_login: state.authenticators.accepts_passwords(),
+            sso_login: state.oidc.is_some(),
+            csrf_token,
+        }
+    }
 }
 
 #[derive(Template)]
 #[template(path = "home.html")]
 struct HomeTemplate {
     allowed_domains: Vec<String>,
+    is_admin: bool,
+    csrf_token: String,
 }
 
 #[derive(Template)]
 #[template(path = "error.html")]
-struct ErrorTemplate {
-    error_message: String,
-    blocked_domain: String,
-    allowed_domains: Vec<String>,
+pub(super) struct ErrorTemplate {
+    pub(super) error_message: String,
+    pub(super) blocked_domain: String,
+    pub(super) allowed_domains: Vec<String>,
+    /// Offer to ask an admin for `blocked_url`
+    pub(super) request_access: bool,
+    pub(super) blocked_url: String,
+    pub(super) csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "access_requested.html")]
+struct AccessRequestedTemplate {
+    domain: String,
 }
 
 #[derive(Deserialize)]
@@ -44,46 +90,216 @@ pub struct BrowseForm {
     url: String,
 }
 
-pub async fn login_page() -> impl IntoResponse {
-    Html(
-        LoginTemplate {
-            error: String::new(),
-        }
-        .render()
-        .unwrap(),
+#[derive(Deserialize)]
+pub struct RequestAccessForm {
+    url: String,
+}
+
+pub async fn login_page(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    cert: Option<Extension<ClientCert>>,
+) -> Response {
+    // A mapped client certificate skips the form; an unmapped one falls
+    // through to it rather than looping back through `require_auth`
+    if let Some(Extension(cert)) = cert
+        && client_cert_login(&session, &state, &cert).await.is_some()
+    {
+        return Redirect::to("/home").into_response();
+    }
+    let template = LoginTemplate::new(&state, "", csrf_token(&session).await);
+    Html(template.render().unwrap()).into_response()
+}
+
+/// Start a session for the user a verified client certificate maps to.
+/// The certificate stands in for both factors, so no TOTP step follows.
+async fn client_cert_login(
+    session: &Session,
+    state: &AppState,
+    cert: &ClientCert,
+) -> Option<String> {
+    let identity = state
+        .authenticators
+        .authenticate(&Credentials::ClientCert(cert))
+        .await?;
+    if let Err(e) = establish_session(
+        session,
+        &identity.username,
+        &identity.roles,
+        identity.source,
     )
+    .await
+    {
+        tracing::error!("Failed to create session: {}", e);
+        return None;
+    }
+    tracing::info!(
+        "User {} logged in with client certificate {:?}",
+        identity.username,
+        cert.subject_cn
+    );
+    Some(identity.username)
+}
+
+/// Start an authenticated session for a user, under a fresh session ID to
+/// prevent session fixation
+pub(super) async fn establish_session(
+    session: &Session,
+    username: &str,
+    roles: &[String],
+    source: IdentitySource,
+) -> Result<(), tower_sessions::session::Error> {
+    session.cycle_id().await?;
+    session.remove_value(PENDING_2FA_KEY).await?;
+    // A token issued before login may have been planted; pages rendered from
+    // here on get a fresh one
+    session.remove_value(CSRF_KEY).await?;
+    session.insert(USER_ID_KEY, username).await?;
+    session.insert(ROLES_KEY, roles).await?;
+    session.insert(SOURCE_KEY, source).await?;
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    session.insert(LOGGED_IN_AT_KEY, now).await?;
+    session.insert(LAST_SEEN_KEY, now).await
+}
+
+/// Whether a user who passed the first factor still owes a TOTP code
+fn needs_second_factor(state: &AppState, identity: &Identity) -> bool {
+    // External identities never share a name with a local user (see
+    // `Authenticators`), so the name-keyed lookups below are theirs
+    state.config.auth.require_2fa
+        || state.totp.is_enrolled(&identity.username)
+        || state
+            .users
+            .get(&identity.username)
+            .is_some_and(|u| u.require_2fa)
+}
+
+/// Log, count and audit lockouts triggered by a failed login
+pub(super) fn report_lockouts(state: &AppState, ip: IpAddr, lockouts: Vec<Lockout>) {
+    for lockout in lockouts {
+        tracing::warn!(
+            "Locked out {} {} after {} failed logins",
+            lockout.scope,
+            lockout.key,
+            lockout.failures
+        );
+        state
+            .metrics
+            .login_lockouts
+            .with_label_values(&[lockout.scope])
+            .inc();
+        state.audit.record(
+            "login_lockout",
+            json!({
+                "scope": lockout.scope,
+                "key": lockout.key,
+                "failures": lockout.failures,
+                "until": lockout.until,
+                "ip": ip.to_string(),
+            }),
+        );
+    }
+}
+
+/// Finish a successful first-factor login: either start the session or,
+/// when the user needs a second factor, hand over to the TOTP step
+pub(super) async fn complete_login(
+    session: &Session,
+    state: &AppState,
+    identity: Identity,
+) -> Response {
+    if needs_second_factor(state, &identity) {
+        return begin_second_factor(session, state, identity).await;
+    }
+
+    if let Err(e) = establish_session(
+        session,
+        &identity.username,
+        &identity.roles,
+        identity.source,
+    )
+    .await
+    {
+        tracing::error!("Failed to create session: {}", e);
+        return Html(
+            LoginTemplate::new(state, "Failed to create session", csrf_token(session).await)
+                .render()
+                .unwrap(),
+        )
+        .into_response();
+    }
+
+    tracing::info!("User {} logged in", identity.username);
+    Redirect::to("/home").into_response()
 }
 
 pub async fn login_handler(
     session: Session,
     State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Form(credentials): Form<LoginForm>,
 ) -> impl IntoResponse {
-    // Validate credentials
-    if credentials.username == state.config.auth.username
-        && credentials.password == state.config.auth.password
-    {
-        // Store user in session
-        if let Err(e) = session.insert(USER_ID_KEY, &credentials.username).await {
-            tracing::error!("Failed to create session: {}", e);
-            return Html(
-                LoginTemplate {
-                    error: "Failed to create session".to_string(),
-                }
-                .render()
-                .unwrap(),
-            )
+    let ip = addr.ip();
+    if let Some(retry_after) = state.throttle.retry_after(ip, &credentials.username) {
+        tracing::warn!(
+            "Throttled login attempt for user {} from {}",
+            credentials.username,
+            ip
+        );
+        state.metrics.login_throttled.inc();
+        let error = format!(
+            "Too many failed attempts. Try again in {} seconds",
+            retry_after
+        );
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+            Html(
+                LoginTemplate::new(&state, &error, csrf_token(&session).await)
+                    .render()
+                    .unwrap(),
+            ),
+        )
             .into_response();
-        }
+    }
 
-        tracing::info!("User {} logged in", credentials.username);
-        Redirect::to("/home").into_response()
+    // Validate credentials against local users, then any configured directory
+    let verified = state
+        .authenticators
+        .authenticate(&Credentials::Password {
+            username: &credentials.username,
+            password: &credentials.password,
+        })
+        .await;
+
+    if let Some(identity) = verified {
+        // A user who still owes a second factor keeps their failures until
+        // the code is accepted
+        if !needs_second_factor(&state, &identity) {
+            state
+                .throttle
+                .record_success(ip, &credentials.username)
+                .await;
+        }
+        complete_login(&session, &state, identity).await
     } else {
-        tracing::warn!("Failed login attempt for user: {}", credentials.username);
+        tracing::warn!(
+            "Failed login attempt for user {} from {}",
+            credentials.username,
+            ip
+        );
+        state.metrics.login_failures.inc();
+        let lockouts = state
+            .throttle
+            .record_failure(ip, &credentials.username)
+            .await;
+        report_lockouts(&state, ip, lockouts);
         Html(
-            LoginTemplate {
-                error: "Invalid username or password".to_string(),
-            }
+            LoginTemplate::new(
+                &state,
+                "Invalid username or password",
+                csrf_token(&session).await,
+            )
             .render()
             .unwrap(),
         )
@@ -91,10 +307,57 @@ pub async fn login_handler(
     }
 }
 
-pub async fn home_page(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+/// Response that sends the browser back to the login page and clears what
+/// proxied pages may have stored under this origin (cookies set by scripts,
+/// local storage). The proxy itself keeps no upstream cookies.
+fn logged_out() -> Response {
+    (
+        [("clear-site-data", "\"cookies\", \"storage\"")],
+        Redirect::to("/login"),
+    )
+        .into_response()
+}
+
+pub async fn logout_handler(session: Session) -> Response {
+    if let Ok(Some(username)) = session.get::<String>(USER_ID_KEY).await {
+        tracing::info!("User {} logged out", username);
+    }
+    if let Err(e) = session.flush().await {
+        tracing::error!("Failed to delete session: {}", e);
+    }
+    logged_out()
+}
+
+/// Revoke every session of the current user, on all devices
+pub async fn logout_everywhere_handler(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+) -> Response {
+    if let Err(e) = session.flush().await {
+        tracing::error!("Failed to delete session: {}", e);
+    }
+    match state.sessions.revoke_user(&user.username).await {
+        Ok(count) => tracing::info!(
+            "User {} logged out everywhere ({} other session(s) revoked)",
+            user.username,
+            count
+        ),
+        Err(e) => tracing::error!("Failed to revoke sessions for {}: {}", user.username, e),
+    }
+    logged_out()
+}
+
+pub async fn home_page(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+) -> impl IntoResponse {
     Html(
         HomeTemplate {
-            allowed_domains: state.config.domain_filter.allowlist.clone(),
+            allowed_domains: state.policy(&user).allowed_domains(),
+            is_admin: state.is_admin(&user),
+            csrf_token: csrf_token(&session).await,
         }
         .render()
         .unwrap(),
@@ -102,9 +365,13 @@ pub async fn home_page(State(state): State<Arc<AppState>>) -> impl IntoResponse
 }
 
 pub async fn browse_handler(
+    session: Session,
     State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
     Form(form): Form<BrowseForm>,
 ) -> impl IntoResponse {
+    let policy = state.policy(&user);
+
     // Parse URL
     let url = match Url::parse(&form.url) {
         Ok(u) => u,
@@ -114,7 +381,10 @@ pub async fn browse_handler(
                 ErrorTemplate {
                     error_message: format!("Invalid URL: {}", e),
                     blocked_domain: String::new(),
-                    allowed_domains: state.config.domain_filter.allowlist.clone(),
+                    allowed_domains: policy.allowed_domains(),
+                    request_access: false,
+                    blocked_url: String::new(),
+                    csrf_token: csrf_token(&session).await,
                 }
                 .render()
                 .unwrap(),
@@ -124,13 +394,16 @@ pub async fn browse_handler(
     };
 
     // Validate against allowlist
-    if let Err(e) = state.domain_filter.validate_start_url(&url) {
+    if let Err(e) = policy.validate_start_url(&url) {
         tracing::warn!("URL not in allowlist: {}", form.url);
         return Html(
             ErrorTemplate {
                 error_message: e.to_string(),
                 blocked_domain: url.host_str().unwrap_or("").to_string(),
-                allowed_domains: state.config.domain_filter.allowlist.clone(),
+                allowed_domains: policy.allowed_domains(),
+                request_access: state.can_request_access(&policy.check_url(&url)),
+                blocked_url: url.to_string(),
+                csrf_token: csrf_token(&session).await,
             }
             .render()
             .unwrap(),
@@ -138,6 +411,12 @@ pub async fn browse_handler(
         .into_response();
     }
 
+    if state.config.learning.enabled
+        && let Some(site) = url.host_str()
+    {
+        learning::remember_site(&session, site).await;
+    }
+
     // Redirect to proxy
     let scheme = url.scheme();
     let path = format!("{}{}", url.host_str().unwrap_or(""), url.path());
@@ -150,19 +429,220 @@ pub async fn browse_handler(
     Redirect::to(&format!("/proxy/{}/{}{}", scheme, path, query)).into_response()
 }
 
+/// Queue a refused domain for the admins, under its own site in the learning
+/// queue
+pub async fn request_access_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+    Form(form): Form<RequestAccessForm>,
+) -> Response {
+    let Some((url, domain)) = Url::parse(&form.url)
+        .ok()
+        .and_then(|url| Some((url.clone(), url.host_str()?.to_string())))
+    else {
+        return (StatusCode::BAD_REQUEST, "Invalid URL").into_response();
+    };
+    // Only domains this user is really refused, so the queue holds nothing
+    // an approval would not change
+    if !state.can_request_access(&state.policy(&user).check_url(&url)) {
+        return Redirect::to("/home").into_response();
+    }
+
+    state
+        .learning
+        .record(&domain, &domain, Some(url.as_str()), &user.username, false);
+    tracing::info!("User {} requested access to {}", user.username, domain);
+    state.audit.record(
+        "access_requested",
+        json!({ "username": user.username, "domain": domain, "url": url.as_str() }),
+    );
+
+    Html(AccessRequestedTemplate { domain }.render().unwrap()).into_response()
+}
+
+/// Whether a session has outlived the idle or absolute timeout. The store
+/// also expires idle sessions, but not every store prunes on load.
+fn session_expired(state: &AppState, logged_in_at: i64, last_seen: i64, now: i64) -> bool {
+    let session = &state.config.session;
+    now - logged_in_at >= session.absolute_timeout_secs as i64
+        || now - last_seen >= session.idle_timeout_secs as i64
+}
+
+/// `Authorization: Bearer` value of a request, if it has one
+fn bearer_token(request: &axum::extract::Request) -> Option<String> {
+    let value = request
+        .headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim().to_string())
+}
+
+/// Authenticate a request by personal API token. Tokens only reach proxied
+/// pages and the `/api/` endpoints, and the session cookie is not consulted
+/// for such requests.
+async fn token_auth(
+    state: &AppState,
+    token: &str,
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let unauthorized = || {
+        (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "Invalid or expired API token",
+        )
+            .into_response()
+    };
+
+    // Tokens also let a scraper read metrics served on the main listener
+    let path = request.uri().path();
+    let metrics = state.config.metrics.enabled
+        && state.config.metrics.admin_port.is_none()
+        && path == state.config.metrics.path;
+    if !path.starts_with("/proxy/") && !path.starts_with("/api/") && !metrics {
+        return (
+            StatusCode::FORBIDDEN,
+            "API tokens can only be used for /proxy/, /api/ and metrics requests",
+        )
+            .into_response();
+    }
+    let token = match state.tokens.authenticate(token) {
+        Ok(token) => token,
+        Err(TokenError::Invalid) => {
+            tracing::warn!("Rejected invalid or expired API token");
+            return unauthorized();
+        }
+        Err(TokenError::RateLimited(retry_after)) => {
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                "API token rate limit exceeded",
+            )
+                .into_response();
+        }
+    };
+
+    // Local users may have been disabled or given other roles since the
+    // token was created. An LDAP or SSO user's name means nothing to the
+    // local store, so their tokens keep the roles they were issued with.
+    let local = token
+        .source
+        .is_local_account()
+        .then(|| state.users.get(&token.username))
+        .flatten();
+    let roles = match local {
+        Some(user) if !user.enabled => {
+            tracing::warn!("Rejected API token of disabled user {}", token.username);
+            return unauthorized();
+        }
+        Some(user) => user.roles,
+        None => token.roles.clone(),
+    };
+    request.extensions_mut().insert(CurrentUser {
+        username: token.username.clone(),
+        roles,
+        source: token.source,
+        token_domains: token.domain_filter(),
+    });
+
+    let mut response = next.run(request).await;
+    response
+        .extensions_mut()
+        .insert(AuthenticatedUser(token.username));
+    response
+}
+
 // Auth middleware
 pub async fn require_auth(
+    State(state): State<Arc<AppState>>,
     session: Session,
-    request: axum::extract::Request,
+    mut request: axum::extract::Request,
     next: axum::middleware::Next,
 ) -> Result<axum::response::Response, StatusCode> {
-    let user_id: Option<String> = session
+    if let Some(token) = bearer_token(&request) {
+        return Ok(token_auth(&state, &token, request, next).await);
+    }
+
+    let mut user_id: Option<String> = session
         .get(USER_ID_KEY)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if user_id.is_none()
+        && let Some(cert) = request.extensions().get::<ClientCert>().cloned()
+    {
+        user_id = client_cert_login(&session, &state, &cert).await;
+    }
+
+    if let Some(user_id) = user_id {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let logged_in_at: i64 = session
+            .get(LOGGED_IN_AT_KEY)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .unwrap_or(0);
+        let last_seen: i64 = session
+            .get(LAST_SEEN_KEY)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .unwrap_or(0);
+
+        if session_expired(&state, logged_in_at, last_seen, now) {
+            tracing::info!("Session for user {} expired", user_id);
+            session
+                .flush()
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            return Ok(Redirect::to("/login").into_response());
+        }
+
+        // Touching the session saves it, which also slides the store's
+        // inactivity expiry forward
+        if now - last_seen >= LAST_SEEN_RESOLUTION_SECS {
+            session
+                .insert(LAST_SEEN_KEY, now)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        }
+        // Shown on the admin sessions page
+        if let Some(ConnectInfo(addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+            let ip = addr.ip().to_string();
+            let known: Option<String> = session
+                .get(CLIENT_IP_KEY)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            if known.as_deref() != Some(ip.as_str()) {
+                session
+                    .insert(CLIENT_IP_KEY, ip)
+                    .await
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            }
+        }
+
+        let roles: Vec<String> = session
+            .get(ROLES_KEY)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .unwrap_or_default();
+        let source: IdentitySource = session
+            .get(SOURCE_KEY)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .unwrap_or_default();
+        request.extensions_mut().insert(CurrentUser {
+            username: user_id.clone(),
+            roles,
+            source,
+            token_domains: None,
+        });
 
-    if user_id.is_some() {
-        Ok(next.run(request).await)
+        let mut response = next.run(request).await;
+        response.extensions_mut().insert(AuthenticatedUser(user_id));
+        Ok(response)
     } else {
         Ok(Redirect::to("/login").into_response())
     }
@@ -0,0 +1,231 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tower_sessions::{session::Id, SessionStore};
+
+use crate::config::HealthConfig;
+use crate::AppState;
+
+const CANARY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Last canary outcome, reused until it is older than the configured TTL
+pub struct CanaryCache {
+    ttl: Duration,
+    last: Mutex<Option<(Instant, bool)>>,
+}
+
+impl CanaryCache {
+    pub fn new(config: &HealthConfig) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.canary_cache_secs),
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Whether `url` answered without a server error, fetching it only when
+    /// the cached outcome has expired. Probes arriving during a fetch wait
+    /// for it rather than starting their own.
+    async fn check(&self, client: &reqwest::Client, url: &str) -> bool {
+        let mut last = self.last.lock().await;
+        if let Some((at, ok)) = *last
+            && at.elapsed() < self.ttl
+        {
+            return ok;
+        }
+
+        let ok = match client.get(url).timeout(CANARY_TIMEOUT).send().await {
+            Ok(r) if r.status().is_server_error() => {
+                tracing::warn!("Readiness canary returned {}", r.status());
+                false
+            }
+            Ok(_) => true,
+            Err(e) => {
+                tracing::warn!("Readiness canary failed: {}", e);
+                false
+            }
+        };
+        *last = Some((Instant::now(), ok));
+        ok
+    }
+}
+
+#[derive(Serialize)]
+struct ReadinessReport {
+    ready: bool,
+    checks: Vec<Check>,
+}
+
+/// Outcome of one check. Failure details go to the log, not the response,
+/// since probes are unauthenticated.
+#[derive(Serialize)]
+struct Check {
+    name: &'static str,
+    ok: bool,
+}
+
+#[derive(Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    git_commit: &'static str,
+    features: Vec<&'static str>,
+}
+
+/// Liveness probe: the process is up and serving requests
+pub async fn healthz() -> impl IntoResponse {
+    "ok"
+}
+
+/// Readiness probe: configuration, session store and (optionally) an
+/// upstream canary fetch all succeed
+pub async fn readyz(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let mut checks = vec![Check {
+        name: "accepting",
+        ok: !state.shutting_down.load(Ordering::SeqCst),
+    }];
+
+    checks.push(Check {
+        name: "config",
+        ok: !state.config.domain_filter.allowlist.is_empty(),
+    });
+
+    let session_store = state.sessions.load(&Id::default()).await;
+    if let Err(e) = &session_store {
+        tracing::warn!("Readiness session store check failed: {}", e);
+    }
+    checks.push(Check {
+        name: "session_store",
+        ok: session_store.is_ok(),
+    });
+
+    if let Some(canary_url) = &state.config.health.canary_url {
+        checks.push(Check {
+            name: "canary",
+            ok: state.canary.check(&state.client, canary_url).await,
+        });
+    }
+
+    let ready = checks.iter().all(|c| c.ok);
+    if !ready {
+        let failed: Vec<_> = checks.iter().filter(|c| !c.ok).map(|c| c.name).collect();
+        tracing::warn!("Readiness check failed: {}", failed.join(", "));
+    }
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(ReadinessReport { ready, checks }))
+}
+
+/// Build information: crate version, git commit and enabled cargo features
+pub async fn version() -> impl IntoResponse {
+    let mut features = Vec::new();
+    if cfg!(feature = "otel") {
+        features.push("otel");
+    }
+
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: env!("GIT_COMMIT"),
+        features,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+    use axum::response::Response;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn body_json(response: Response) -> serde_json::Value {
+        let bytes = to_bytes(response.into_body(), 64 * 1024).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    /// An upstream that answers every request with `status` and counts them
+    async fn upstream(status: u16) -> (String, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let reply = format!(
+                    "HTTP/1.1 {status} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                );
+                let _ = stream.write_all(reply.as_bytes()).await;
+            }
+        });
+        (url, hits)
+    }
+
+    #[tokio::test]
+    async fn test_healthz_and_version() {
+        let response = healthz().await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let info = body_json(version().await.into_response()).await;
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert!(info["git_commit"].is_string());
+        assert!(info["features"].is_array());
+    }
+
+    #[tokio::test]
+    async fn test_readyz_reports_failures_without_details() {
+        let state = AppState::for_tests(|_| {}).await;
+        let response = readyz(State(state.clone())).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_json(response).await["ready"], true);
+
+        state.shutting_down.store(true, Ordering::SeqCst);
+        let response = readyz(State(state)).await.into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let report = body_json(response).await;
+        assert_eq!(report["ready"], false);
+        assert_eq!(report["checks"][0]["name"], "accepting");
+        assert_eq!(report["checks"][0]["ok"], false);
+        assert!(report["checks"][0].get("error").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_canary_result_cached() {
+        let (url, hits) = upstream(503).await;
+        let state = AppState::for_tests(|config| config.health.canary_url = Some(url)).await;
+
+        for _ in 0..3 {
+            let response = readyz(State(state.clone())).await.into_response();
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+            let report = body_json(response).await;
+            let canary = &report["checks"][3];
+            assert_eq!(canary["name"], "canary");
+            assert_eq!(canary["ok"], false);
+            assert!(canary.get("error").is_none());
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_canary_refetched_after_ttl() {
+        let (url, hits) = upstream(200).await;
+        let state = AppState::for_tests(|config| {
+            config.health.canary_url = Some(url);
+            config.health.canary_cache_secs = 0;
+        })
+        .await;
+
+        for _ in 0..2 {
+            let response = readyz(State(state.clone())).await.into_response();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+}
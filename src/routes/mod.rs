@@ -1,5 +1,27 @@
+pub mod admin;
+pub mod admin_console;
 pub mod app;
+pub mod health;
+pub mod metrics;
+pub mod oidc;
 pub mod proxy;
+pub mod tokens;
+pub mod two_factor;
 
-pub use app::{browse_handler, home_page, login_handler, login_page, require_auth};
+pub use admin::{add_rule, list_rules, remove_rule, require_admin, test_rule};
+pub use admin_console::{
+    admin_add_rule_handler, admin_learning_approve_handler, admin_learning_dismiss_handler,
+    admin_learning_page, admin_learning_window_handler, admin_remove_rule_handler,
+    admin_requests_page, admin_revoke_session_handler, admin_rules_page, admin_save_user_handler,
+    admin_sessions_page, admin_user_enabled_handler, admin_users_page,
+};
+pub use app::{
+    browse_handler, home_page, login_handler, login_page, logout_everywhere_handler,
+    logout_handler, request_access_handler, require_auth,
+};
+pub use health::{healthz, readyz, version, CanaryCache};
+pub use metrics::metrics_handler;
+pub use oidc::{oidc_callback, oidc_login};
 pub use proxy::proxy_handler;
+pub use tokens::{create_token_handler, revoke_token_handler, tokens_page};
+pub use two_factor::{enroll_handler, enroll_page, second_factor_handler, second_factor_page};
@@ -0,0 +1,102 @@
+use askama::Template;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tower_sessions::Session;
+
+use super::app::{complete_login, LoginTemplate};
+use crate::auth::{oidc::PendingLogin, Credentials};
+use crate::middleware::csrf::csrf_token;
+use crate::AppState;
+
+const OIDC_PENDING_KEY: &str = "oidc_pending";
+
+#[derive(Deserialize)]
+pub struct CallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+async fn login_error(state: &AppState, session: &Session, error: &str) -> Response {
+    let template = LoginTemplate::new(state, error, csrf_token(session).await);
+    Html(template.render().unwrap()).into_response()
+}
+
+/// Redirect the browser to the identity provider
+pub async fn oidc_login(session: Session, State(state): State<Arc<AppState>>) -> Response {
+    let Some(oidc) = &state.oidc else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let (url, pending) = match oidc.authorization_url().await {
+        Ok(result) => result,
+        Err(e) => {
+            tracing::error!("Failed to start SSO login: {:#}", e);
+            return login_error(&state, &session, "Single sign-on is currently unavailable").await;
+        }
+    };
+
+    if let Err(e) = session.insert(OIDC_PENDING_KEY, &pending).await {
+        tracing::error!("Failed to store SSO login state: {}", e);
+        return login_error(&state, &session, "Failed to create session").await;
+    }
+
+    Redirect::to(url.as_str()).into_response()
+}
+
+/// Complete the login once the identity provider redirects back
+pub async fn oidc_callback(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<CallbackParams>,
+) -> Response {
+    if state.oidc.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    // The pending login is single use, whatever the outcome
+    let pending: Option<PendingLogin> = session.remove(OIDC_PENDING_KEY).await.ok().flatten();
+
+    if let Some(error) = &params.error {
+        tracing::warn!(
+            "Identity provider returned an error: {} {}",
+            error,
+            params.error_description.as_deref().unwrap_or("")
+        );
+        state.metrics.login_failures.inc();
+        return login_error(&state, &session, "Single sign-on was not completed").await;
+    }
+
+    let (Some(pending), Some(code), Some(returned_state)) = (pending, &params.code, &params.state)
+    else {
+        tracing::warn!("SSO callback without a matching login request");
+        return login_error(&state, &session, "Single sign-on was not completed").await;
+    };
+    if *returned_state != pending.state {
+        tracing::warn!("SSO callback state does not match the login request");
+        state.metrics.login_failures.inc();
+        return login_error(&state, &session, "Single sign-on was not completed").await;
+    }
+
+    let credentials = Credentials::OidcCode {
+        pending: &pending,
+        code,
+    };
+    let Some(identity) = state.authenticators.authenticate(&credentials).await else {
+        state.metrics.login_failures.inc();
+        return login_error(&state, &session, "Single sign-on failed").await;
+    };
+
+    tracing::debug!(
+        "SSO user {} has roles {:?}",
+        identity.username,
+        identity.roles
+    );
+    complete_login(&session, &state, identity).await
+}
@@ -1,18 +1,34 @@
+use askama::Template;
 use axum::{
     body::Body,
-    extract::{Host, Path, State},
-    http::{Response, StatusCode},
-    response::IntoResponse,
+    extract::{Extension, Host, Path, State},
+    http::{header, HeaderMap, Response, StatusCode},
+    response::{Html, IntoResponse},
 };
 use std::sync::Arc;
+use std::time::Instant;
+use tower_sessions::Session;
+use tracing::Instrument;
 use url::Url;
 
+use super::app::ErrorTemplate;
+use crate::auth::CurrentUser;
+use crate::middleware::csrf::csrf_token;
+use crate::middleware::domain_filter::FilterDecision;
+use crate::middleware::learning::{self, current_site, is_navigation, referring_page};
+use crate::middleware::{BlockedDomain, ProxyOutcome};
 use crate::proxy::get_handler;
+use crate::proxy::placeholder::{self, Placeholder};
+use crate::ssrf;
+use crate::telemetry;
 use crate::AppState;
 
 pub async fn proxy_handler(
+    session: Session,
     State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
     Host(host): Host,
+    headers: HeaderMap,
     Path((scheme, target_path)): Path<(String, String)>,
 ) -> impl IntoResponse {
     // 1. Construct target URL from scheme and path
@@ -29,31 +45,114 @@ pub async fn proxy_handler(
 
     let domain = url.host_str().unwrap_or("");
 
-    // 2. Check domain filter
-    if !state.domain_filter.is_allowed(domain) {
+    // 2. Check the user's domain policy
+    let decision = tracing::info_span!("filter_check", domain)
+        .in_scope(|| state.policy(&user).check_url(&url));
+    let decision = if state.config.learning.enabled && decision.rule == "default-deny" {
+        learn(&state, &session, &headers, &user, domain)
+            .await
+            .unwrap_or(decision)
+    } else {
+        decision
+    };
+    state
+        .metrics
+        .filter_decisions
+        .with_label_values(&[
+            if decision.allowed { "allow" } else { "deny" },
+            &decision.rule,
+        ])
+        .inc();
+    if !decision.allowed {
         tracing::warn!("Domain blocked: {}", domain);
+        let mut response = refused(&state, &session, &headers, &user, &url, &decision).await;
+        response.extensions_mut().insert(BlockedDomain {
+            domain: domain.to_string(),
+            rule: decision.rule,
+        });
+        return response;
+    }
+    if state.config.learning.enabled && is_navigation(&headers) {
+        learning::remember_site(&session, domain).await;
+    }
+
+    // 3. Refuse internal addresses written into the URL; host names are
+    // checked by the client's resolver as they are looked up
+    if let Some(ip) = state
+        .addresses
+        .as_ref()
+        .and_then(|a| a.blocked_literal(&url))
+    {
+        tracing::warn!("Upstream address blocked: {}", ip);
         return (
             StatusCode::FORBIDDEN,
-            format!("Domain '{}' is not allowed", domain),
+            format!("Address {} is not allowed", ip),
         )
             .into_response();
     }
 
-    // 3. Make request to target URL
-    let response = match state.client.get(&target_url).send().await {
-        Ok(r) => r,
+    // 4. Make request to target URL
+    let fetch_span = tracing::info_span!("upstream_fetch", url = %target_url);
+    let mut upstream_request = state.client.get(&target_url);
+    if state.telemetry.should_propagate(domain) {
+        for (name, value) in fetch_span.in_scope(telemetry::trace_context_headers) {
+            upstream_request = upstream_request.header(name, value);
+        }
+    }
+
+    let fetch_start = Instant::now();
+    let response = match upstream_request.send().instrument(fetch_span).await {
+        Ok(r) => {
+            state
+                .metrics
+                .upstream_latency
+                .with_label_values(&[domain])
+                .observe(fetch_start.elapsed().as_secs_f64());
+            r
+        }
         Err(e) => {
+            if let Some(blocked) = ssrf::blocked_address(&e) {
+                tracing::warn!("Upstream address blocked: {}", blocked);
+                return (
+                    StatusCode::FORBIDDEN,
+                    format!(
+                        "Domain '{}' resolves to an address that is not allowed",
+                        domain
+                    ),
+                )
+                    .into_response();
+            }
             tracing::error!("Failed to fetch: {}", e);
             return (StatusCode::BAD_GATEWAY, format!("Failed to fetch: {}", e)).into_response();
         }
     };
 
     let status = response.status();
-    let content_type = response
-        .headers()
+    let headers = response.headers().clone();
+    let content_type = headers
         .get("content-type")
         .and_then(|v| v.to_str().ok())
-        .unwrap_or("application/octet-stream");
+        .unwrap_or("application/octet-stream")
+        .to_string();
+
+    // Read the body here to count what upstream actually sent: chunked
+    // responses have no Content-Length, and handlers rewrite what they read
+    let upstream_body = match response.bytes().await {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("Failed to read upstream response: {}", e);
+            return (
+                StatusCode::BAD_GATEWAY,
+                format!("Failed to read response: {}", e),
+            )
+                .into_response();
+        }
+    };
+    let bytes_in = upstream_body.len() as u64;
+    let mut upstream = hyper014::Response::new(upstream_body);
+    *upstream.status_mut() = status;
+    *upstream.headers_mut() = headers;
+    let response = reqwest::Response::from(upstream);
 
     tracing::debug!(
         "Response status: {}, content-type: {}",
@@ -61,13 +160,30 @@ pub async fn proxy_handler(
         content_type
     );
 
-    // 4. Select appropriate handler based on content-type
-    let handler = get_handler(content_type);
+    // 5. Select appropriate handler based on content-type
+    let handler = get_handler(&content_type);
 
-    // 5. Process response with handler
-    let proxy_base = format!("http://{}/proxy", host);
-    let (body, content_type) = match handler.handle(response, &proxy_base, &url).await {
-        Ok(result) => result,
+    // 6. Process response with handler
+    let scheme = if state.config.server.tls.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    let proxy_base = format!("{}://{}/proxy", scheme, host);
+    let handle_start = Instant::now();
+    let (body, content_type) = match handler
+        .handle(response, &proxy_base, &url)
+        .instrument(tracing::info_span!("rewrite", handler = handler.name()))
+        .await
+    {
+        Ok(result) => {
+            state
+                .metrics
+                .handler_duration
+                .with_label_values(&[handler.name()])
+                .observe(handle_start.elapsed().as_secs_f64());
+            result
+        }
         Err(e) => {
             tracing::error!("Processing error: {}", e);
             return (
@@ -78,11 +194,171 @@ pub async fn proxy_handler(
         }
     };
 
-    // 6. Build response
-    Response::builder()
+    // 7. Build response
+    let outcome = ProxyOutcome {
+        target_url: url.to_string(),
+        upstream_host: domain.to_string(),
+        upstream_status: status.as_u16(),
+        handler: handler.name(),
+        bytes_in,
+    };
+    state
+        .metrics
+        .bytes_proxied
+        .with_label_values(&["in"])
+        .inc_by(outcome.bytes_in);
+
+    let mut response = Response::builder()
         .status(StatusCode::OK)
         .header("content-type", content_type)
         .body(Body::from(body))
         .unwrap()
-        .into_response()
+        .into_response();
+    response.extensions_mut().insert(outcome);
+    response
+}
+
+/// Answer a request the domain policy refused with what the browser expected
+/// to load, so a blocked image or stylesheet does not break the page. Pages
+/// and frames explain the refusal instead.
+async fn refused(
+    state: &AppState,
+    session: &Session,
+    headers: &HeaderMap,
+    user: &CurrentUser,
+    url: &Url,
+    decision: &FilterDecision,
+) -> axum::response::Response {
+    let domain = url.host_str().unwrap_or("");
+    // Placeholders must not outlive an approval of the domain
+    let no_store = [(header::CACHE_CONTROL, "no-store")];
+    match placeholder::for_request(headers) {
+        None => (
+            StatusCode::FORBIDDEN,
+            format!("Domain '{}' is not allowed", domain),
+        )
+            .into_response(),
+        Some(Placeholder::Page) => {
+            let page = ErrorTemplate {
+                error_message: format!("Domain '{}' is not allowed", domain),
+                blocked_domain: domain.to_string(),
+                allowed_domains: state.policy(user).allowed_domains(),
+                request_access: state.can_request_access(decision),
+                blocked_url: url.to_string(),
+                csrf_token: csrf_token(session).await,
+            };
+            (
+                StatusCode::FORBIDDEN,
+                no_store,
+                Html(page.render().unwrap()),
+            )
+                .into_response()
+        }
+        Some(Placeholder::Body { content_type, body }) => {
+            (no_store, [(header::CONTENT_TYPE, content_type)], body).into_response()
+        }
+        Some(Placeholder::NoContent) => (StatusCode::NO_CONTENT, no_store).into_response(),
+    }
+}
+
+/// Queue a domain refused by default-deny under the site that needed it.
+/// Returns an allow decision if the user has an auto-allow window open on
+/// that site.
+async fn learn(
+    state: &AppState,
+    session: &Session,
+    headers: &HeaderMap,
+    user: &CurrentUser,
+    domain: &str,
+) -> Option<FilterDecision> {
+    let page = referring_page(headers);
+    let site = current_site(session, page.as_deref())
+        .await
+        .unwrap_or_else(|| domain.to_string());
+    let window = state.learning.window_open(&user.username, &site);
+    state
+        .learning
+        .record(&site, domain, page.as_deref(), &user.username, window);
+
+    window.then(|| {
+        tracing::info!(
+            "Domain {} let through for {} by the auto-allow window on {}",
+            domain,
+            user.username,
+            site
+        );
+        FilterDecision {
+            allowed: true,
+            rule: "learning-window".to_string(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::authenticator::IdentitySource;
+    use crate::config::TlsConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tower_sessions::MemoryStore;
+
+    /// An upstream serving one HTML page with a root-relative link
+    async fn upstream() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let page = r#"<html><body><a href="/next">next</a></body></html>"#;
+                let reply = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: text/html\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    page.len(),
+                    page
+                );
+                let _ = stream.write_all(reply.as_bytes()).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_links_use_https_with_tls() {
+        let upstream = upstream().await;
+        let state = AppState::for_tests(|config| {
+            config.domain_filter.allowlist = vec!["127.0.0.1".to_string()];
+            config.server.tls = Some(TlsConfig {
+                cert_path: "server.pem".to_string(),
+                key_path: "server.key".to_string(),
+                client_ca_path: None,
+            });
+        })
+        .await;
+        let user = CurrentUser {
+            username: "admin".to_string(),
+            roles: vec!["admin".to_string()],
+            source: IdentitySource::Local,
+            token_domains: None,
+        };
+
+        let response = proxy_handler(
+            Session::new(None, Arc::new(MemoryStore::default()), None),
+            State(state),
+            Extension(user),
+            Host("proxy.example:8443".to_string()),
+            HeaderMap::new(),
+            Path(("http".to_string(), format!("{}/", upstream))),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), 64 * 1024)
+            .await
+            .unwrap();
+        let body = String::from_utf8_lossy(&body);
+        let expected = format!("https://proxy.example:8443/proxy/http/{}/next", upstream);
+        assert!(body.contains(&expected), "{}", body);
+        assert!(!body.contains("http://proxy.example"));
+    }
 }
@@ -0,0 +1,185 @@
+use askama::Template;
+use axum::{
+    extract::{Extension, Form, State},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use chrono::DateTime;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tower_sessions::Session;
+
+use crate::auth::tokens::{ApiToken, TokenRequest};
+use crate::auth::CurrentUser;
+use crate::middleware::csrf::csrf_token;
+use crate::AppState;
+
+/// A token as listed on the settings page
+struct TokenRow {
+    id: String,
+    name: String,
+    domains: String,
+    rate_limit: String,
+    expires: String,
+    expired: bool,
+}
+
+impl TokenRow {
+    fn new(token: &ApiToken, now: u64) -> Self {
+        Self {
+            id: token.id.clone(),
+            name: token.name.clone(),
+            domains: if token.domains.is_empty() {
+                "All allowed".to_string()
+            } else {
+                token.domains.join(", ")
+            },
+            rate_limit: token
+                .rate_limit
+                .map(|limit| format!("{}/min", limit))
+                .unwrap_or_else(|| "None".to_string()),
+            expires: DateTime::from_timestamp(token.expires_at as i64, 0)
+                .map(|t| t.format("%Y-%m-%d %H:%M UTC").to_string())
+                .unwrap_or_default(),
+            expired: token.expires_at <= now,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "api_tokens.html")]
+struct TokensTemplate {
+    username: String,
+    tokens: Vec<TokenRow>,
+    /// The user logged in through a directory or SSO, so tokens are capped
+    /// to the session lifetime
+    external: bool,
+    /// Plaintext of a token just created
+    new_token: String,
+    error: String,
+    csrf_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateTokenForm {
+    name: String,
+    lifetime_days: u64,
+    #[serde(default)]
+    domains: String,
+    #[serde(default)]
+    rate_limit: String,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeTokenForm {
+    id: String,
+}
+
+async fn render(
+    session: &Session,
+    state: &AppState,
+    user: &CurrentUser,
+    new_token: String,
+    error: String,
+) -> Response {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let template = TokensTemplate {
+        username: user.username.clone(),
+        external: !user.source.is_local_account(),
+        tokens: state
+            .tokens
+            .list(&user.username)
+            .iter()
+            .map(|t| TokenRow::new(t, now))
+            .collect(),
+        new_token,
+        error,
+        csrf_token: csrf_token(session).await,
+    };
+    Html(template.render().unwrap()).into_response()
+}
+
+pub async fn tokens_page(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+) -> Response {
+    render(&session, &state, &user, String::new(), String::new()).await
+}
+
+pub async fn create_token_handler(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+    Form(form): Form<CreateTokenForm>,
+) -> Response {
+    let rate_limit = match form.rate_limit.trim() {
+        "" => None,
+        limit => match limit.parse() {
+            Ok(limit) => Some(limit),
+            Err(_) => {
+                let error = "Rate limit must be a whole number".to_string();
+                return render(&session, &state, &user, String::new(), error).await;
+            }
+        },
+    };
+    let request = TokenRequest {
+        name: form.name,
+        lifetime_days: form.lifetime_days,
+        domains: form
+            .domains
+            .split([',', ' ', '\n'])
+            .map(|d| d.trim().to_ascii_lowercase())
+            .filter(|d| !d.is_empty())
+            .collect(),
+        rate_limit,
+    };
+
+    match state
+        .tokens
+        .create(&user.username, &user.roles, user.source, request)
+    {
+        Ok((token, plaintext)) => {
+            tracing::info!("User {} created API token {}", user.username, token.id);
+            state.audit.record(
+                "api_token_created",
+                json!({
+                    "username": user.username,
+                    "token_id": token.id,
+                    "name": token.name,
+                    "expires_at": token.expires_at,
+                    "domains": token.domains,
+                    "rate_limit": token.rate_limit,
+                }),
+            );
+            render(&session, &state, &user, plaintext, String::new()).await
+        }
+        Err(e) => {
+            tracing::warn!("Failed to create API token for {}: {:#}", user.username, e);
+            render(&session, &state, &user, String::new(), e.to_string()).await
+        }
+    }
+}
+
+pub async fn revoke_token_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+    Form(form): Form<RevokeTokenForm>,
+) -> Response {
+    match state.tokens.revoke(&user.username, &form.id) {
+        Ok(true) => {
+            tracing::info!("User {} revoked API token {}", user.username, form.id);
+            state.audit.record(
+                "api_token_revoked",
+                json!({ "username": user.username, "token_id": form.id }),
+            );
+        }
+        Ok(false) => {}
+        Err(e) => tracing::error!("Failed to revoke API token: {:#}", e),
+    }
+    Redirect::to("/settings/tokens").into_response()
+}
@@ -0,0 +1,450 @@
+use anyhow::Result;
+use askama::Template;
+use axum::{
+    extract::{ConnectInfo, Form, State},
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tower_sessions::Session;
+
+use super::app::{establish_session, report_lockouts, LoginTemplate, USER_ID_KEY};
+use crate::auth::{
+    authenticator::{Identity, IdentitySource},
+    throttle::Lockout,
+    totp::PendingEnrollment,
+    LoginThrottle, TotpStore,
+};
+use crate::middleware::csrf::csrf_token;
+use crate::AppState;
+
+pub(super) const PENDING_2FA_KEY: &str = "pending_2fa";
+const TOTP_ENROLLMENT_KEY: &str = "totp_enrollment";
+
+/// Failed codes allowed before the user has to log in again
+const MAX_ATTEMPTS: u32 = 5;
+
+/// A user who passed the first factor but still owes a TOTP code. Sessions
+/// in this stage have no user ID, so `require_auth` turns them away.
+#[derive(Serialize, Deserialize)]
+struct PendingSecondFactor {
+    username: String,
+    roles: Vec<String>,
+    #[serde(default)]
+    source: IdentitySource,
+    attempts: u32,
+}
+
+#[derive(Template)]
+#[template(path = "two_factor.html")]
+struct TwoFactorTemplate {
+    error: String,
+    csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "totp_enroll.html")]
+struct EnrollTemplate {
+    username: String,
+    enrolled: bool,
+    recovery_codes_left: usize,
+    qr_code: String,
+    secret: String,
+    recovery_codes: Vec<String>,
+    error: String,
+    csrf_token: String,
+}
+
+impl EnrollTemplate {
+    fn new(username: &str, csrf_token: String) -> Self {
+        Self {
+            csrf_token,
+            username: username.to_string(),
+            enrolled: false,
+            recovery_codes_left: 0,
+            qr_code: String::new(),
+            secret: String::new(),
+            recovery_codes: Vec::new(),
+            error: String::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CodeForm {
+    code: String,
+}
+
+fn render(template: impl Template) -> Response {
+    Html(template.render().unwrap()).into_response()
+}
+
+async fn login_error(state: &AppState, session: &Session, error: &str) -> Response {
+    render(LoginTemplate::new(state, error, csrf_token(session).await))
+}
+
+/// Park a user who passed the first factor until they present a TOTP code,
+/// or enroll one if they are required to and have not yet
+pub(super) async fn begin_second_factor(
+    session: &Session,
+    state: &AppState,
+    identity: Identity,
+) -> Response {
+    let enrolled = state.totp.is_enrolled(&identity.username);
+    let pending = PendingSecondFactor {
+        username: identity.username,
+        roles: identity.roles,
+        source: identity.source,
+        attempts: 0,
+    };
+
+    let stored = match session.cycle_id().await {
+        Ok(()) => session.insert(PENDING_2FA_KEY, &pending).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = stored {
+        tracing::error!("Failed to create session: {}", e);
+        return login_error(state, session, "Failed to create session").await;
+    }
+
+    if enrolled {
+        Redirect::to("/login/2fa").into_response()
+    } else {
+        tracing::info!(
+            "User {} must enroll in two-factor authentication",
+            pending.username
+        );
+        Redirect::to("/2fa/enroll").into_response()
+    }
+}
+
+/// Outcome of a second-factor code
+#[derive(Debug, PartialEq)]
+enum CodeCheck {
+    Accepted,
+    Rejected(Vec<Lockout>),
+    /// Seconds until the user may try again
+    Throttled(u64),
+}
+
+/// Check a TOTP or recovery code. Wrong codes count against the same per-IP
+/// and per-user limits as wrong passwords, and the user's counter is only
+/// reset once a code is accepted.
+async fn check_code(
+    totp: &TotpStore,
+    throttle: &LoginThrottle,
+    ip: IpAddr,
+    username: &str,
+    code: &str,
+) -> Result<CodeCheck> {
+    if let Some(retry_after) = throttle.retry_after(ip, username) {
+        return Ok(CodeCheck::Throttled(retry_after));
+    }
+    if totp.verify(username, code)? {
+        throttle.record_success(ip, username).await;
+        return Ok(CodeCheck::Accepted);
+    }
+    Ok(CodeCheck::Rejected(
+        throttle.record_failure(ip, username).await,
+    ))
+}
+
+async fn pending(session: &Session) -> Option<PendingSecondFactor> {
+    session.get(PENDING_2FA_KEY).await.ok().flatten()
+}
+
+pub async fn second_factor_page(session: Session) -> Response {
+    if pending(&session).await.is_none() {
+        return Redirect::to("/login").into_response();
+    }
+    render(TwoFactorTemplate {
+        error: String::new(),
+        csrf_token: csrf_token(&session).await,
+    })
+}
+
+pub async fn second_factor_handler(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(form): Form<CodeForm>,
+) -> Response {
+    let Some(mut pending) = pending(&session).await else {
+        return Redirect::to("/login").into_response();
+    };
+
+    let ip = addr.ip();
+    match check_code(
+        &state.totp,
+        &state.throttle,
+        ip,
+        &pending.username,
+        &form.code,
+    )
+    .await
+    {
+        Ok(CodeCheck::Accepted) => {}
+        Ok(CodeCheck::Throttled(retry_after)) => {
+            tracing::warn!(
+                "Throttled two-factor code for user {} from {}",
+                pending.username,
+                ip
+            );
+            state.metrics.login_throttled.inc();
+            let template = TwoFactorTemplate {
+                error: format!(
+                    "Too many failed attempts. Try again in {} seconds",
+                    retry_after
+                ),
+                csrf_token: csrf_token(&session).await,
+            };
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                render(template),
+            )
+                .into_response();
+        }
+        Ok(CodeCheck::Rejected(lockouts)) => {
+            tracing::warn!(
+                "Invalid two-factor code for user {} from {}",
+                pending.username,
+                ip
+            );
+            state.metrics.login_failures.inc();
+            report_lockouts(&state, ip, lockouts);
+
+            pending.attempts += 1;
+            if pending.attempts >= MAX_ATTEMPTS {
+                let _ = session.remove_value(PENDING_2FA_KEY).await;
+                return login_error(&state, &session, "Too many invalid codes. Log in again").await;
+            }
+            if let Err(e) = session.insert(PENDING_2FA_KEY, &pending).await {
+                tracing::error!("Failed to update session: {}", e);
+            }
+            return render(TwoFactorTemplate {
+                error: "Invalid code".to_string(),
+                csrf_token: csrf_token(&session).await,
+            });
+        }
+        Err(e) => {
+            tracing::error!("Failed to verify two-factor code: {:#}", e);
+            return render(TwoFactorTemplate {
+                error: "Failed to verify code".to_string(),
+                csrf_token: csrf_token(&session).await,
+            });
+        }
+    }
+
+    if let Err(e) =
+        establish_session(&session, &pending.username, &pending.roles, pending.source).await
+    {
+        tracing::error!("Failed to create session: {}", e);
+        return login_error(&state, &session, "Failed to create session").await;
+    }
+
+    tracing::info!(
+        "User {} logged in with two-factor authentication",
+        pending.username
+    );
+    Redirect::to("/home").into_response()
+}
+
+/// The user enrolling: a logged-in user, or one parked at the second factor
+/// who has not enrolled yet
+async fn enrolling_user(
+    session: &Session,
+    state: &AppState,
+) -> Option<(String, Option<PendingSecondFactor>)> {
+    if let Ok(Some(username)) = session.get::<String>(USER_ID_KEY).await {
+        return Some((username, None));
+    }
+    let pending = pending(session).await?;
+    if state.totp.is_enrolled(&pending.username) {
+        return None;
+    }
+    Some((pending.username.clone(), Some(pending)))
+}
+
+pub async fn enroll_page(session: Session, State(state): State<Arc<AppState>>) -> Response {
+    let Some((username, _)) = enrolling_user(&session, &state).await else {
+        return Redirect::to("/login").into_response();
+    };
+
+    let mut template = EnrollTemplate::new(&username, csrf_token(&session).await);
+    if state.totp.is_enrolled(&username) {
+        template.enrolled = true;
+        template.recovery_codes_left = state.totp.recovery_codes_left(&username);
+        return render(template);
+    }
+
+    // Keep the same secret across reloads until the enrollment is confirmed
+    let enrollment = match session.get::<PendingEnrollment>(TOTP_ENROLLMENT_KEY).await {
+        Ok(Some(enrollment)) => enrollment,
+        _ => {
+            let enrollment = state.totp.begin_enrollment();
+            if let Err(e) = session.insert(TOTP_ENROLLMENT_KEY, &enrollment).await {
+                tracing::error!("Failed to store TOTP enrollment: {}", e);
+            }
+            enrollment
+        }
+    };
+
+    match state.totp.provisioning(&username, &enrollment) {
+        Ok((qr_code, _)) => {
+            template.qr_code = qr_code;
+            template.secret = enrollment.secret;
+        }
+        Err(e) => {
+            tracing::error!("Failed to create TOTP QR code: {:#}", e);
+            template.error = "Failed to create QR code".to_string();
+        }
+    }
+    render(template)
+}
+
+pub async fn enroll_handler(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(form): Form<CodeForm>,
+) -> Response {
+    let Some((username, pending)) = enrolling_user(&session, &state).await else {
+        return Redirect::to("/login").into_response();
+    };
+    let Ok(Some(enrollment)) = session.get::<PendingEnrollment>(TOTP_ENROLLMENT_KEY).await else {
+        return Redirect::to("/2fa/enroll").into_response();
+    };
+
+    let recovery_codes = match state
+        .totp
+        .confirm_enrollment(&username, &enrollment, &form.code)
+    {
+        Ok(Some(codes)) => codes,
+        Ok(None) => {
+            let mut template = EnrollTemplate::new(&username, csrf_token(&session).await);
+            template.secret = enrollment.secret.clone();
+            template.qr_code = state
+                .totp
+                .provisioning(&username, &enrollment)
+                .map(|(qr, _)| qr)
+                .unwrap_or_default();
+            template.error =
+                "Invalid code. Check the time on your device and try again".to_string();
+            return render(template);
+        }
+        Err(e) => {
+            tracing::error!("Failed to save TOTP enrollment: {:#}", e);
+            let mut template = EnrollTemplate::new(&username, csrf_token(&session).await);
+            template.error = "Failed to save enrollment".to_string();
+            return render(template);
+        }
+    };
+    let _ = session.remove_value(TOTP_ENROLLMENT_KEY).await;
+    tracing::info!("User {} enrolled in two-factor authentication", username);
+
+    // Enrolling proves possession of the second factor, so a parked login
+    // can now become a full session
+    if let Some(pending) = pending {
+        if let Err(e) =
+            establish_session(&session, &pending.username, &pending.roles, pending.source).await
+        {
+            tracing::error!("Failed to create session: {}", e);
+            return login_error(&state, &session, "Failed to create session").await;
+        }
+        state
+            .throttle
+            .record_success(addr.ip(), &pending.username)
+            .await;
+    }
+
+    let mut template = EnrollTemplate::new(&username, csrf_token(&session).await);
+    template.recovery_codes = recovery_codes;
+    render(template)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LockoutConfig;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use totp_rs::{Builder, Secret};
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(203, 0, 113, 7));
+
+    /// A store with "alice" enrolled, and her recovery codes
+    fn enrolled() -> (TotpStore, Vec<String>) {
+        let store = TotpStore::new("Browser Proxy", None).unwrap();
+        let enrollment = store.begin_enrollment();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let code = Builder::new()
+            .with_secret(Secret::try_from_base32(&enrollment.secret).unwrap())
+            .with_issuer(Some("Browser Proxy".to_string()))
+            .with_account_name("alice".to_string())
+            .build()
+            .unwrap()
+            .generate(now);
+        let recovery = store
+            .confirm_enrollment("alice", &enrollment, &code.to_string())
+            .unwrap()
+            .unwrap();
+        (store, recovery)
+    }
+
+    #[tokio::test]
+    async fn test_wrong_codes_feed_login_throttle() {
+        let (totp, recovery) = enrolled();
+        let config = LockoutConfig {
+            max_failures: 2,
+            backoff_secs: 0,
+            ..LockoutConfig::default()
+        };
+        let throttle = LoginThrottle::new(&config, None).await.unwrap();
+
+        // A failed password before the first factor passed is not forgotten
+        throttle.record_failure(IP, "alice").await;
+        let check = check_code(&totp, &throttle, IP, "alice", "000000").await;
+        let Ok(CodeCheck::Rejected(lockouts)) = check else {
+            panic!("expected a rejected code, got {:?}", check);
+        };
+        assert_eq!(lockouts.len(), 1);
+        assert_eq!(lockouts[0].scope, "user");
+
+        // Locked out, even with a valid recovery code
+        assert!(matches!(
+            check_code(&totp, &throttle, IP, "alice", &recovery[0]).await,
+            Ok(CodeCheck::Throttled(_))
+        ));
+        assert_eq!(totp.recovery_codes_left("alice"), recovery.len());
+    }
+
+    #[tokio::test]
+    async fn test_accepted_code_resets_user_counter() {
+        let (totp, recovery) = enrolled();
+        let config = LockoutConfig {
+            backoff_secs: 0,
+            ..LockoutConfig::default()
+        };
+        let throttle = LoginThrottle::new(&config, None).await.unwrap();
+
+        assert!(matches!(
+            check_code(&totp, &throttle, IP, "alice", "not-a-code").await,
+            Ok(CodeCheck::Rejected(_))
+        ));
+        assert_eq!(
+            check_code(&totp, &throttle, IP, "alice", &recovery[0])
+                .await
+                .unwrap(),
+            CodeCheck::Accepted
+        );
+        for _ in 0..config.max_failures - 1 {
+            throttle.record_failure(IP, "alice").await;
+        }
+        assert_eq!(throttle.retry_after(IP, "alice"), None);
+    }
+}
@@ -0,0 +1,227 @@
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use tower_sessions::session::{Id, Record};
+use tower_sessions::session_store;
+
+const NONCE_LEN: usize = 24;
+
+/// Encrypts session records before they reach a persistent store.
+///
+/// Records are sealed with XChaCha20-Poly1305 under `session.encryption_key`.
+/// Stores index them by a SHA-256 of the session ID rather than the ID
+/// itself, so a copy of the database holds nothing that can be replayed as a
+/// session cookie.
+pub struct SessionCipher {
+    cipher: XChaCha20Poly1305,
+}
+
+impl fmt::Debug for SessionCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionCipher")
+    }
+}
+
+impl SessionCipher {
+    /// Key is 32 bytes, base64-encoded (`openssl rand -base64 32`)
+    pub fn from_base64(key: &str) -> Result<Self> {
+        let key = STANDARD
+            .decode(key.trim())
+            .context("session.encryption_key is not valid base64")?;
+        if key.len() != 32 {
+            bail!("session.encryption_key must be 32 bytes, got {}", key.len());
+        }
+        Ok(Self {
+            cipher: XChaCha20Poly1305::new_from_slice(&key)?,
+        })
+    }
+
+    /// Hex SHA-256, used for storage keys
+    fn hash(value: &str) -> String {
+        Sha256::digest(value.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Key under which a session is stored
+    pub fn storage_key(id: &Id) -> String {
+        Self::hash(&id.to_string())
+    }
+
+    /// Key under which a key-value entry is stored
+    pub fn entry_key(namespace: &str, key: &str) -> String {
+        Self::hash(&format!("{}\0{}", namespace, key))
+    }
+
+    /// Encrypt as `nonce || ciphertext`. The storage key is authenticated
+    /// too, so rows cannot be swapped between sessions or entries.
+    fn encrypt(&self, storage_key: &str, plaintext: &[u8]) -> session_store::Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: storage_key.as_bytes(),
+                },
+            )
+            .map_err(|e| session_store::Error::Encode(e.to_string()))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    fn decrypt(&self, storage_key: &str, sealed: &[u8]) -> session_store::Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(session_store::Error::Decode(
+                "sealed data is too short".to_string(),
+            ));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: storage_key.as_bytes(),
+                },
+            )
+            .map_err(|_| {
+                session_store::Error::Decode(
+                    "data could not be decrypted (wrong key or tampered data)".to_string(),
+                )
+            })
+    }
+
+    /// Serialize and encrypt a session record
+    pub fn seal(&self, record: &Record) -> session_store::Result<Vec<u8>> {
+        let plaintext =
+            serde_json::to_vec(record).map_err(|e| session_store::Error::Encode(e.to_string()))?;
+        self.encrypt(&Self::storage_key(&record.id), &plaintext)
+    }
+
+    pub fn open(&self, storage_key: &str, sealed: &[u8]) -> session_store::Result<Record> {
+        let plaintext = self.decrypt(storage_key, sealed)?;
+        serde_json::from_slice(&plaintext).map_err(|e| session_store::Error::Decode(e.to_string()))
+    }
+
+    /// Encrypt a key-value entry, keeping the key inside so it can be listed.
+    /// Returns the storage key and the sealed data.
+    pub fn seal_entry(
+        &self,
+        namespace: &str,
+        key: &str,
+        value: &str,
+    ) -> session_store::Result<(String, Vec<u8>)> {
+        let storage_key = Self::entry_key(namespace, key);
+        let plaintext = serde_json::to_vec(&(key, value))
+            .map_err(|e| session_store::Error::Encode(e.to_string()))?;
+        let sealed = self.encrypt(&storage_key, &plaintext)?;
+        Ok((storage_key, sealed))
+    }
+
+    pub fn open_entry(
+        &self,
+        storage_key: &str,
+        sealed: &[u8],
+    ) -> session_store::Result<(String, String)> {
+        let plaintext = self.decrypt(storage_key, sealed)?;
+        serde_json::from_slice(&plaintext).map_err(|e| session_store::Error::Decode(e.to_string()))
+    }
+
+    /// Open a stored session, treating one that cannot be read as missing
+    /// so its cookie leads to a fresh login rather than an error
+    pub fn open_readable(&self, storage_key: &str, sealed: &[u8]) -> Option<Record> {
+        readable(storage_key, self.open(storage_key, sealed))
+    }
+
+    /// Open stored rows (storage key and sealed data) with `open`, skipping
+    /// the ones that fail. A row sealed under an earlier key, or damaged,
+    /// must not make every other session unreadable.
+    pub fn open_each<T>(
+        &self,
+        rows: &[(String, Vec<u8>)],
+        open: impl Fn(&Self, &str, &[u8]) -> session_store::Result<T>,
+    ) -> Vec<T> {
+        rows.iter()
+            .filter_map(|(key, data)| readable(key, open(self, key, data)))
+            .collect()
+    }
+}
+
+fn readable<T>(storage_key: &str, result: session_store::Result<T>) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(e) => {
+            tracing::warn!(
+                "Skipping unreadable stored session data {}: {}",
+                storage_key,
+                e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use time::OffsetDateTime;
+
+    const KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+    fn record() -> Record {
+        Record {
+            id: Id::default(),
+            data: HashMap::from([("user_id".to_string(), "alice".into())]),
+            expiry_date: OffsetDateTime::now_utc(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let cipher = SessionCipher::from_base64(KEY).unwrap();
+        let record = record();
+
+        let sealed = cipher.seal(&record).unwrap();
+        assert!(!String::from_utf8_lossy(&sealed).contains("alice"));
+
+        let key = SessionCipher::storage_key(&record.id);
+        assert_eq!(cipher.open(&key, &sealed).unwrap(), record);
+    }
+
+    #[test]
+    fn test_sealed_record_bound_to_its_key() {
+        let cipher = SessionCipher::from_base64(KEY).unwrap();
+        let sealed = cipher.seal(&record()).unwrap();
+
+        let other = SessionCipher::storage_key(&Id::default());
+        assert!(cipher.open(&other, &sealed).is_err());
+    }
+
+    #[test]
+    fn test_entry_round_trip() {
+        let cipher = SessionCipher::from_base64(KEY).unwrap();
+
+        let (key, sealed) = cipher.seal_entry("login", "user:alice", "{}").unwrap();
+        assert_eq!(key, SessionCipher::entry_key("login", "user:alice"));
+        assert!(!String::from_utf8_lossy(&sealed).contains("alice"));
+        assert_eq!(
+            cipher.open_entry(&key, &sealed).unwrap(),
+            ("user:alice".to_string(), "{}".to_string())
+        );
+    }
+
+    #[test]
+    fn test_key_must_be_32_bytes() {
+        assert!(SessionCipher::from_base64("c2hvcnQ=").is_err());
+        assert!(SessionCipher::from_base64("not base64!").is_err());
+    }
+}
@@ -0,0 +1,399 @@
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time::OffsetDateTime;
+use tower_sessions::session::{Id, Record};
+use tower_sessions::session_store::{self, SessionStore};
+use tower_sessions::MemoryStore;
+
+mod cipher;
+mod redis;
+mod sqlite;
+
+use self::redis::RedisStore;
+use cipher::SessionCipher;
+use sqlite::SqliteStore;
+
+use crate::config::SessionConfig;
+
+/// Session key holding the logged-in username
+pub const USER_ID_KEY: &str = "user_id";
+/// Unix time of login, for the absolute timeout
+pub const LOGGED_IN_AT_KEY: &str = "logged_in_at";
+/// Unix time of the last request, for the idle timeout
+pub const LAST_SEEN_KEY: &str = "last_seen";
+/// Address the session was last used from
+pub const CLIENT_IP_KEY: &str = "client_ip";
+
+/// A persistent store that can enumerate its sessions, so the index below
+/// survives restarts and sees sessions saved by other instances
+#[async_trait]
+pub trait ListSessions: SessionStore {
+    /// All sessions that have not expired
+    async fn list(&self) -> session_store::Result<Vec<Record>>;
+
+    /// Remove expired sessions, returning how many were removed
+    async fn delete_expired(&self) -> session_store::Result<usize>;
+}
+
+/// Small records kept next to the sessions in a persistent store, such as
+/// login failure counters. Values are encrypted like sessions.
+#[async_trait]
+pub trait KeyValueStore: std::fmt::Debug + Send + Sync {
+    async fn put(
+        &self,
+        namespace: &str,
+        key: &str,
+        value: &str,
+        expiry: OffsetDateTime,
+    ) -> session_store::Result<()>;
+
+    async fn remove(&self, namespace: &str, key: &str) -> session_store::Result<()>;
+
+    /// Unexpired entries in a namespace
+    async fn entries(&self, namespace: &str) -> session_store::Result<Vec<(String, String)>>;
+}
+
+#[derive(Debug)]
+struct Entry {
+    expiry: OffsetDateTime,
+    username: Option<String>,
+    client_ip: Option<String>,
+    logged_in_at: Option<i64>,
+    last_seen: Option<i64>,
+}
+
+impl Entry {
+    fn new(record: &Record) -> Self {
+        let string = |key| {
+            record
+                .data
+                .get(key)
+                .and_then(|v| v.as_str())
+                .map(str::to_string)
+        };
+        let time = |key| record.data.get(key).and_then(|v| v.as_i64());
+        Self {
+            expiry: record.expiry_date,
+            username: string(USER_ID_KEY),
+            client_ip: string(CLIENT_IP_KEY),
+            logged_in_at: time(LOGGED_IN_AT_KEY),
+            last_seen: time(LAST_SEEN_KEY),
+        }
+    }
+}
+
+/// A logged-in session, as shown to administrators
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionInfo {
+    /// Stands in for the session ID, which would let whoever sees it take
+    /// over the session
+    pub handle: String,
+    pub username: String,
+    pub client_ip: Option<String>,
+    pub logged_in_at: Option<i64>,
+    pub last_seen: Option<i64>,
+}
+
+/// Public handle for a session ID
+pub fn session_handle(id: &Id) -> String {
+    Sha256::digest(id.to_string().as_bytes())
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Session store wrapper that keeps an index of live sessions.
+///
+/// tower-sessions stores are opaque, so this records each session as it is
+/// saved to answer questions such as how many sessions are active, or which
+/// sessions belong to a user.
+#[derive(Debug, Clone)]
+pub struct TrackedStore {
+    inner: Arc<dyn SessionStore>,
+    /// The same store as `inner`, when it is persistent
+    persistent: Option<Arc<dyn ListSessions>>,
+    key_values: Option<Arc<dyn KeyValueStore>>,
+    index: Arc<Mutex<HashMap<Id, Entry>>>,
+}
+
+impl TrackedStore {
+    pub fn new(inner: impl SessionStore) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            persistent: None,
+            key_values: None,
+            index: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Track a persistent store, starting from the sessions already in it
+    pub async fn persistent(
+        store: impl ListSessions + KeyValueStore + Clone,
+    ) -> session_store::Result<Self> {
+        let tracked = Self {
+            inner: Arc::new(store.clone()),
+            persistent: Some(Arc::new(store.clone())),
+            key_values: Some(Arc::new(store)),
+            index: Arc::new(Mutex::new(HashMap::new())),
+        };
+        tracked.refresh().await?;
+        Ok(tracked)
+    }
+
+    /// The persistent store's key-value entries; `None` for the memory store
+    pub fn key_values(&self) -> Option<Arc<dyn KeyValueStore>> {
+        self.key_values.clone()
+    }
+
+    /// Rebuild the index from the persistent store
+    async fn refresh(&self) -> session_store::Result<()> {
+        let Some(store) = &self.persistent else {
+            return Ok(());
+        };
+        let records = store.list().await?;
+        *self.index.lock().unwrap() = records
+            .iter()
+            .map(|record| (record.id, Entry::new(record)))
+            .collect();
+        Ok(())
+    }
+
+    /// Delete expired sessions from the persistent store and resync the index
+    pub async fn cleanup(&self) -> session_store::Result<()> {
+        if let Some(store) = &self.persistent {
+            let removed = store.delete_expired().await?;
+            if removed > 0 {
+                tracing::debug!("Removed {} expired session(s)", removed);
+            }
+        }
+        self.refresh().await
+    }
+
+    /// Number of sessions that have not yet expired
+    pub fn active_count(&self) -> usize {
+        let now = OffsetDateTime::now_utc();
+        let mut index = self.index.lock().unwrap();
+        index.retain(|_, entry| entry.expiry > now);
+        index.len()
+    }
+
+    /// Logged-in sessions that have not expired, most recently used first
+    pub async fn logged_in(&self) -> session_store::Result<Vec<SessionInfo>> {
+        self.refresh().await?;
+        let now = OffsetDateTime::now_utc();
+        let mut sessions: Vec<SessionInfo> = self
+            .index
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| entry.expiry > now)
+            .filter_map(|(id, entry)| {
+                Some(SessionInfo {
+                    handle: session_handle(id),
+                    username: entry.username.clone()?,
+                    client_ip: entry.client_ip.clone(),
+                    logged_in_at: entry.logged_in_at,
+                    last_seen: entry.last_seen,
+                })
+            })
+            .collect();
+        sessions.sort_by_key(|s| std::cmp::Reverse(s.last_seen));
+        Ok(sessions)
+    }
+
+    /// Delete the session with the given handle. Returns its username, or
+    /// `None` if no such session exists.
+    pub async fn revoke_handle(&self, handle: &str) -> session_store::Result<Option<String>> {
+        self.refresh().await?;
+        let found = self
+            .index
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(id, _)| session_handle(id) == handle)
+            .map(|(id, entry)| (*id, entry.username.clone().unwrap_or_default()));
+
+        match found {
+            Some((id, username)) => {
+                self.delete(&id).await?;
+                Ok(Some(username))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Delete every session belonging to a user, returning how many there were
+    pub async fn revoke_user(&self, username: &str) -> session_store::Result<usize> {
+        // Another instance may have saved sessions for the user
+        self.refresh().await?;
+        let ids: Vec<Id> = self
+            .index
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| entry.username.as_deref() == Some(username))
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in &ids {
+            self.delete(id).await?;
+        }
+        Ok(ids.len())
+    }
+}
+
+#[async_trait]
+impl SessionStore for TrackedStore {
+    async fn save(&self, record: &Record) -> session_store::Result<()> {
+        self.inner.save(record).await?;
+        self.index
+            .lock()
+            .unwrap()
+            .insert(record.id, Entry::new(record));
+        Ok(())
+    }
+
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+        self.inner.load(session_id).await
+    }
+
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+        self.inner.delete(session_id).await?;
+        self.index.lock().unwrap().remove(session_id);
+        Ok(())
+    }
+}
+
+/// Open the configured session store. Persistent stores are encrypted and
+/// cleaned of expired sessions in the background.
+pub async fn open(config: &SessionConfig) -> Result<TrackedStore> {
+    if config.store == "memory" {
+        return Ok(TrackedStore::new(MemoryStore::default()));
+    }
+
+    let Some(key) = &config.encryption_key else {
+        bail!(
+            "session.encryption_key is required for the {} session store \
+             (generate one with `openssl rand -base64 32`)",
+            config.store
+        );
+    };
+    let cipher = SessionCipher::from_base64(key)?;
+
+    let tracked = match config.store.as_str() {
+        "sqlite" => {
+            tracing::info!("Storing sessions in {}", config.sqlite_path);
+            let store = SqliteStore::open(&config.sqlite_path, cipher)
+                .with_context(|| format!("Failed to open {}", config.sqlite_path))?;
+            TrackedStore::persistent(store).await?
+        }
+        "redis" => {
+            let Some(url) = &config.redis_url else {
+                bail!("session.redis_url is required for the redis session store");
+            };
+            tracing::info!("Storing sessions in Redis");
+            let store = RedisStore::connect(url, cipher)
+                .await
+                .context("Failed to connect to the session Redis server")?;
+            TrackedStore::persistent(store).await?
+        }
+        other => bail!(
+            "Unknown session store '{}'. Use memory, sqlite or redis",
+            other
+        ),
+    };
+    tracing::info!("Loaded {} existing session(s)", tracked.active_count());
+
+    let period = Duration::from_secs(config.cleanup_interval_secs.max(1));
+    let cleanup = tracked.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = cleanup.cleanup().await {
+                tracing::warn!("Session cleanup failed: {}", e);
+            }
+        }
+    });
+
+    Ok(tracked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Duration;
+
+    async fn save(store: &TrackedStore, username: Option<&str>) -> Id {
+        let mut data = HashMap::new();
+        if let Some(username) = username {
+            data.insert(USER_ID_KEY.to_string(), username.into());
+        }
+        let record = Record {
+            id: Id::default(),
+            data,
+            expiry_date: OffsetDateTime::now_utc() + Duration::hours(1),
+        };
+        store.save(&record).await.unwrap();
+        record.id
+    }
+
+    #[tokio::test]
+    async fn test_revoke_user_deletes_only_their_sessions() {
+        let store = TrackedStore::new(MemoryStore::default());
+        let alice_laptop = save(&store, Some("alice")).await;
+        let alice_phone = save(&store, Some("alice")).await;
+        let bob = save(&store, Some("bob")).await;
+        save(&store, None).await;
+
+        assert_eq!(store.revoke_user("alice").await.unwrap(), 2);
+        assert!(store.load(&alice_laptop).await.unwrap().is_none());
+        assert!(store.load(&alice_phone).await.unwrap().is_none());
+        assert!(store.load(&bob).await.unwrap().is_some());
+        assert_eq!(store.active_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_logged_in_sessions_listed_and_revoked_by_handle() {
+        let store = TrackedStore::new(MemoryStore::default());
+        let alice = save(&store, Some("alice")).await;
+        save(&store, Some("bob")).await;
+        save(&store, None).await;
+
+        let sessions = store.logged_in().await.unwrap();
+        assert_eq!(sessions.len(), 2);
+        let handle = session_handle(&alice);
+        assert!(!handle.contains(&alice.to_string()));
+        assert!(sessions
+            .iter()
+            .any(|s| s.handle == handle && s.username == "alice"));
+
+        assert_eq!(
+            store.revoke_handle(&handle).await.unwrap().as_deref(),
+            Some("alice")
+        );
+        assert!(store.load(&alice).await.unwrap().is_none());
+        assert_eq!(store.revoke_handle(&handle).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_persistent_index_rebuilt_on_open() {
+        let cipher =
+            SessionCipher::from_base64("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap();
+        let sqlite = SqliteStore::open(":memory:", cipher).unwrap();
+        let before_restart = TrackedStore::persistent(sqlite.clone()).await.unwrap();
+        save(&before_restart, Some("alice")).await;
+        save(&before_restart, Some("bob")).await;
+
+        let after_restart = TrackedStore::persistent(sqlite).await.unwrap();
+        assert_eq!(after_restart.active_count(), 2);
+        assert_eq!(after_restart.revoke_user("alice").await.unwrap(), 1);
+        assert_eq!(after_restart.active_count(), 1);
+    }
+}
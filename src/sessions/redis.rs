@@ -0,0 +1,356 @@
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use std::fmt;
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tower_sessions::session::{Id, Record};
+use tower_sessions::session_store::{self, SessionStore};
+
+use super::cipher::SessionCipher;
+use super::{KeyValueStore, ListSessions};
+
+const KEY_PREFIX: &str = "browser_proxy:session:";
+const ENTRY_PREFIX: &str = "browser_proxy:entry:";
+
+/// Session store shared by several instances through Redis (or any server
+/// speaking its protocol, such as Valkey or KeyDB). Keys carry a TTL, so
+/// the server expires sessions itself.
+#[derive(Clone)]
+pub struct RedisStore {
+    conn: ConnectionManager,
+    cipher: Arc<SessionCipher>,
+}
+
+impl fmt::Debug for RedisStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisStore").finish_non_exhaustive()
+    }
+}
+
+fn backend(e: redis::RedisError) -> session_store::Error {
+    session_store::Error::Backend(e.to_string())
+}
+
+impl RedisStore {
+    pub async fn connect(url: &str, cipher: SessionCipher) -> anyhow::Result<Self> {
+        let client = redis::Client::open(url)?;
+        let conn = ConnectionManager::new(client).await?;
+        Ok(Self {
+            conn,
+            cipher: Arc::new(cipher),
+        })
+    }
+
+    fn key(storage_key: &str) -> String {
+        format!("{}{}", KEY_PREFIX, storage_key)
+    }
+
+    /// Values of all keys under a prefix, with the prefix stripped from keys
+    async fn scan(&self, prefix: &str) -> session_store::Result<Vec<(String, Vec<u8>)>> {
+        let mut conn = self.conn.clone();
+        let mut keys: Vec<String> = Vec::new();
+        let mut cursor: u64 = 0;
+        loop {
+            let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(format!("{}*", prefix))
+                .arg("COUNT")
+                .arg(500)
+                .query_async(&mut conn)
+                .await
+                .map_err(backend)?;
+            keys.extend(batch);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            let data: Option<Vec<u8>> = redis::cmd("GET")
+                .arg(&key)
+                .query_async(&mut conn)
+                .await
+                .map_err(backend)?;
+            // Expired between SCAN and GET
+            if let Some(data) = data {
+                values.push((key[prefix.len()..].to_string(), data));
+            }
+        }
+        Ok(values)
+    }
+
+    /// SET with a millisecond TTL; a TTL already past deletes the key
+    async fn set(
+        &self,
+        key: &str,
+        data: Vec<u8>,
+        expiry: OffsetDateTime,
+    ) -> session_store::Result<()> {
+        let mut conn = self.conn.clone();
+        let ttl_ms = (expiry - OffsetDateTime::now_utc()).whole_milliseconds();
+        if ttl_ms <= 0 {
+            let _: () = redis::cmd("DEL")
+                .arg(key)
+                .query_async(&mut conn)
+                .await
+                .map_err(backend)?;
+            return Ok(());
+        }
+        let _: () = redis::cmd("SET")
+            .arg(key)
+            .arg(data)
+            .arg("PX")
+            .arg(ttl_ms as u64)
+            .query_async(&mut conn)
+            .await
+            .map_err(backend)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionStore for RedisStore {
+    async fn save(&self, record: &Record) -> session_store::Result<()> {
+        let key = Self::key(&SessionCipher::storage_key(&record.id));
+        self.set(&key, self.cipher.seal(record)?, record.expiry_date)
+            .await
+    }
+
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+        let storage_key = SessionCipher::storage_key(session_id);
+        let mut conn = self.conn.clone();
+        let data: Option<Vec<u8>> = redis::cmd("GET")
+            .arg(Self::key(&storage_key))
+            .query_async(&mut conn)
+            .await
+            .map_err(backend)?;
+
+        Ok(data.and_then(|data| self.cipher.open_readable(&storage_key, &data)))
+    }
+
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+        let mut conn = self.conn.clone();
+        let _: () = redis::cmd("DEL")
+            .arg(Self::key(&SessionCipher::storage_key(session_id)))
+            .query_async(&mut conn)
+            .await
+            .map_err(backend)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ListSessions for RedisStore {
+    async fn list(&self) -> session_store::Result<Vec<Record>> {
+        let rows = self.scan(KEY_PREFIX).await?;
+        Ok(self.cipher.open_each(&rows, SessionCipher::open))
+    }
+
+    async fn delete_expired(&self) -> session_store::Result<usize> {
+        // Keys expire through their TTL
+        Ok(0)
+    }
+}
+
+#[async_trait]
+impl KeyValueStore for RedisStore {
+    async fn put(
+        &self,
+        namespace: &str,
+        key: &str,
+        value: &str,
+        expiry: OffsetDateTime,
+    ) -> session_store::Result<()> {
+        let (storage_key, data) = self.cipher.seal_entry(namespace, key, value)?;
+        let key = format!("{}{}:{}", ENTRY_PREFIX, namespace, storage_key);
+        self.set(&key, data, expiry).await
+    }
+
+    async fn remove(&self, namespace: &str, key: &str) -> session_store::Result<()> {
+        let key = format!(
+            "{}{}:{}",
+            ENTRY_PREFIX,
+            namespace,
+            SessionCipher::entry_key(namespace, key)
+        );
+        let mut conn = self.conn.clone();
+        let _: () = redis::cmd("DEL")
+            .arg(key)
+            .query_async(&mut conn)
+            .await
+            .map_err(backend)?;
+        Ok(())
+    }
+
+    async fn entries(&self, namespace: &str) -> session_store::Result<Vec<(String, String)>> {
+        let rows = self
+            .scan(&format!("{}{}:", ENTRY_PREFIX, namespace))
+            .await?;
+        Ok(self.cipher.open_each(&rows, SessionCipher::open_entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::sync::Mutex;
+
+    type Keyspace = Arc<Mutex<HashMap<String, (Vec<u8>, Instant)>>>;
+
+    /// Read one RESP command (an array of bulk strings)
+    async fn read_command(
+        reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>,
+    ) -> Option<Vec<Vec<u8>>> {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.ok()?;
+        let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            line.clear();
+            reader.read_line(&mut line).await.ok()?;
+            let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+            let mut arg = vec![0u8; len + 2];
+            reader.read_exact(&mut arg).await.ok()?;
+            arg.truncate(len);
+            args.push(arg);
+        }
+        Some(args)
+    }
+
+    fn bulk(value: &[u8]) -> Vec<u8> {
+        let mut out = format!("${}\r\n", value.len()).into_bytes();
+        out.extend_from_slice(value);
+        out.extend_from_slice(b"\r\n");
+        out
+    }
+
+    /// Minimal Redis stand-in: SET with PX, GET, DEL and a single-page SCAN
+    async fn serve(keyspace: Keyspace, stream: tokio::net::TcpStream) {
+        let (read, mut write) = stream.into_split();
+        let mut reader = BufReader::new(read);
+        while let Some(args) = read_command(&mut reader).await {
+            let arg = |i: usize| String::from_utf8_lossy(&args[i]).to_string();
+            let mut keyspace = keyspace.lock().await;
+            keyspace.retain(|_, (_, expiry)| *expiry > Instant::now());
+
+            let reply = match arg(0).to_uppercase().as_str() {
+                "SET" => {
+                    let ttl: u64 = arg(4).parse().unwrap();
+                    let expiry = Instant::now() + Duration::from_millis(ttl);
+                    keyspace.insert(arg(1), (args[2].clone(), expiry));
+                    b"+OK\r\n".to_vec()
+                }
+                "GET" => match keyspace.get(&arg(1)) {
+                    Some((value, _)) => bulk(value),
+                    None => b"$-1\r\n".to_vec(),
+                },
+                "DEL" => format!(":{}\r\n", keyspace.remove(&arg(1)).is_some() as u8).into_bytes(),
+                "SCAN" => {
+                    let prefix = arg(3).trim_end_matches('*').to_string();
+                    let keys: Vec<&String> =
+                        keyspace.keys().filter(|k| k.starts_with(&prefix)).collect();
+                    let mut out = b"*2\r\n".to_vec();
+                    out.extend(bulk(b"0"));
+                    out.extend(format!("*{}\r\n", keys.len()).into_bytes());
+                    for key in keys {
+                        out.extend(bulk(key.as_bytes()));
+                    }
+                    out
+                }
+                _ => b"+OK\r\n".to_vec(),
+            };
+            if write.write_all(&reply).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn redis() -> String {
+        let keyspace = Keyspace::default();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(keyspace.clone(), stream));
+            }
+        });
+        url
+    }
+
+    async fn store(url: &str) -> RedisStore {
+        store_with_key(url, "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").await
+    }
+
+    async fn store_with_key(url: &str, key: &str) -> RedisStore {
+        let cipher = SessionCipher::from_base64(key).unwrap();
+        RedisStore::connect(url, cipher).await.unwrap()
+    }
+
+    fn record(expires_in: time::Duration) -> Record {
+        Record {
+            id: Id::default(),
+            data: HashMap::from([("user_id".to_string(), "alice".into())]),
+            expiry_date: OffsetDateTime::now_utc() + expires_in,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sessions_shared_between_instances() {
+        let url = redis().await;
+        let first = store(&url).await;
+        let second = store(&url).await;
+        let record = record(time::Duration::hours(1));
+
+        first.save(&record).await.unwrap();
+        assert_eq!(second.load(&record.id).await.unwrap(), Some(record.clone()));
+        assert_eq!(second.list().await.unwrap(), vec![record.clone()]);
+
+        second.delete(&record.id).await.unwrap();
+        assert!(first.load(&record.id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_session_under_rotated_key_loads_as_missing() {
+        let url = redis().await;
+        let record = record(time::Duration::hours(1));
+        store(&url).await.save(&record).await.unwrap();
+
+        let rotated = store_with_key(&url, "ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=").await;
+        assert!(rotated.load(&record.id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_entries_shared_between_instances() {
+        let url = redis().await;
+        let first = store(&url).await;
+        let second = store(&url).await;
+        let later = OffsetDateTime::now_utc() + time::Duration::hours(1);
+
+        first.put("login", "ip:10.0.0.1", "4", later).await.unwrap();
+        first.put("other", "ip:10.0.0.1", "x", later).await.unwrap();
+        assert_eq!(
+            second.entries("login").await.unwrap(),
+            vec![("ip:10.0.0.1".to_string(), "4".to_string())]
+        );
+
+        second.remove("login", "ip:10.0.0.1").await.unwrap();
+        assert!(first.entries("login").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sessions_expire_with_ttl() {
+        let store = store(&redis().await).await;
+        let record = record(time::Duration::milliseconds(50));
+
+        store.save(&record).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(store.load(&record.id).await.unwrap().is_none());
+    }
+}
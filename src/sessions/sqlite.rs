@@ -0,0 +1,345 @@
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::sync::{Arc, Mutex};
+use time::OffsetDateTime;
+use tower_sessions::session::{Id, Record};
+use tower_sessions::session_store::{self, SessionStore};
+
+use super::cipher::SessionCipher;
+use super::{KeyValueStore, ListSessions};
+
+/// Single-node session store in a SQLite database file
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+    cipher: Arc<SessionCipher>,
+}
+
+fn backend(e: impl std::fmt::Display) -> session_store::Error {
+    session_store::Error::Backend(e.to_string())
+}
+
+impl SqliteStore {
+    pub fn open(path: &str, cipher: SessionCipher) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS sessions (
+                 id TEXT PRIMARY KEY NOT NULL,
+                 data BLOB NOT NULL,
+                 expiry_date INTEGER NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS sessions_expiry ON sessions (expiry_date);
+             CREATE TABLE IF NOT EXISTS entries (
+                 namespace TEXT NOT NULL,
+                 id TEXT NOT NULL,
+                 data BLOB NOT NULL,
+                 expiry_date INTEGER NOT NULL,
+                 PRIMARY KEY (namespace, id)
+             );",
+        )?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            cipher: Arc::new(cipher),
+        })
+    }
+
+    /// Run a query on the blocking pool; SQLite calls block on disk I/O
+    async fn with_conn<T, F>(&self, f: F) -> session_store::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&conn.lock().unwrap()))
+            .await
+            .map_err(backend)?
+            .map_err(backend)
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqliteStore {
+    async fn save(&self, record: &Record) -> session_store::Result<()> {
+        let key = SessionCipher::storage_key(&record.id);
+        let data = self.cipher.seal(record)?;
+        let expiry = record.expiry_date.unix_timestamp();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO sessions (id, data, expiry_date) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (id) DO UPDATE SET data = excluded.data, expiry_date = excluded.expiry_date",
+                params![key, data, expiry],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+        let key = SessionCipher::storage_key(session_id);
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let lookup = key.clone();
+        let data: Option<Vec<u8>> = self
+            .with_conn(move |conn| {
+                conn.query_row(
+                    "SELECT data FROM sessions WHERE id = ?1 AND expiry_date > ?2",
+                    params![lookup, now],
+                    |row| row.get(0),
+                )
+                .optional()
+            })
+            .await?;
+
+        Ok(data.and_then(|data| self.cipher.open_readable(&key, &data)))
+    }
+
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+        let key = SessionCipher::storage_key(session_id);
+        self.with_conn(move |conn| conn.execute("DELETE FROM sessions WHERE id = ?1", [key]))
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ListSessions for SqliteStore {
+    async fn list(&self) -> session_store::Result<Vec<Record>> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let rows: Vec<(String, Vec<u8>)> = self
+            .with_conn(move |conn| {
+                conn.prepare("SELECT id, data FROM sessions WHERE expiry_date > ?1")?
+                    .query_map([now], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect()
+            })
+            .await?;
+
+        Ok(self.cipher.open_each(&rows, SessionCipher::open))
+    }
+
+    async fn delete_expired(&self) -> session_store::Result<usize> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        self.with_conn(move |conn| {
+            conn.execute("DELETE FROM entries WHERE expiry_date <= ?1", [now])?;
+            conn.execute("DELETE FROM sessions WHERE expiry_date <= ?1", [now])
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl KeyValueStore for SqliteStore {
+    async fn put(
+        &self,
+        namespace: &str,
+        key: &str,
+        value: &str,
+        expiry: OffsetDateTime,
+    ) -> session_store::Result<()> {
+        let (id, data) = self.cipher.seal_entry(namespace, key, value)?;
+        let namespace = namespace.to_string();
+        let expiry = expiry.unix_timestamp();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO entries (namespace, id, data, expiry_date) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (namespace, id) DO UPDATE SET data = excluded.data, expiry_date = excluded.expiry_date",
+                params![namespace, id, data, expiry],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, namespace: &str, key: &str) -> session_store::Result<()> {
+        let id = SessionCipher::entry_key(namespace, key);
+        let namespace = namespace.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "DELETE FROM entries WHERE namespace = ?1 AND id = ?2",
+                [namespace, id],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn entries(&self, namespace: &str) -> session_store::Result<Vec<(String, String)>> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let namespace = namespace.to_string();
+        let rows: Vec<(String, Vec<u8>)> = self
+            .with_conn(move |conn| {
+                conn.prepare(
+                    "SELECT id, data FROM entries WHERE namespace = ?1 AND expiry_date > ?2",
+                )?
+                .query_map(params![namespace, now], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?
+                .collect()
+            })
+            .await?;
+
+        Ok(self.cipher.open_each(&rows, SessionCipher::open_entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use time::Duration;
+
+    fn store(path: &str) -> SqliteStore {
+        store_with_key(path, "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=")
+    }
+
+    fn store_with_key(path: &str, key: &str) -> SqliteStore {
+        SqliteStore::open(path, SessionCipher::from_base64(key).unwrap()).unwrap()
+    }
+
+    fn record(expires_in: Duration) -> Record {
+        Record {
+            id: Id::default(),
+            data: HashMap::from([("user_id".to_string(), "alice".into())]),
+            expiry_date: OffsetDateTime::now_utc() + expires_in,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sessions_survive_reopen() {
+        let dir =
+            std::env::temp_dir().join(format!("browser_proxy_sessions_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sessions.db");
+        let path = path.to_str().unwrap();
+        let record = record(Duration::hours(1));
+
+        store(path).save(&record).await.unwrap();
+
+        let reopened = store(path);
+        assert_eq!(
+            reopened.load(&record.id).await.unwrap(),
+            Some(record.clone())
+        );
+        assert_eq!(reopened.list().await.unwrap(), vec![record.clone()]);
+
+        reopened.delete(&record.id).await.unwrap();
+        assert!(reopened.load(&record.id).await.unwrap().is_none());
+
+        drop(reopened);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expired_sessions_hidden_and_deleted() {
+        let store = store(":memory:");
+        let live = record(Duration::hours(1));
+        let expired = record(Duration::seconds(-1));
+        store.save(&live).await.unwrap();
+        store.save(&expired).await.unwrap();
+
+        assert!(store.load(&expired.id).await.unwrap().is_none());
+        assert_eq!(store.delete_expired().await.unwrap(), 1);
+        assert_eq!(store.list().await.unwrap(), vec![live]);
+    }
+
+    #[tokio::test]
+    async fn test_session_under_rotated_key_loads_as_missing() {
+        let dir = std::env::temp_dir().join(format!(
+            "browser_proxy_sessions_rotated_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sessions.db");
+        let path = path.to_str().unwrap();
+        let record = record(Duration::hours(1));
+
+        store(path).save(&record).await.unwrap();
+
+        let rotated = store_with_key(path, "ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=");
+        assert!(rotated.load(&record.id).await.unwrap().is_none());
+
+        drop(rotated);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_rows_skipped() {
+        let store = store(":memory:");
+        let live = record(Duration::hours(1));
+        store.save(&live).await.unwrap();
+        let later = (OffsetDateTime::now_utc() + Duration::hours(1)).unix_timestamp();
+        store
+            .with_conn(move |conn| {
+                conn.execute(
+                    "INSERT INTO sessions (id, data, expiry_date) VALUES ('stale', x'00ff', ?1)",
+                    [later],
+                )?;
+                conn.execute(
+                    "INSERT INTO entries (namespace, id, data, expiry_date) \
+                     VALUES ('login', 'stale', x'00ff', ?1)",
+                    [later],
+                )
+            })
+            .await
+            .unwrap();
+        store
+            .put(
+                "login",
+                "user:alice",
+                "{}",
+                OffsetDateTime::now_utc() + Duration::hours(1),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(store.list().await.unwrap(), vec![live]);
+        assert_eq!(
+            store.entries("login").await.unwrap(),
+            vec![("user:alice".to_string(), "{}".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_entries_by_namespace() {
+        let store = store(":memory:");
+        let later = OffsetDateTime::now_utc() + Duration::hours(1);
+        store.put("login", "user:alice", "3", later).await.unwrap();
+        store.put("login", "user:bob", "1", later).await.unwrap();
+        store.put("other", "user:alice", "x", later).await.unwrap();
+        store
+            .put(
+                "login",
+                "user:carol",
+                "9",
+                OffsetDateTime::now_utc() - Duration::seconds(1),
+            )
+            .await
+            .unwrap();
+        store.remove("login", "user:bob").await.unwrap();
+
+        assert_eq!(
+            store.entries("login").await.unwrap(),
+            vec![("user:alice".to_string(), "3".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_session_id_not_stored_in_clear() {
+        let store = store(":memory:");
+        let record = record(Duration::hours(1));
+        store.save(&record).await.unwrap();
+
+        let id = record.id.to_string();
+        let leaked: i64 = store
+            .with_conn(move |conn| {
+                conn.query_row(
+                    "SELECT COUNT(*) FROM sessions WHERE id = ?1 OR CAST(data AS TEXT) LIKE '%alice%'",
+                    [id],
+                    |row| row.get(0),
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(leaked, 0);
+    }
+}
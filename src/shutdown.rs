@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Resolves when the process receives SIGINT (Ctrl+C) or SIGTERM
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut s) => {
+                s.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to install SIGTERM handler: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => tracing::info!("Received SIGINT"),
+        _ = terminate => tracing::info!("Received SIGTERM"),
+    }
+}
+
+/// Fail readiness, keep serving for `delay` while load balancers notice,
+/// then tell the listeners to stop accepting
+pub async fn begin(shutting_down: &AtomicBool, delay: Duration, tx: &watch::Sender<bool>) {
+    shutting_down.store(true, Ordering::SeqCst);
+    if !delay.is_zero() {
+        tracing::info!("Failing readiness for {:?} before closing listeners", delay);
+        tokio::time::sleep(delay).await;
+    }
+    let _ = tx.send(true);
+}
+
+/// Resolves once shutdown has been triggered on the channel
+pub async fn triggered(mut rx: watch::Receiver<bool>) {
+    // An error means the sender is gone, which also means shutting down
+    let _ = rx.wait_for(|stopping| *stopping).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::readyz;
+    use crate::AppState;
+    use axum::{extract::State, http::StatusCode, response::IntoResponse};
+
+    #[tokio::test]
+    async fn test_not_ready_before_listeners_stop() {
+        let state = AppState::for_tests(|_| {}).await;
+        let (tx, rx) = watch::channel(false);
+
+        let shutting_down = state.shutting_down.clone();
+        let begun = tokio::spawn(async move {
+            begin(&shutting_down, Duration::from_millis(200), &tx).await;
+            tx
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Still accepting, but already out of rotation
+        assert!(!*rx.borrow());
+        let response = readyz(State(state.clone())).await.into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let _tx = begun.await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), triggered(rx))
+            .await
+            .expect("listeners told to stop after the delay");
+    }
+}
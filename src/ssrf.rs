@@ -0,0 +1,209 @@
+use anyhow::{anyhow, Result};
+use hyper014::client::connect::dns::Name;
+use ipnet::IpNet;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use url::{Host, Url};
+
+use crate::config::SsrfConfig;
+
+/// Which upstream addresses the proxy may connect to
+pub struct AddressPolicy {
+    deny: Vec<IpNet>,
+    allow: Vec<IpNet>,
+}
+
+fn parse_ranges(entries: &[String], list: &str) -> Result<Vec<IpNet>> {
+    entries
+        .iter()
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| anyhow!("Invalid address or CIDR range '{}' in ssrf.{}", entry, list))
+        })
+        .collect()
+}
+
+impl AddressPolicy {
+    pub fn new(config: &SsrfConfig) -> Result<Self> {
+        Ok(Self {
+            deny: parse_ranges(&config.deny, "deny")?,
+            allow: parse_ranges(&config.allow, "allow")?,
+        })
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        // An IPv4-mapped IPv6 address reaches the IPv4 host
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        self.allow.iter().any(|net| net.contains(&ip))
+            || !self.deny.iter().any(|net| net.contains(&ip))
+    }
+
+    /// A denied address written directly in the URL. Host names are checked
+    /// by [`GuardedResolver`] instead.
+    pub fn blocked_literal(&self, url: &Url) -> Option<IpAddr> {
+        let ip = match url.host()? {
+            Host::Ipv4(ip) => IpAddr::V4(ip),
+            Host::Ipv6(ip) => IpAddr::V6(ip),
+            Host::Domain(_) => return None,
+        };
+        (!self.is_allowed(ip)).then_some(ip)
+    }
+}
+
+/// Why the resolver refused a host. Failed upstream requests carry it in
+/// their error's source chain; see [`blocked_address`].
+#[derive(Debug)]
+pub struct BlockedAddress {
+    pub host: String,
+    pub addrs: Vec<IpAddr>,
+}
+
+impl fmt::Display for BlockedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addrs: Vec<String> = self.addrs.iter().map(IpAddr::to_string).collect();
+        write!(
+            f,
+            "{} resolves only to blocked addresses ({})",
+            self.host,
+            addrs.join(", ")
+        )
+    }
+}
+
+impl Error for BlockedAddress {}
+
+/// DNS resolver for the proxy's HTTP client that drops denied addresses.
+///
+/// reqwest connects to exactly the addresses returned here, so the address
+/// that was checked is the one connected to; a name cannot be rebound to an
+/// internal address between the check and the connection.
+pub struct GuardedResolver {
+    policy: Arc<AddressPolicy>,
+}
+
+impl GuardedResolver {
+    pub fn new(policy: Arc<AddressPolicy>) -> Self {
+        Self { policy }
+    }
+}
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.policy.clone();
+        Box::pin(async move {
+            let host = name.as_str();
+            let (allowed, denied): (Vec<SocketAddr>, Vec<SocketAddr>) =
+                tokio::net::lookup_host((host, 0))
+                    .await?
+                    .partition(|addr| policy.is_allowed(addr.ip()));
+
+            if !denied.is_empty() {
+                tracing::warn!(
+                    "Refusing blocked addresses for {}: {:?}",
+                    host,
+                    denied.iter().map(SocketAddr::ip).collect::<Vec<_>>()
+                );
+            }
+            if allowed.is_empty() {
+                return Err(BlockedAddress {
+                    host: host.to_string(),
+                    addrs: denied.iter().map(SocketAddr::ip).collect(),
+                }
+                .into());
+            }
+            Ok(Box::new(allowed.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// The resolver's refusal behind a failed upstream request, if that is why
+/// it failed
+pub fn blocked_address<'a>(error: &'a (dyn Error + 'static)) -> Option<&'a BlockedAddress> {
+    let mut source = Some(error);
+    while let Some(e) = source {
+        if let Some(blocked) = e.downcast_ref::<BlockedAddress>() {
+            return Some(blocked);
+        }
+        source = e.source();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::default_ssrf_deny;
+
+    fn policy(allow: &[&str]) -> AddressPolicy {
+        AddressPolicy::new(&SsrfConfig {
+            enabled: true,
+            deny: default_ssrf_deny(),
+            allow: allow.iter().map(|a| a.to_string()).collect(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_internal_addresses_denied() {
+        let policy = policy(&["10.1.0.0/16"]);
+
+        for ip in [
+            "127.0.0.1",
+            "169.254.169.254",
+            "10.2.3.4",
+            "172.16.0.1",
+            "192.168.1.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!policy.is_allowed(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(policy.is_allowed("93.184.216.34".parse().unwrap()));
+        assert!(policy.is_allowed("2606:2800:220:1::1".parse().unwrap()));
+        // Allowed ranges are exempt from the deny list
+        assert!(policy.is_allowed("10.1.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_literal_addresses_in_urls() {
+        let policy = policy(&[]);
+        let blocked = |url: &str| policy.blocked_literal(&Url::parse(url).unwrap());
+
+        assert!(blocked("http://169.254.169.254/latest/meta-data/").is_some());
+        assert!(blocked("http://[::1]:8080/").is_some());
+        // Decimal notation is normalized by the URL parser
+        assert!(blocked("http://2130706433/").is_some());
+        assert!(blocked("https://93.184.216.34/").is_none());
+        assert!(blocked("https://localhost/").is_none());
+        assert!(AddressPolicy::new(&SsrfConfig {
+            enabled: true,
+            deny: vec!["10.0.0.0/33".to_string()],
+            allow: Vec::new(),
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_refuses_names_resolving_to_loopback() {
+        let client = reqwest::Client::builder()
+            .dns_resolver(Arc::new(GuardedResolver::new(Arc::new(policy(&[])))))
+            .build()
+            .unwrap();
+
+        let error = client.get("http://localhost:9/").send().await.unwrap_err();
+        let blocked = blocked_address(&error).expect("refused by the resolver");
+        assert_eq!(blocked.host, "localhost");
+        assert!(blocked.addrs.iter().all(|ip| ip.is_loopback()));
+    }
+}
@@ -0,0 +1,238 @@
+use anyhow::Result;
+use axum::http::HeaderMap;
+use tracing::Subscriber;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+use wildmatch::WildMatch;
+
+use crate::config::TelemetryConfig;
+
+#[cfg(feature = "otel")]
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::TracerProvider};
+#[cfg(feature = "otel")]
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// OpenTelemetry trace export and W3C trace context propagation.
+///
+/// Without the `otel` feature this is inert: no spans are exported and no
+/// trace headers are read or written.
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<TracerProvider>,
+    propagate_domains: Vec<WildMatch>,
+}
+
+impl Telemetry {
+    pub fn new(config: &TelemetryConfig) -> Result<Self> {
+        let propagate_domains = config
+            .propagate_domains
+            .iter()
+            .map(|p| WildMatch::new(p))
+            .collect();
+
+        #[cfg(feature = "otel")]
+        {
+            let provider = if config.enabled {
+                Some(build_provider(config)?)
+            } else {
+                None
+            };
+            Ok(Self {
+                provider,
+                propagate_domains,
+            })
+        }
+
+        #[cfg(not(feature = "otel"))]
+        Ok(Self { propagate_domains })
+    }
+
+    /// Tracing layer that forwards spans to the OTLP exporter, if enabled
+    pub fn layer<S>(&self) -> Option<Box<dyn Layer<S> + Send + Sync>>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+    {
+        #[cfg(feature = "otel")]
+        {
+            use opentelemetry::trace::TracerProvider as _;
+
+            self.provider.as_ref().map(|provider| {
+                let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+                tracing_opentelemetry::layer().with_tracer(tracer).boxed()
+            })
+        }
+
+        #[cfg(not(feature = "otel"))]
+        None
+    }
+
+    /// Whether trace context headers may be sent to this upstream domain
+    pub fn should_propagate(&self, domain: &str) -> bool {
+        self.propagate_domains.iter().any(|p| p.matches(domain))
+    }
+
+    /// Export any spans still buffered in the batch processor
+    pub fn flush(&self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = &self.provider {
+            for result in provider.force_flush() {
+                if let Err(e) = result {
+                    tracing::warn!("Failed to flush spans: {}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+fn build_provider(config: &TelemetryConfig) -> Result<TracerProvider> {
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, trace, Resource};
+
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(&config.otlp_endpoint)
+        .build_span_exporter()?;
+
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                config.service_name.clone(),
+            )])),
+        )
+        .build())
+}
+
+#[cfg(feature = "otel")]
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+#[cfg(feature = "otel")]
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// Continue the trace named by an incoming `traceparent` header, if any
+pub fn set_parent_from_headers(span: &tracing::Span, headers: &HeaderMap) {
+    #[cfg(feature = "otel")]
+    if headers.contains_key("traceparent") {
+        let context = TraceContextPropagator::new().extract(&HeaderExtractor(headers));
+        span.set_parent(context);
+    }
+
+    #[cfg(not(feature = "otel"))]
+    let _ = (span, headers);
+}
+
+/// W3C trace context headers identifying the current span
+pub fn trace_context_headers() -> Vec<(String, String)> {
+    #[cfg(feature = "otel")]
+    {
+        let mut headers = std::collections::HashMap::new();
+        let context = tracing::Span::current().context();
+        TraceContextPropagator::new().inject_context(&context, &mut headers);
+        headers.into_iter().collect()
+    }
+
+    #[cfg(not(feature = "otel"))]
+    Vec::new()
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use super::*;
+    use axum::{body::Bytes, routing::post, Router};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn config(endpoint: &str) -> TelemetryConfig {
+        TelemetryConfig {
+            enabled: true,
+            otlp_endpoint: endpoint.to_string(),
+            service_name: "browser_proxy_test".to_string(),
+            propagate_domains: vec!["*.internal.example.com".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_should_propagate() {
+        let telemetry = Telemetry::new(&TelemetryConfig {
+            enabled: false,
+            ..config("http://localhost:4318")
+        })
+        .unwrap();
+
+        assert!(telemetry.should_propagate("api.internal.example.com"));
+        assert!(!telemetry.should_propagate("example.com"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_incoming_traceparent_is_continued() {
+        let telemetry = Telemetry::new(&config("http://127.0.0.1:9")).unwrap();
+        let subscriber = tracing_subscriber::registry().with(telemetry.layer());
+
+        let mut incoming = HeaderMap::new();
+        incoming.insert(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                .parse()
+                .unwrap(),
+        );
+
+        let outgoing = tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request");
+            set_parent_from_headers(&span, &incoming);
+            span.in_scope(trace_context_headers)
+        });
+
+        let traceparent = outgoing
+            .iter()
+            .find(|(name, _)| name == "traceparent")
+            .map(|(_, value)| value.as_str())
+            .unwrap();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(!traceparent.contains("00f067aa0ba902b7"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_spans_exported_to_collector() {
+        // Local stand-in for an OTLP/HTTP collector
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let collector = Router::new().route(
+            "/v1/traces",
+            post(move |body: Bytes| {
+                let tx = tx.clone();
+                async move {
+                    let _ = tx.send(body.len());
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, collector).await });
+
+        let telemetry = Telemetry::new(&config(&format!("http://{}", addr))).unwrap();
+        let subscriber = tracing_subscriber::registry().with(telemetry.layer());
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("upstream_fetch").entered();
+        });
+
+        tokio::task::spawn_blocking(move || telemetry.flush())
+            .await
+            .unwrap();
+
+        let received = tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(received > 0);
+    }
+}
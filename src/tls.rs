@@ -0,0 +1,241 @@
+use anyhow::{anyhow, Context, Result};
+use axum::{extract::ConnectInfo, Router};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::GracefulShutdown;
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tower::Service;
+
+use crate::auth::ClientCert;
+use crate::config::TlsConfig;
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to read certificates from {}", path))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", path);
+    }
+    Ok(certs)
+}
+
+/// TLS settings from `[server.tls]`. With `client_ca_path` set, clients may
+/// present a certificate signed by one of those CAs; presenting none is
+/// still allowed so password logins keep working.
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let certs = load_certs(&config.cert_path)?;
+    let key_file = File::open(&config.key_path)
+        .with_context(|| format!("Failed to open {}", config.key_path))?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(key_file))?
+        .ok_or_else(|| anyhow!("No private key found in {}", config.key_path))?;
+
+    let builder = ServerConfig::builder();
+    let builder = match &config.client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .allow_unauthenticated()
+                .build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut server = builder.with_single_cert(certs, key)?;
+    server.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(server)))
+}
+
+/// Serve `app` over TLS until `shutdown` resolves, then wait for open
+/// connections to finish.
+///
+/// Requests carry the peer address as `ConnectInfo<SocketAddr>`, like the
+/// plain HTTP listener, and a `ClientCert` extension when the client
+/// presented a verified certificate.
+pub async fn serve(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    app: Router,
+    shutdown: impl Future<Output = ()>,
+) -> std::io::Result<()> {
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::debug!("TLS handshake with {} failed: {}", addr, e);
+                    return;
+                }
+            };
+            // rustls has already verified the chain against the client CAs
+            let client_cert = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|der| match ClientCert::from_der(der) {
+                    Ok(cert) => Some(cert),
+                    Err(e) => {
+                        tracing::warn!("Ignoring client certificate from {}: {:#}", addr, e);
+                        None
+                    }
+                });
+
+            let service = hyper::service::service_fn(
+                move |mut request: hyper::Request<hyper::body::Incoming>| {
+                    request.extensions_mut().insert(ConnectInfo(addr));
+                    if let Some(cert) = &client_cert {
+                        request.extensions_mut().insert(cert.clone());
+                    }
+                    app.clone().call(request)
+                },
+            );
+            let connection = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .into_owned();
+            if let Err(e) = watcher.watch(connection).await {
+                tracing::debug!("Connection from {} closed: {}", addr, e);
+            }
+        });
+    }
+
+    drop(listener);
+    graceful.shutdown().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::client_cert::test_certs::{ca, issue, Ca};
+    use axum::{routing::get, Extension};
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::pki_types::{PrivateKeyDer, ServerName};
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::TlsConnector;
+
+    struct Server {
+        addr: SocketAddr,
+        ca: Ca,
+        dir: std::path::PathBuf,
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Start a TLS server whose only route echoes the client certificate CN
+    async fn server(name: &str) -> Server {
+        let dir =
+            std::env::temp_dir().join(format!("browser_proxy_tls_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca = ca("Test CA");
+        let (cert, key) = issue(&ca, "localhost", &["localhost"], &[]);
+        let write = |file: &str, pem: String| {
+            let path = dir.join(file);
+            std::fs::write(&path, pem).unwrap();
+            path.to_str().unwrap().to_string()
+        };
+        let config = TlsConfig {
+            cert_path: write("cert.pem", cert.pem()),
+            key_path: write("key.pem", key.serialize_pem()),
+            client_ca_path: Some(write("ca.pem", ca.cert.pem())),
+        };
+
+        let app = Router::new().route(
+            "/",
+            get(|cert: Option<Extension<ClientCert>>| async move {
+                cert.and_then(|Extension(c)| c.subject_cn)
+                    .unwrap_or_else(|| "anonymous".to_string())
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = acceptor(&config).unwrap();
+        tokio::spawn(serve(listener, acceptor, app, std::future::pending()));
+        Server { addr, ca, dir }
+    }
+
+    /// GET / over TLS, optionally with a client certificate
+    async fn get_root(server: &Server, client: Option<&Ca>) -> std::io::Result<String> {
+        let mut roots = RootCertStore::empty();
+        roots.add(server.ca.cert.der().clone()).unwrap();
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let config = match client {
+            Some(issuer) => {
+                let (cert, key) = issue(issuer, "kiosk-1", &[], &[]);
+                let key = PrivateKeyDer::try_from(key.serialize_der()).unwrap();
+                builder
+                    .with_client_auth_cert(vec![cert.der().clone()], key)
+                    .unwrap()
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let tcp = tokio::net::TcpStream::connect(server.addr).await?;
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response
+            .rsplit("\r\n")
+            .next()
+            .unwrap_or_default()
+            .to_string())
+    }
+
+    #[tokio::test]
+    async fn test_verified_client_cert_reaches_handler() {
+        let server = server("verified").await;
+        let body = get_root(&server, Some(&server.ca)).await.unwrap();
+        assert_eq!(body, "kiosk-1");
+    }
+
+    #[tokio::test]
+    async fn test_client_without_cert_allowed() {
+        let server = server("anonymous").await;
+        assert_eq!(get_root(&server, None).await.unwrap(), "anonymous");
+    }
+
+    #[tokio::test]
+    async fn test_cert_from_unknown_ca_rejected() {
+        let server = server("untrusted").await;
+        let other = ca("Other CA");
+        assert!(get_root(&server, Some(&other)).await.is_err());
+    }
+}